@@ -10,7 +10,7 @@ Router.map(function() {
   this.resource('logout');
   this.resource('login');
   this.resource('github_login');
-  this.resource('github_authorize', { path: '/authorize/github' });
+  this.resource('authorize', { path: '/authorize/:provider' });
   this.resource('crates');
   this.resource('crate', { path: '/crates/*crate_id' }, function() {
     this.route('download');
@@ -29,7 +29,6 @@ export default Ember.Route.extend({
             }
 
             var user = self.store.push('user', data.user);
-            user.set('api_token', data.api_token);
             var transition = self.session.get('savedTransition');
             self.session.loginUser(user);
             if (transition) {
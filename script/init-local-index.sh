@@ -27,7 +27,6 @@ git commit -qm 'Initial commit'
 git remote add origin file://`pwd`/../index-bare
 git push -q origin master -u > /dev/null
 cd ../..
-touch tmp/index-co/.git/git-daemon-export-ok
 
 cat - <<-EOF
 Please add the following to your HOME/.cargo/config:
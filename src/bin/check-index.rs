@@ -0,0 +1,247 @@
+// Audit the git index against the database, without changing either.
+//
+// Every version in the database is looked up in the index checkout, and the
+// two are compared: versions missing from either side, `yanked` flags which
+// differ, checksums which don't agree with each other or with the tarball in
+// storage, tarballs which are missing or can't be read, and differing
+// dependency lists are all reported. The report is
+// printed as JSON, and the exit status is nonzero if anything was found.
+//
+// Usage:
+//      cargo run --bin check-index [--skip-tarballs]
+//
+// The index is read from `GIT_REPO_CHECKOUT`, and storage is configured just
+// as it is for the server, from the same environment variables.
+// Downloading every tarball takes a while, so `--skip-tarballs` leaves them
+// out of the checksum comparison.
+
+#![deny(warnings)]
+#![feature(io, core, os, env, path)]
+
+extern crate "cargo-registry" as cargo_registry;
+extern crate "rustc-serialize" as rustc_serialize;
+extern crate openssl;
+extern crate postgres;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::old_io::fs::{self, PathExtensions};
+use std::old_io::File;
+use openssl::crypto::hash::{self, Type};
+use rustc_serialize::Encodable;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json};
+
+use cargo_registry::{Config, Crate, Model};
+use cargo_registry::git;
+use cargo_registry::storage::{self, Storage};
+
+#[derive(RustcEncodable)]
+struct Report {
+    crates: u32,
+    versions: u32,
+    problems: Vec<Problem>,
+}
+
+#[derive(RustcEncodable)]
+struct Problem {
+    kind: String,
+    name: String,
+    version: Option<String>,
+    index: Option<Json>,
+    database: Option<Json>,
+    storage: Option<Json>,
+}
+
+// Index entries, keyed by the path of their file and then by version.
+type Index = BTreeMap<String, BTreeMap<String, git::Crate>>;
+
+fn main() {
+    let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
+                                             &postgres::SslMode::None).unwrap();
+    let checkout = Path::new(env("GIT_REPO_CHECKOUT"));
+    let skip_tarballs = env::args().any(|arg| {
+        arg.to_str() == Some("--skip-tarballs")
+    });
+    let storage = if skip_tarballs {
+        None
+    } else {
+        Some(storage::new(&Config::from_env()))
+    };
+
+    let mut report = Report { crates: 0, versions: 0, problems: Vec::new() };
+    let mut index = read_index(&checkout, &mut report);
+    {
+        let tx = conn.transaction().unwrap();
+        check_database(&tx, &mut index, storage.as_ref(), &mut report);
+    }
+
+    // Whatever is left in the index has nothing in the database.
+    for (_, entries) in index.into_iter() {
+        for (vers, entry) in entries.into_iter() {
+            report.problems.push(problem("missing_from_database",
+                                         entry.name.as_slice(), Some(vers)));
+        }
+    }
+
+    println!("{}", json::encode(&report).unwrap());
+    if report.problems.len() > 0 {
+        env::set_exit_status(1);
+    }
+}
+
+fn env(s: &str) -> String {
+    match env::var_string(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
+
+fn problem(kind: &str, name: &str, version: Option<String>) -> Problem {
+    Problem {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        version: version,
+        index: None,
+        database: None,
+        storage: None,
+    }
+}
+
+fn to_json<T: Encodable>(t: &T) -> Json {
+    Json::from_str(json::encode(t).unwrap().as_slice()).unwrap()
+}
+
+fn read_index(checkout: &Path, report: &mut Report) -> Index {
+    let mut index = BTreeMap::new();
+    for path in fs::walk_dir(checkout).unwrap() {
+        let rel = path.path_relative_from(checkout).unwrap();
+        let rel = rel.as_str().unwrap().to_string();
+        if !path.is_file() || rel.starts_with(".git/") ||
+           rel.as_slice() == "config.json" {
+            continue
+        }
+        let contents = File::open(&path).read_to_string().unwrap();
+        let mut entries = BTreeMap::new();
+        for line in contents.as_slice().lines() {
+            match json::decode::<git::Crate>(line) {
+                Ok(entry) => { entries.insert(entry.vers.clone(), entry); }
+                Err(..) => {
+                    let mut p = problem("invalid_index_entry",
+                                        rel.as_slice(), None);
+                    p.index = Some(Json::String(line.to_string()));
+                    report.problems.push(p);
+                }
+            }
+        }
+        index.insert(rel, entries);
+    }
+    index
+}
+
+fn check_database(tx: &postgres::Transaction, index: &mut Index,
+                  storage: Option<&Box<Storage + Send + Sync>>,
+                  report: &mut Report) {
+    let stmt = tx.prepare("SELECT * FROM crates ORDER BY name ASC").unwrap();
+    for row in stmt.query(&[]).unwrap() {
+        let krate: Crate = Model::from_row(&row);
+        let name = krate.name.as_slice();
+        report.crates += 1;
+
+        let mut entries = index.remove(&git::index_path(name))
+                               .unwrap_or(BTreeMap::new());
+        let stmt = tx.prepare("SELECT id, checksum FROM versions \
+                               WHERE crate_id = $1").unwrap();
+        let checksums = stmt.query(&[&krate.id]).unwrap().map(|row| {
+            let id: i32 = row.get("id");
+            let checksum: Option<String> = row.get("checksum");
+            (id, checksum)
+        }).collect::<BTreeMap<_, _>>();
+
+        for version in krate.versions(tx).unwrap().into_iter() {
+            // Pending versions are on their way into the index.
+            if version.pending { continue }
+            report.versions += 1;
+            let vers = version.num.to_string();
+            let entry = match entries.remove(&vers) {
+                Some(entry) => entry,
+                None => {
+                    report.problems.push(problem("missing_from_index", name,
+                                                 Some(vers)));
+                    continue
+                }
+            };
+
+            if entry.yanked.unwrap_or(false) != version.yanked {
+                let mut p = problem("yanked_mismatch", name, Some(vers.clone()));
+                p.index = Some(Json::Boolean(entry.yanked.unwrap_or(false)));
+                p.database = Some(Json::Boolean(version.yanked));
+                report.problems.push(p);
+            }
+
+            // The database's checksum is checked whether or not the tarball
+            // could be, and only a tarball which isn't there at all is
+            // reported as missing.
+            let checksum = checksums.get(&version.id).and_then(|c| c.clone());
+            let path = krate.s3_path(vers.as_slice());
+            let stored = storage.and_then(|storage| {
+                let res = storage.exists(path.as_slice()).and_then(|exists| {
+                    if !exists { return Ok(None) }
+                    storage.get(path.as_slice()).map(|data| {
+                        Some(hash::hash(Type::SHA256, data.as_slice()).to_hex())
+                    })
+                });
+                match res {
+                    Ok(Some(stored)) => Some(stored),
+                    Ok(None) => {
+                        report.problems.push(problem("missing_tarball", name,
+                                                     Some(vers.clone())));
+                        None
+                    }
+                    Err(e) => {
+                        let mut p = problem("storage_error", name,
+                                            Some(vers.clone()));
+                        p.storage = Some(Json::String(e.to_string()));
+                        report.problems.push(p);
+                        None
+                    }
+                }
+            });
+            let database_differs = checksum.as_ref() != Some(&entry.cksum);
+            let storage_differs = stored.as_ref().map_or(false, |stored| {
+                *stored != entry.cksum
+            });
+            if database_differs || storage_differs {
+                let mut p = problem("checksum_mismatch", name,
+                                    Some(vers.clone()));
+                p.index = Some(Json::String(entry.cksum.clone()));
+                p.database = Some(to_json(&checksum));
+                p.storage = stored.map(Json::String);
+                report.problems.push(p);
+            }
+
+            let mut deps = version.dependencies(tx).unwrap().into_iter()
+                                  .map(|(dep, name)| {
+                json::encode(&dep.git_encode(name.as_slice())).unwrap()
+            }).collect::<Vec<_>>();
+            let mut index_deps = entry.deps.iter().map(|dep| {
+                json::encode(dep).unwrap()
+            }).collect::<Vec<_>>();
+            deps.sort();
+            index_deps.sort();
+            if deps != index_deps {
+                let mut p = problem("dependencies_mismatch", name, Some(vers));
+                p.index = Some(to_json(&entry.deps));
+                p.database = Some(Json::Array(deps.iter().map(|d| {
+                    Json::from_str(d.as_slice()).unwrap()
+                }).collect()));
+                report.problems.push(p);
+            }
+        }
+
+        for (vers, _) in entries.into_iter() {
+            report.problems.push(problem("missing_from_database", name,
+                                         Some(vers)));
+        }
+    }
+}
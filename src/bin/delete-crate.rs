@@ -1,9 +1,13 @@
-// Purge all references to a crate from the database.
+// Purge all references to a crate from the database, along with its
+// tarballs in storage.
 //
 // Please be super sure you want to do this before running this.
 //
 // Usage:
 //      cargo run --bin delete-crate crate-name
+//
+// Storage is configured just as it is for the server, so this needs the same
+// environment variables set.
 
 #![deny(warnings)]
 #![feature(io, core, env, os)]
@@ -16,16 +20,29 @@ extern crate time;
 use std::env;
 use std::old_io;
 
-use cargo_registry::Crate;
+use cargo_registry::{Config, Crate};
+use cargo_registry::storage;
 
 fn main() {
     let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
                                              &postgres::SslMode::None).unwrap();
-    {
+    let storage = storage::new(&Config::from_env());
+    let name = {
         let tx = conn.transaction().unwrap();
-        delete(&tx);
+        let name = delete(&tx);
         tx.set_commit();
         tx.finish().unwrap();
+        name
+    };
+
+    // Only touch storage once the database no longer refers to the tarballs.
+    let name = match name { Some(name) => name, None => return };
+    let tarballs = storage.list(format!("/crates/{}/", name).as_slice())
+                          .unwrap();
+    println!("deleting {} tarballs", tarballs.len());
+    for path in tarballs.iter() {
+        println!("  {}", path);
+        storage.delete(path.as_slice()).unwrap();
     }
 }
 
@@ -36,16 +53,16 @@ fn env(s: &str) -> String {
     }
 }
 
-fn delete(tx: &postgres::Transaction) {
+fn delete(tx: &postgres::Transaction) -> Option<String> {
     let name = match env::args().nth(1) {
-        None => { println!("needs a crate-name argument"); return }
+        None => { println!("needs a crate-name argument"); return None }
         Some(s) => s.into_string().unwrap(),
     };
 
     let krate = Crate::find_by_name(tx, name.as_slice()).unwrap();
     print!("Are you sure you want to delete {} ({}) [y/N]: ", name, krate.id);
     let line = old_io::stdin().read_line().unwrap();
-    if !line.starts_with("y") { return }
+    if !line.starts_with("y") { return None }
 
     let versions = krate.versions(tx).unwrap();
 
@@ -60,6 +77,12 @@ fn delete(tx: &postgres::Transaction) {
         let n = tx.execute("DELETE FROM dependencies WHERE version_id = $1",
                            &[&v.id]).unwrap();
         println!("  {} dependencies deleted", n);
+        let n = tx.execute("DELETE FROM readmes WHERE version_id = $1",
+                           &[&v.id]).unwrap();
+        println!("  {} readmes deleted", n);
+        let n = tx.execute("DELETE FROM license_files WHERE version_id = $1",
+                           &[&v.id]).unwrap();
+        println!("  {} license files deleted", n);
         tx.execute("DELETE FROM versions WHERE id = $1",
                    &[&v.id]).unwrap();
     }
@@ -79,6 +102,11 @@ fn delete(tx: &postgres::Transaction) {
                        &[&krate.id]).unwrap();
     println!("  {} deleted", n);
 
+    println!("deleting crate license records");
+    let n = tx.execute("DELETE FROM crate_licenses WHERE crate_id = $1",
+                       &[&krate.id]).unwrap();
+    println!("  {} deleted", n);
+
     println!("deleting crate keyword connections");
     let n = tx.execute("DELETE FROM crates_keywords WHERE crate_id = $1",
                        &[&krate.id]).unwrap();
@@ -92,5 +120,6 @@ fn delete(tx: &postgres::Transaction) {
     print!("commit? [y/N]: ");
     let line = old_io::stdin().read_line().unwrap();
     if !line.starts_with("y") { panic!("aborting transaction"); }
+    Some(krate.name)
 }
 
@@ -1,9 +1,13 @@
-// Purge all references to a crate's version from the database.
+// Purge all references to a crate's version from the database, along with
+// its tarball in storage.
 //
 // Please be super sure you want to do this before running this.
 //
 // Usage:
 //      cargo run --bin delete-version crate-name version-number
+//
+// Storage is configured just as it is for the server, so this needs the same
+// environment variables set.
 
 #![deny(warnings)]
 #![feature(io, core, os, env)]
@@ -16,16 +20,25 @@ extern crate semver;
 use std::env;
 use std::old_io;
 
-use cargo_registry::{Crate, Version};
+use cargo_registry::{Config, Crate, Version};
+use cargo_registry::storage;
 
 fn main() {
     let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
                                              &postgres::SslMode::None).unwrap();
-    {
+    let storage = storage::new(&Config::from_env());
+    let tarball = {
         let tx = conn.transaction().unwrap();
-        delete(&tx);
+        let tarball = delete(&tx);
         tx.set_commit();
         tx.finish().unwrap();
+        tarball
+    };
+
+    // Only touch storage once the database no longer refers to the tarball.
+    if let Some(path) = tarball {
+        println!("deleting {}", path);
+        storage.delete(path.as_slice()).unwrap();
     }
 }
 
@@ -36,13 +49,13 @@ fn env(s: &str) -> String {
     }
 }
 
-fn delete(tx: &postgres::Transaction) {
+fn delete(tx: &postgres::Transaction) -> Option<String> {
     let name = match env::args().nth(1) {
-        None => { println!("needs a crate-name argument"); return }
+        None => { println!("needs a crate-name argument"); return None }
         Some(s) => s.into_string().unwrap(),
     };
     let version = match env::args().nth(2) {
-        None => { println!("needs a version argument"); return }
+        None => { println!("needs a version argument"); return None }
         Some(s) => s.into_string().unwrap(),
     };
     let version = semver::Version::parse(&version[]).unwrap();
@@ -52,7 +65,7 @@ fn delete(tx: &postgres::Transaction) {
     print!("Are you sure you want to delete {}#{} ({}) [y/N]: ", name, version,
            v.id);
     let line = old_io::stdin().read_line().unwrap();
-    if !line.starts_with("y") { return }
+    if !line.starts_with("y") { return None }
 
     println!("deleting version {} ({})", v.num, v.id);
     let n = tx.execute("DELETE FROM version_downloads WHERE version_id = $1",
@@ -64,11 +77,18 @@ fn delete(tx: &postgres::Transaction) {
     let n = tx.execute("DELETE FROM dependencies WHERE version_id = $1",
                        &[&v.id]).unwrap();
     println!("  {} dependencies deleted", n);
+    let n = tx.execute("DELETE FROM readmes WHERE version_id = $1",
+                       &[&v.id]).unwrap();
+    println!("  {} readmes deleted", n);
+    let n = tx.execute("DELETE FROM license_files WHERE version_id = $1",
+                       &[&v.id]).unwrap();
+    println!("  {} license files deleted", n);
     tx.execute("DELETE FROM versions WHERE id = $1",
                &[&v.id]).unwrap();
 
     print!("commit? [y/N]: ");
     let line = old_io::stdin().read_line().unwrap();
     if !line.starts_with("y") { panic!("aborting transaction"); }
+    Some(krate.s3_path(v.num.to_string().as_slice()))
 }
 
@@ -1,16 +1,22 @@
 #![deny(warnings)]
-#![feature(os, core, env)]
+#![feature(os, core, env, io, path)]
 
 extern crate "cargo-registry" as cargo_registry;
+extern crate "rustc-serialize" as rustc_serialize;
 extern crate migrate;
 extern crate postgres;
 
 use std::env;
 use std::collections::HashSet;
+use std::old_io::File;
 use migrate::Migration;
+use rustc_serialize::json;
 
+use cargo_registry::git;
 use cargo_registry::krate::Crate;
 use cargo_registry::model::Model;
+use cargo_registry::user::{DEFAULT_TOKEN, Scope};
+use cargo_registry::user::token::Digest;
 
 fn main() {
     let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
@@ -418,6 +424,149 @@ fn migrations() -> Vec<Migration> {
                              crate_owners_unique_user_per_crate", &[]));
             Ok(())
         }),
+        Migration::add_table(20150224171245, "readmes", "
+            id               SERIAL PRIMARY KEY,
+            version_id       INTEGER NOT NULL UNIQUE,
+            path             VARCHAR NOT NULL,
+            content          VARCHAR NOT NULL,
+            rendered         VARCHAR NOT NULL
+        "),
+        foreign_key(20150224171246, "readmes", "version_id", "versions (id)"),
+        Migration::add_column(20150302112410, "versions", "license", "VARCHAR"),
+        Migration::add_table(20150302112411, "license_files", "
+            id               SERIAL PRIMARY KEY,
+            version_id       INTEGER NOT NULL UNIQUE,
+            path             VARCHAR NOT NULL,
+            content          VARCHAR NOT NULL
+        "),
+        foreign_key(20150302112412, "license_files", "version_id",
+                    "versions (id)"),
+        Migration::add_table(20150303140517, "crate_licenses", "
+            id               SERIAL PRIMARY KEY,
+            crate_id         INTEGER NOT NULL,
+            license          VARCHAR NOT NULL,
+            allowed          BOOLEAN NOT NULL
+        "),
+        foreign_key(20150303140518, "crate_licenses", "crate_id",
+                    "crates (id)"),
+        index(20150303140519, "crate_licenses", "license"),
+        Migration::new(20150303140520, |tx| {
+            let stmt = try!(tx.prepare("SELECT * FROM crates"));
+            for row in try!(stmt.query(&[])) {
+                let krate: Crate = Model::from_row(&row);
+                krate.update_licenses(tx).unwrap();
+            }
+            Ok(())
+        }, |tx| {
+            try!(tx.execute("DELETE FROM crate_licenses", &[]));
+            Ok(())
+        }),
+        Migration::add_column(20150305093114, "versions", "checksum",
+                              "VARCHAR"),
+        Migration::new(20150305093115, |tx| {
+            try!(fill_checksums(tx));
+            Ok(())
+        }, |_| Ok(())),
+        Migration::add_table(20150309101530, "jobs", "
+            id               SERIAL PRIMARY KEY,
+            job              VARCHAR NOT NULL,
+            payload          BYTEA,
+            attempts         INTEGER NOT NULL DEFAULT 0,
+            last_error       VARCHAR,
+            dead             BOOLEAN NOT NULL DEFAULT FALSE,
+            run_at           TIMESTAMP NOT NULL,
+            created_at       TIMESTAMP NOT NULL
+        "),
+        Migration::add_table(20150311093805, "api_tokens", "
+            id               SERIAL PRIMARY KEY,
+            user_id          INTEGER NOT NULL,
+            name             VARCHAR NOT NULL,
+            token            VARCHAR NOT NULL UNIQUE,
+            scopes           VARCHAR NOT NULL,
+            crate_pattern    VARCHAR,
+            last_used_at     TIMESTAMP,
+            expires_at       TIMESTAMP,
+            created_at       TIMESTAMP NOT NULL,
+            UNIQUE (user_id, name)
+        "),
+        foreign_key(20150311093806, "api_tokens", "user_id", "users (id)"),
+        Migration::new(20150312151642, |tx| {
+            try!(hash_api_tokens(tx));
+            Ok(())
+        }, |tx| {
+            // The tokens themselves are gone, so everyone gets new ones.
+            try!(tx.execute("ALTER TABLE users ADD COLUMN api_token VARCHAR",
+                            &[]));
+            try!(tx.execute("UPDATE users SET api_token = md5(random()::text)",
+                            &[]));
+            try!(tx.execute("ALTER TABLE users ALTER COLUMN api_token \
+                             SET NOT NULL", &[]));
+            try!(tx.execute("ALTER TABLE api_tokens ADD COLUMN token VARCHAR",
+                            &[]));
+            try!(tx.execute("UPDATE api_tokens \
+                             SET token = md5(random()::text)", &[]));
+            try!(tx.execute("ALTER TABLE api_tokens ALTER COLUMN token \
+                             SET NOT NULL", &[]));
+            try!(tx.execute("ALTER TABLE api_tokens ADD UNIQUE (token)", &[]));
+            try!(tx.execute("DROP INDEX index_api_tokens_token_prefix", &[]));
+            try!(tx.execute("ALTER TABLE api_tokens DROP COLUMN token_prefix, \
+                             DROP COLUMN salt, DROP COLUMN token_hash", &[]));
+            Ok(())
+        }),
+        Migration::add_table(20150316103215, "identities", "
+            id               SERIAL PRIMARY KEY,
+            user_id          INTEGER NOT NULL,
+            provider         VARCHAR NOT NULL,
+            uid              VARCHAR NOT NULL,
+            access_token     VARCHAR,
+            salt             VARCHAR,
+            password_hash    VARCHAR,
+            created_at       TIMESTAMP NOT NULL,
+            UNIQUE (provider, uid)
+        "),
+        foreign_key(20150316103216, "identities", "user_id", "users (id)"),
+        index(20150316103217, "identities", "user_id"),
+        Migration::new(20150316103218, |tx| {
+            try!(move_github_logins(tx));
+            Ok(())
+        }, |tx| {
+            try!(tx.execute("ALTER TABLE users \
+                             ADD COLUMN gh_access_token VARCHAR", &[]));
+            try!(tx.execute("UPDATE users SET gh_access_token = \
+                             COALESCE((SELECT access_token FROM identities \
+                                       WHERE user_id = users.id AND \
+                                             provider = 'github'), '')", &[]));
+            try!(tx.execute("ALTER TABLE users ALTER COLUMN gh_access_token \
+                             SET NOT NULL", &[]));
+            try!(tx.execute("ALTER TABLE users RENAME COLUMN login \
+                             TO gh_login", &[]));
+            try!(tx.execute("ALTER TABLE users RENAME COLUMN avatar \
+                             TO gh_avatar", &[]));
+            try!(tx.execute("ALTER INDEX index_users_login \
+                             RENAME TO index_users_gh_login", &[]));
+            Ok(())
+        }),
+        Migration::add_column(20150317142602, "versions", "pending",
+                              "BOOLEAN NOT NULL DEFAULT false"),
+        // GitHub identities are keyed on GitHub's ids now, which each gets
+        // the next time its user logs in.
+        Migration::run(20150317160311,
+                       format!("UPDATE identities SET uid = 'login:' || uid \
+                                WHERE provider = 'github'"),
+                       format!("UPDATE identities SET uid = users.login \
+                                FROM users \
+                                WHERE identities.user_id = users.id AND \
+                                      identities.provider = 'github'"),
+        // Versions published before they had licenses of their own get the
+        // crate's, which is only known to be right for the latest one.
+        Migration::new(20150317173012, |tx| {
+            try!(tx.execute("UPDATE versions SET license = crates.license \
+                             FROM crates \
+                             WHERE versions.crate_id = crates.id AND \
+                                   versions.num = crates.max_version AND \
+                                   versions.license IS NULL", &[]));
+            Ok(())
+        }, |_| Ok(())),
     ];
     // NOTE: Generate a new id via `date +"%Y%m%d%H%M%S"`
 
@@ -471,3 +620,123 @@ fn fix_duplicate_crate_owners(tx: &postgres::Transaction) -> postgres::Result<()
     }
     Ok(())
 }
+
+// Checksums used to only be recorded in the git index, so they're read back
+// out of a checkout of it. Whatever can't be found there is left empty rather
+// than holding up the rest of the migrations.
+fn fill_checksums(tx: &postgres::Transaction) -> postgres::Result<()> {
+    let crates: Vec<Crate> = {
+        let stmt = try!(tx.prepare("SELECT * FROM crates"));
+        try!(stmt.query(&[])).map(|row| Model::from_row(&row)).collect()
+    };
+    if crates.len() == 0 { return Ok(()) }
+
+    let checkout = match env::var_string("GIT_REPO_CHECKOUT") {
+        Ok(s) => Path::new(s),
+        Err(..) => {
+            println!("GIT_REPO_CHECKOUT isn't defined, so no checksums were \
+                      filled in from the index");
+            return Ok(())
+        }
+    };
+    for krate in crates.iter() {
+        let path = checkout.join(git::index_path(krate.name.as_slice()));
+        let contents = match File::open(&path).read_to_string() {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("no checksums for `{}`, its index file couldn't be \
+                          read: {}", krate.name, e);
+                continue
+            }
+        };
+        for line in contents.as_slice().lines() {
+            let entry: git::Crate = match json::decode(line) {
+                Ok(entry) => entry,
+                Err(..) => {
+                    println!("skipping an entry of `{}` which couldn't be \
+                              decoded: {}", krate.name, line);
+                    continue
+                }
+            };
+            try!(tx.execute("UPDATE versions SET checksum = $1
+                              WHERE crate_id = $2 AND num = $3",
+                            &[&entry.cksum, &krate.id, &entry.vers]));
+        }
+    }
+    Ok(())
+}
+
+// API tokens used to be stored as they were, both in `api_tokens` and as each
+// user's one `api_token`. The former are hashed, and the latter become their
+// `default` tokens.
+fn hash_api_tokens(tx: &postgres::Transaction) -> postgres::Result<()> {
+    try!(tx.execute("ALTER TABLE api_tokens ADD COLUMN token_prefix VARCHAR, \
+                     ADD COLUMN salt VARCHAR, ADD COLUMN token_hash VARCHAR",
+                    &[]));
+    let tokens: Vec<(i32, String)> = {
+        let stmt = try!(tx.prepare("SELECT id, token FROM api_tokens"));
+        try!(stmt.query(&[])).map(|row| {
+            (row.get("id"), row.get("token"))
+        }).collect()
+    };
+    for &(id, ref token) in tokens.iter() {
+        let digest = Digest::new(token.as_slice());
+        try!(tx.execute("UPDATE api_tokens
+                         SET token_prefix = $1, salt = $2, token_hash = $3
+                         WHERE id = $4",
+                        &[&digest.prefix, &digest.salt, &digest.hash, &id]));
+    }
+
+    let users: Vec<(i32, String)> = {
+        let stmt = try!(tx.prepare("SELECT id, api_token FROM users"));
+        try!(stmt.query(&[])).map(|row| {
+            (row.get("id"), row.get("api_token"))
+        }).collect()
+    };
+    let scopes = Scope::all().iter().map(|s| s.name()).collect::<Vec<_>>()
+                             .connect(",");
+    let now = cargo_registry::now();
+    for &(id, ref token) in users.iter() {
+        // Someone could have already made a token with the same name.
+        let stmt = try!(tx.prepare("SELECT 1 FROM api_tokens
+                                    WHERE user_id = $1 AND name = $2"));
+        let taken = try!(stmt.query(&[&id, &DEFAULT_TOKEN])).next().is_some();
+        let name = if taken {
+            format!("{}-migrated", DEFAULT_TOKEN)
+        } else {
+            DEFAULT_TOKEN.to_string()
+        };
+        let digest = Digest::new(token.as_slice());
+        try!(tx.execute("INSERT INTO api_tokens
+                         (user_id, name, token_prefix, salt, token_hash,
+                          scopes, created_at)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                        &[&id, &name, &digest.prefix, &digest.salt,
+                          &digest.hash, &scopes, &now]));
+    }
+
+    try!(tx.execute("ALTER TABLE api_tokens DROP COLUMN token", &[]));
+    try!(tx.execute("ALTER TABLE api_tokens ALTER COLUMN token_prefix \
+                     SET NOT NULL, ALTER COLUMN salt SET NOT NULL, \
+                     ALTER COLUMN token_hash SET NOT NULL", &[]));
+    try!(tx.execute("CREATE INDEX index_api_tokens_token_prefix \
+                     ON api_tokens (token_prefix)", &[]));
+    try!(tx.execute("ALTER TABLE users DROP COLUMN api_token", &[]));
+    Ok(())
+}
+
+// Everyone used to log in with GitHub, and users were known by their GitHub
+// login. That login becomes their first identity, and stays their login here.
+fn move_github_logins(tx: &postgres::Transaction) -> postgres::Result<()> {
+    try!(tx.execute("INSERT INTO identities
+                     (user_id, provider, uid, access_token, created_at)
+                     SELECT id, 'github', gh_login, gh_access_token, $1
+                     FROM users", &[&cargo_registry::now()]));
+    try!(tx.execute("ALTER TABLE users DROP COLUMN gh_access_token", &[]));
+    try!(tx.execute("ALTER TABLE users RENAME COLUMN gh_login TO login", &[]));
+    try!(tx.execute("ALTER TABLE users RENAME COLUMN gh_avatar TO avatar",
+                    &[]));
+    try!(tx.execute("ALTER INDEX index_users_gh_login \
+                     RENAME TO index_users_login", &[]));
+    Ok(())
+}
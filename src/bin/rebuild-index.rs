@@ -0,0 +1,158 @@
+// Rebuild the git index from the database.
+//
+// Every index file is regenerated from the crates, versions and dependencies
+// tables, and compared with the file in the checkout, once it's been brought
+// up to date with the index. All differences are reported, and if there are
+// any the fixed files can be committed and pushed. Files which aren't where a
+// crate's file would be, like `config.json`, are left alone. If the index or
+// the database changes before the fixed files are pushed, they aren't, and it
+// has to be run again.
+//
+// Usage:
+//      cargo run --bin rebuild-index
+//
+// The index is configured with the same `GIT_REPO_CHECKOUT` and
+// `GIT_HTTP_USER`/`GIT_HTTP_PWD` environment variables as the server.
+
+#![deny(warnings)]
+#![feature(io, core, os, env, path)]
+
+extern crate "cargo-registry" as cargo_registry;
+extern crate git2;
+extern crate postgres;
+
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::old_io::fs::{self, PathExtensions};
+use std::old_io::{self, File};
+
+use cargo_registry::{Crate, Model};
+use cargo_registry::{git, index};
+use cargo_registry::util::human;
+
+fn main() {
+    let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
+                                             &postgres::SslMode::None).unwrap();
+    let checkout = Path::new(env("GIT_REPO_CHECKOUT"));
+    let repo = git2::Repository::open(&checkout).unwrap();
+    git::fetch(&repo).unwrap();
+
+    let files = changes(&conn, &checkout, true);
+    if files.len() == 0 {
+        println!("the index is up to date");
+        return
+    }
+    print!("commit {} files? [y/N]: ", files.len());
+    let line = old_io::stdin().read_line().unwrap();
+    if !line.starts_with("y") { return }
+
+    // The index may well have moved on by the time the commit is pushed, in
+    // which case it's compared with the database all over again, rather than
+    // overwriting whatever was pushed in the meantime with what's above.
+    git::replace_files_with(&repo, "Rebuilding the index from the database",
+                            || {
+        let now = changes(&conn, &checkout, false);
+        if now != files {
+            return Err(human("the index or the database changed before the \
+                              rebuild could be pushed, so nothing was; run \
+                              this again to see what needs fixing now"))
+        }
+        Ok(now)
+    }).unwrap();
+}
+
+// The files in the checkout which don't match the database, with the contents
+// they should have, or `None` for those which shouldn't be there at all. Each
+// difference is printed if `verbose` is set.
+fn changes(conn: &postgres::Connection, checkout: &Path,
+           verbose: bool) -> Vec<(String, Option<String>)> {
+    let expected = {
+        let tx = conn.transaction().unwrap();
+        expected(&tx)
+    };
+    let actual = actual(checkout);
+
+    let mut files = Vec::new();
+    for (path, contents) in expected.iter() {
+        match actual.get(path) {
+            Some(old) if old == contents => {}
+            Some(old) => {
+                if verbose {
+                    println!("{} differs", path);
+                    diff(old.as_slice(), contents.as_slice());
+                }
+                files.push((path.clone(), Some(contents.clone())));
+            }
+            None => {
+                if verbose {
+                    println!("{} is missing", path);
+                    diff("", contents.as_slice());
+                }
+                files.push((path.clone(), Some(contents.clone())));
+            }
+        }
+    }
+    for (path, old) in actual.iter() {
+        if expected.contains_key(path) { continue }
+        if verbose {
+            println!("{} has no crate in the database", path);
+            diff(old.as_slice(), "");
+        }
+        files.push((path.clone(), None));
+    }
+    files
+}
+
+fn env(s: &str) -> String {
+    match env::var_string(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
+
+// The contents of every index file according to the database, keyed by path.
+fn expected(tx: &postgres::Transaction) -> BTreeMap<String, String> {
+    let stmt = tx.prepare("SELECT * FROM crates ORDER BY name ASC").unwrap();
+    let mut ret = BTreeMap::new();
+    for row in stmt.query(&[]).unwrap() {
+        let krate: Crate = Model::from_row(&row);
+        let (entries, _) = index::entries(tx, &krate).unwrap();
+        if entries.len() == 0 { continue }
+        ret.insert(git::index_path(krate.name.as_slice()),
+                   index::render(entries.as_slice()));
+    }
+    ret
+}
+
+// The contents of every index file in the checkout, keyed by path.
+fn actual(checkout: &Path) -> BTreeMap<String, String> {
+    let mut ret = BTreeMap::new();
+    for path in fs::walk_dir(checkout).unwrap() {
+        let rel = path.path_relative_from(checkout).unwrap();
+        let rel = rel.as_str().unwrap().to_string();
+        if !path.is_file() || !is_index_path(rel.as_slice()) { continue }
+        ret.insert(rel, File::open(&path).read_to_string().unwrap());
+    }
+    ret
+}
+
+// Whether `rel` is where the index file of some crate would be.
+fn is_index_path(rel: &str) -> bool {
+    let name = rel.split('/').last().unwrap();
+    name.len() > 0 && !name.starts_with(".") && git::index_path(name) == rel
+}
+
+// Prints the lines only found in one of the two versions of a file.
+fn diff(old: &str, new: &str) {
+    let old_lines = old.lines().collect::<HashSet<_>>();
+    let new_lines = new.lines().collect::<HashSet<_>>();
+    for line in old.lines().filter(|l| !new_lines.contains(l)) {
+        println!("  - {}", line);
+    }
+    for line in new.lines().filter(|l| !old_lines.contains(l)) {
+        println!("  + {}", line);
+    }
+    if old_lines == new_lines {
+        println!("  (the same lines, in a different order)");
+    }
+}
@@ -1,13 +1,12 @@
 #![deny(warnings)]
-#![feature(io, core, path, env)]
+#![feature(io, core, path, env, std_misc)]
 
 extern crate "cargo-registry" as cargo_registry;
 extern crate "conduit-middleware" as conduit_middleware;
 extern crate civet;
-extern crate git2;
 extern crate env_logger;
 
-use std::old_io::{self, fs, File};
+use std::old_io::File;
 use std::env;
 use std::sync::Arc;
 use std::sync::mpsc::channel;
@@ -15,54 +14,27 @@ use civet::Server;
 
 fn main() {
     env_logger::init().unwrap();
+    let config = cargo_registry::Config::from_env();
     let url = env("GIT_REPO_URL");
-    let checkout = Path::new(env("GIT_REPO_CHECKOUT"));
-
-    let repo = match git2::Repository::open(&checkout) {
-        Ok(r) => r,
-        Err(..) => {
-            let _ = fs::rmdir_recursive(&checkout);
-            fs::mkdir_recursive(&checkout, old_io::USER_DIR).unwrap();
-            let mut cb = git2::RemoteCallbacks::new();
-            cb.credentials(cargo_registry::git::credentials);
-            git2::build::RepoBuilder::new()
-                                     .remote_callbacks(cb)
-                                     .clone(url.as_slice(), &checkout).unwrap()
-        }
-    };
-    let mut cfg = repo.config().unwrap();
-    cfg.set_str("user.name", "bors").unwrap();
-    cfg.set_str("user.email", "bors@rust-lang.org").unwrap();
+    let repo = cargo_registry::git::checkout(url.as_slice(), &config).unwrap();
 
     let heroku = env::var("HEROKU").is_some();
-    let cargo_env = if heroku {
-        cargo_registry::Env::Production
-    } else {
-        cargo_registry::Env::Development
-    };
-    let config = cargo_registry::Config {
-        s3_bucket: env("S3_BUCKET"),
-        s3_access_key: env("S3_ACCESS_KEY"),
-        s3_secret_key: env("S3_SECRET_KEY"),
-        s3_region: env::var_string("S3_REGION").ok(),
-        s3_proxy: None,
-        session_key: env("SESSION_KEY"),
-        git_repo_checkout: checkout,
-        gh_client_id: env("GH_CLIENT_ID"),
-        gh_client_secret: env("GH_CLIENT_SECRET"),
-        db_url: env("DATABASE_URL"),
-        env: cargo_env,
-        max_upload_size: 10 * 1024 * 1024,
-    };
+    let port = cargo_registry::config::port();
+
+    // The index's config.json tells clients where to find everything, so it
+    // has to agree with how the server is set up.
+    if let Err(e) = cargo_registry::git::sync_config(&repo, &config) {
+        panic!("refusing to start: {}", e)
+    }
+
     let app = cargo_registry::App::new(&config);
     let app = cargo_registry::middleware(Arc::new(app));
 
-    let port = if heroku {
-        8888
+    let threads = if config.env == cargo_registry::Env::Development {
+        1
     } else {
-        env::var_string("PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(8888)
+        8
     };
-    let threads = if cargo_env == cargo_registry::Env::Development {1} else {8};
     let _a = Server::start(civet::Config { port: port, threads: threads }, app);
     println!("listening on port {}", port);
     if heroku {
@@ -0,0 +1,41 @@
+// Squash the history of the git index into a single commit.
+//
+// The index gets a commit for every publish and yank, so its history grows
+// forever. This queues a job for the worker to push the current history to a
+// branch named after the time, `archive/YYYY-MM-DD-HHMMSS`, and then replace
+// it with one root commit holding the same files. The worker is what pushes
+// everything else to the index, so no changes are lost in the meantime.
+//
+// It's meant to be run every so often, for example from a scheduler.
+//
+// Usage:
+//      cargo run --bin squash-index
+
+#![deny(warnings)]
+#![feature(core, env)]
+
+extern crate "cargo-registry" as cargo_registry;
+extern crate postgres;
+extern crate time;
+
+use std::env;
+
+use cargo_registry::jobs::{self, Job};
+
+fn main() {
+    let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
+                                             &postgres::SslMode::None).unwrap();
+    let now = time::now_utc();
+    let archive = format!("archive/{}",
+                          time::strftime("%Y-%m-%d-%H%M%S", &now).unwrap());
+    jobs::enqueue(&conn, &Job::SquashIndex(archive.clone()), None).unwrap();
+    println!("queued squashing the index, its history will be on `{}`",
+             archive);
+}
+
+fn env(s: &str) -> String {
+    match env::var_string(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
@@ -138,6 +138,7 @@ mod test {
     use semver;
 
     use cargo_registry::{Version, Crate, User};
+    use cargo_registry::user::Profile;
 
     fn conn() -> postgres::Connection {
         postgres::Connection::connect(::env("TEST_DATABASE_URL").as_slice(),
@@ -145,8 +146,8 @@ mod test {
     }
 
     fn user(conn: &postgres::Transaction) -> User{
-        User::find_or_insert(conn, "login", None, None, None,
-                             "access_token", "api_token").unwrap()
+        User::find_or_insert(conn, "github",
+                             &Profile::new("login")).unwrap()
     }
 
     fn crate_downloads(tx: &postgres::Transaction, id: i32, expected: usize) {
@@ -167,7 +168,7 @@ mod test {
                                           &None).unwrap();
         let version = Version::insert(&tx, krate.id,
                                       &semver::Version::parse("1.0.0").unwrap(),
-                                      &HashMap::new(), &[]).unwrap();
+                                      &HashMap::new(), &[], None).unwrap();
         tx.execute("INSERT INTO version_downloads \
                     (version_id, downloads, counted, date, processed)
                     VALUES ($1, 1, 0, current_date, false)",
@@ -194,7 +195,7 @@ mod test {
                                           &None, &None).unwrap();
         let version = Version::insert(&tx, krate.id,
                                       &semver::Version::parse("1.0.0").unwrap(),
-                                      &HashMap::new(), &[]).unwrap();
+                                      &HashMap::new(), &[], None).unwrap();
         tx.execute("INSERT INTO version_downloads \
                     (version_id, downloads, counted, date, processed)
                     VALUES ($1, 2, 1, current_date, false)",
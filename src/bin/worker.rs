@@ -0,0 +1,59 @@
+// Run the background jobs queued by the server, such as updating the git
+// index and uploading tarballs to storage.
+//
+// Usage:
+//      cargo run --bin worker
+//
+// The index is configured with the same `GIT_REPO_URL`, `GIT_REPO_CHECKOUT`,
+// `GIT_SIGNING_KEY`/`GIT_SIGNING_FORMAT` and `GIT_HTTP_USER`/`GIT_HTTP_PWD`
+// environment variables as the server, and storage is configured just as it
+// is for the server, from the rest of them.
+
+#![deny(warnings)]
+#![feature(io, core, path, env, std_misc)]
+
+extern crate "cargo-registry" as cargo_registry;
+extern crate env_logger;
+extern crate postgres;
+
+use std::env;
+use std::old_io::timer;
+use std::time::Duration;
+
+use cargo_registry::{git, jobs, storage, Config};
+
+// How many seconds changes to the index are held back for, so that a burst
+// of them is pushed in a single commit.
+static BATCH_WINDOW: i64 = 5;
+
+fn main() {
+    env_logger::init().unwrap();
+    let config = Config::from_env();
+    let repo = git::checkout(env("GIT_REPO_URL").as_slice(), &config).unwrap();
+    let storage = storage::new(&config);
+    let conn = postgres::Connection::connect(env("DATABASE_URL").as_slice(),
+                                             &postgres::SslMode::None).unwrap();
+
+    loop {
+        // Each job, or batch of them, is run in its own transaction, which
+        // keeps them locked while they run and records how they went.
+        let ran = {
+            let tx = conn.transaction().unwrap();
+            let ran = jobs::run_next(&tx, &repo, &*storage,
+                                     Duration::seconds(BATCH_WINDOW)).unwrap();
+            tx.set_commit();
+            tx.finish().unwrap();
+            ran
+        };
+        if !ran {
+            timer::sleep(Duration::seconds(1));
+        }
+    }
+}
+
+fn env(s: &str) -> String {
+    match env::var_string(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
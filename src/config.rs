@@ -1,3 +1,13 @@
+use std::env;
+use std::time::Duration;
+
+use s3;
+
+use Env;
+use git::SigningKey;
+use storage::Backend;
+use user::identity::OAuthConfig;
+
 #[derive(Clone)]
 pub struct Config {
     pub s3_bucket: String,
@@ -5,11 +15,138 @@ pub struct Config {
     pub s3_access_key: String,
     pub s3_secret_key: String,
     pub s3_proxy: Option<String>,
+    pub s3_signature: s3::Signature,
+    pub s3_endpoint: Option<String>,
+    pub s3_addressing: s3::Addressing,
+    pub s3_part_size: usize,
+    pub s3_url_expiry: Option<Duration>,
     pub session_key: String,
     pub git_repo_checkout: Path,
+    pub git_signing_key: Option<SigningKey>,
+    pub api_url: String,
+    pub dl_url: String,
+    pub index_auth_required: bool,
     pub gh_client_id: String,
     pub gh_client_secret: String,
+    pub oidc: Option<OAuthConfig>,
+    pub local_accounts: bool,
     pub db_url: String,
-    pub env: ::Env,
+    pub env: Env,
     pub max_upload_size: usize,
+    pub max_unpack_size: usize,
+    pub storage: Backend,
+}
+
+impl Config {
+    /// Reads the configuration of the server from the environment variables
+    /// described in the README, panicking if any which are required are
+    /// missing or don't make sense. The other binaries use this too, so
+    /// they're set up in just the same way as the server.
+    pub fn from_env() -> Config {
+        let heroku = env::var("HEROKU").is_some();
+        let local_storage = env::var_string("LOCAL_STORAGE").ok();
+        let storage = match local_storage {
+            Some(ref dir) => Backend::Local(Path::new(dir)),
+            None => Backend::S3,
+        };
+        let s3_env = |s: &str| {
+            if local_storage.is_some() {
+                env::var_string(s).unwrap_or(String::new())
+            } else {
+                var(s)
+            }
+        };
+        let api_url = env::var_string("API_URL").unwrap_or_else(|_| {
+            format!("http://localhost:{}", port())
+        });
+        Config {
+            s3_bucket: s3_env("S3_BUCKET"),
+            s3_access_key: s3_env("S3_ACCESS_KEY"),
+            s3_secret_key: s3_env("S3_SECRET_KEY"),
+            s3_region: env::var_string("S3_REGION").ok(),
+            s3_proxy: None,
+            s3_signature: match env::var_string("S3_SIGNATURE").ok() {
+                None => s3::Signature::V2,
+                Some(s) => match s3::Signature::from_name(s.as_slice()) {
+                    Some(signature) => signature,
+                    None => panic!("S3_SIGNATURE must be `v2` or `v4`, not \
+                                    `{}`", s),
+                },
+            },
+            s3_endpoint: env::var_string("S3_ENDPOINT").ok(),
+            s3_addressing: if env::var("S3_PATH_STYLE").is_some() {
+                s3::Addressing::Path
+            } else {
+                s3::Addressing::VirtualHost
+            },
+            s3_part_size: s3::MIN_PART_SIZE,
+            s3_url_expiry: env::var_string("S3_URL_EXPIRY").ok().map(|s| {
+                match s.parse() {
+                    Ok(n) if n > 0 && n <= s3::MAX_PRESIGNED_EXPIRY_SECS => {
+                        Duration::seconds(n)
+                    }
+                    _ => panic!("S3_URL_EXPIRY must be a number of seconds \
+                                 between 1 and {} (7 days), not `{}`",
+                                s3::MAX_PRESIGNED_EXPIRY_SECS, s),
+                }
+            }),
+            session_key: var("SESSION_KEY"),
+            git_repo_checkout: Path::new(var("GIT_REPO_CHECKOUT")),
+            git_signing_key: env::var_string("GIT_SIGNING_KEY").ok().map(|key| {
+                let format = env::var_string("GIT_SIGNING_FORMAT").ok();
+                match format.as_ref().map(|s| s.as_slice()) {
+                    None | Some("openpgp") => {
+                        SigningKey::Gpg { key: key, home: None }
+                    }
+                    Some("ssh") => SigningKey::Ssh(Path::new(key)),
+                    Some(s) => panic!("GIT_SIGNING_FORMAT must be `openpgp` \
+                                       or `ssh`, not `{}`", s),
+                }
+            }),
+            api_url: api_url.clone(),
+            dl_url: env::var_string("DL_URL").unwrap_or_else(|_| {
+                format!("{}/api/v1/crates", api_url)
+            }),
+            index_auth_required: env::var("INDEX_AUTH_REQUIRED").is_some(),
+            gh_client_id: var("GH_CLIENT_ID"),
+            gh_client_secret: var("GH_CLIENT_SECRET"),
+            oidc: env::var_string("OIDC_CLIENT_ID").ok().map(|id| {
+                OAuthConfig {
+                    name: env::var_string("OIDC_NAME").unwrap_or_else(|_| {
+                        "oidc".to_string()
+                    }),
+                    client_id: id,
+                    client_secret: var("OIDC_CLIENT_SECRET"),
+                    authorize_url: var("OIDC_AUTHORIZE_URL"),
+                    token_url: var("OIDC_TOKEN_URL"),
+                    userinfo_url: var("OIDC_USERINFO_URL"),
+                    redirect_url: var("OIDC_REDIRECT_URL"),
+                }
+            }),
+            local_accounts: env::var("LOCAL_ACCOUNTS").is_some(),
+            db_url: var("DATABASE_URL"),
+            env: if heroku {Env::Production} else {Env::Development},
+            max_upload_size: 10 * 1024 * 1024,
+            max_unpack_size: 50 * 1024 * 1024,
+            storage: storage,
+        }
+    }
+}
+
+/// The port the server listens on, which is always 8888 on Heroku and can
+/// otherwise be set with `PORT`.
+pub fn port() -> u16 {
+    if env::var("HEROKU").is_some() {
+        8888
+    } else {
+        env::var_string("PORT").ok().and_then(|s| s.parse().ok())
+                               .unwrap_or(8888)
+    }
+}
+
+fn var(s: &str) -> String {
+    match env::var_string(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
 }
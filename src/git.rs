@@ -1,33 +1,28 @@
-use std::ascii::AsciiExt;
-use std::collections::hash_map::{HashMap, Entry};
+use std::collections::BTreeMap;
 use std::env;
 use std::old_io::fs::PathExtensions;
-use std::old_io::util;
-use std::old_io::{Command, BufferedReader, Process, IoResult, File, fs};
+use std::old_io::process::Command;
+use std::old_io::{File, TempDir, fs};
 use std::old_io;
 
-use semver;
-use flate2::reader::GzDecoder;
 use git2;
-use rustc_serialize::json;
+use rustc_serialize::json::{self, Json};
 
-use conduit::{Request, Response};
-
-use app::{App, RequestApp};
+use Config;
 use dependency::Kind;
-use util::{CargoResult, internal, ChainError};
+use util::{CargoResult, internal, ChainError, exec};
 
-#[derive(RustcEncodable, RustcDecodable)]
+#[derive(RustcEncodable, RustcDecodable, Clone)]
 pub struct Crate {
     pub name: String,
     pub vers: String,
     pub deps: Vec<Dependency>,
     pub cksum: String,
-    pub features: HashMap<String, Vec<String>>,
+    pub features: BTreeMap<String, Vec<String>>,
     pub yanked: Option<bool>,
 }
 
-#[derive(RustcEncodable, RustcDecodable)]
+#[derive(RustcEncodable, RustcDecodable, Clone)]
 pub struct Dependency {
     pub name: String,
     pub req: String,
@@ -38,150 +33,402 @@ pub struct Dependency {
     pub kind: Option<Kind>,
 }
 
-pub fn serve_index(req: &mut Request) -> CargoResult<Response> {
-    let mut cmd = Command::new("git");
-    cmd.arg("http-backend");
-
-    // Required environment variables
-    cmd.env("REQUEST_METHOD",
-            format!("{:?}", req.method()).as_slice().to_ascii_uppercase());
-    cmd.env("GIT_PROJECT_ROOT", &req.app().git_repo_checkout);
-    cmd.env("PATH_INFO", req.path().replace("/git/index", ""));
-    cmd.env("REMOTE_USER", "");
-    cmd.env("REMOTE_ADDR", req.remote_ip().to_string());
-    cmd.env("QUERY_STRING", req.query_string().unwrap_or(""));
-    cmd.env("CONTENT_TYPE", header(req, "Content-Type"));
-    cmd.stderr(::std::old_io::process::InheritFd(2));
-    let mut p = try!(cmd.spawn());
-
-    // Pass in the body of the request (if any)
-    //
-    // As part of the CGI interface we're required to take care of gzip'd
-    // requests. I'm not totally sure that this sequential copy is the best
-    // thing to do or actually correct...
-    if header(req, "Content-Encoding") == "gzip" {
-        let mut body = GzDecoder::new(req.body());
-        try!(util::copy(&mut body, &mut p.stdin.take().unwrap()));
-    } else {
-        try!(util::copy(&mut req.body(), &mut p.stdin.take().unwrap()));
-    }
-
-    // Parse the headers coming out, and the pass through the rest of the
-    // process back down the stack.
-    //
-    // Note that we have to be careful to not drop the process which will wait
-    // for the process to exit (and we haven't read stdout)
-    let mut rdr = BufferedReader::new(p.stdout.take().unwrap());
-
-    let mut headers = HashMap::new();
-    for line in rdr.lines() {
-        let line = try!(line);
-        if line.as_slice() == "\r\n" { break }
-
-        let mut parts = line.as_slice().splitn(2, ':');
-        let key = parts.next().unwrap();
-        let value = parts.next().unwrap();
-        let value = &value[1 .. value.len() - 2];
-        match headers.entry(key.to_string()) {
-            Entry::Occupied(e) => e.into_mut(),
-            Entry::Vacant(e) => e.insert(Vec::new()),
-        }.push(value.to_string());
-    }
-
-    let (status_code, status_desc) = {
-        let line = headers.remove("Status").unwrap_or(Vec::new());
-        let line = line.into_iter().next().unwrap_or(String::new());
-        let mut parts = line.as_slice().splitn(1, ' ');
-        (parts.next().unwrap_or("").parse().unwrap_or(200),
-         match parts.next() {
-             Some("Not Found") => "Not Found",
-             _ => "Ok",
-         })
+fn index_file(base: &Path, name: &str) -> Path {
+    base.join(index_path(name))
+}
+
+/// Returns the path of the file listing the versions of the crate `name`,
+/// relative to the root of the index.
+pub fn index_path(name: &str) -> String {
+    let name = name.chars().map(|c| c.to_lowercase()).collect::<String>();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[0..2], &name[2..4], name),
+    }
+}
+
+/// A key which the commits pushed to the index are signed with.
+#[derive(Clone)]
+pub enum SigningKey {
+    /// A GPG key, looked up in the keyring in `home` if one's given, or the
+    /// one `gpg` uses by default otherwise.
+    Gpg { key: String, home: Option<Path> },
+    /// The path of an SSH private key.
+    Ssh(Path),
+}
+
+/// Opens the index checkout at `config.git_repo_checkout`, first cloning it
+/// from `url` if there isn't a usable repository there.
+///
+/// Commits are signed with `config.git_signing_key` if there is one. A GPG
+/// key has to name exactly one secret key, which every signature is then
+/// checked to have been made by.
+pub fn checkout(url: &str, config: &Config) -> CargoResult<git2::Repository> {
+    let checkout = &config.git_repo_checkout;
+    let repo = match git2::Repository::open(checkout) {
+        Ok(r) => r,
+        Err(..) => {
+            let _ = fs::rmdir_recursive(checkout);
+            try!(fs::mkdir_recursive(checkout, old_io::USER_DIR));
+            let mut cb = git2::RemoteCallbacks::new();
+            cb.credentials(credentials);
+            try!(git2::build::RepoBuilder::new()
+                                          .remote_callbacks(cb)
+                                          .clone(url, checkout))
+        }
     };
+    {
+        let mut cfg = try!(repo.config());
+        try!(cfg.set_str("user.name", "bors"));
+        try!(cfg.set_str("user.email", "bors@rust-lang.org"));
+        // `gpg` is pointed at a keyring of its own with this, as git itself
+        // has no setting for it.
+        try!(cfg.set_str("registry.gnupghome", ""));
+        match config.git_signing_key {
+            Some(SigningKey::Gpg { ref key, ref home }) => {
+                let fingerprint = try!(fingerprint(key.as_slice(),
+                                                   home.as_ref()));
+                try!(cfg.set_str("user.signingkey", fingerprint.as_slice()));
+                try!(cfg.set_str("gpg.format", "openpgp"));
+                if let Some(ref home) = *home {
+                    let home = try!(home.as_str().chain_error(|| {
+                        internal("the GPG home directory isn't unicode")
+                    }));
+                    try!(cfg.set_str("registry.gnupghome", home));
+                }
+                try!(cfg.set_bool("commit.gpgsign", true));
+            }
+            Some(SigningKey::Ssh(ref key)) => {
+                let key = try!(key.as_str().chain_error(|| {
+                    internal("the path of the signing key isn't unicode")
+                }));
+                try!(cfg.set_str("user.signingkey", key));
+                try!(cfg.set_str("gpg.format", "ssh"));
+                try!(cfg.set_bool("commit.gpgsign", true));
+            }
+            None => try!(cfg.set_bool("commit.gpgsign", false)),
+        }
+    }
+    Ok(repo)
+}
+
+// Returns the fingerprint of the one secret GPG key which `key` names.
+fn fingerprint(key: &str, home: Option<&Path>) -> CargoResult<String> {
+    let mut list = gpg(home);
+    list.arg("--batch").arg("--with-colons").arg("--list-secret-keys")
+        .arg(key);
+    let output = try!(exec(&list));
+    let output = String::from_utf8_lossy(output.output.as_slice());
 
-    struct ProcessAndBuffer<R> { _p: Process, buf: BufferedReader<R> }
-    impl<R: Reader> Reader for ProcessAndBuffer<R> {
-        fn read(&mut self, b: &mut [u8]) -> IoResult<usize> { self.buf.read(b) }
+    // Each key's fingerprint is on the first `fpr` line after its `sec` line,
+    // and those of its subkeys come later.
+    let mut fingerprints = Vec::new();
+    let mut primary = false;
+    for line in output.lines() {
+        let fields = line.split(':').collect::<Vec<_>>();
+        match fields[0] {
+            "sec" => primary = true,
+            "fpr" if primary && fields.len() > 9 => {
+                fingerprints.push(fields[9].to_string());
+                primary = false;
+            }
+            _ => {}
+        }
+    }
+    match fingerprints.len() {
+        1 => Ok(fingerprints.pop().unwrap()),
+        0 => Err(internal(format!("there's no secret GPG key `{}`", key))),
+        _ => Err(internal(format!("`{}` names more than one secret GPG key, \
+                                   so its fingerprint has to be used",
+                                  key))),
     }
-    return Ok(Response {
-        status: (status_code, status_desc),
-        headers: headers,
-        body: Box::new(ProcessAndBuffer { _p: p, buf: rdr }),
-    });
+}
 
-    fn header<'a>(req: &'a Request, name: &str) -> &'a str {
-        let h = req.headers().find(name).unwrap_or(Vec::new());
-        h.as_slice().get(0).map(|s| *s).unwrap_or("")
+// Returns a command to run `gpg` with the keyring in `home`.
+fn gpg(home: Option<&Path>) -> Command {
+    let mut cmd = Command::new("gpg");
+    if let Some(home) = home {
+        cmd.env("GNUPGHOME", home);
     }
+    cmd
 }
 
-fn index_file(base: &Path, name: &str) -> Path {
-    let name = name.chars().map(|c| c.to_lowercase()).collect::<String>();
-    match name.len() {
-        1 => base.join("1").join(name),
-        2 => base.join("2").join(name),
-        3 => base.join("3").join(&name[..1]).join(name),
-        _ => base.join(&name[0..2])
-                 .join(&name[2..4])
-                 .join(name),
+/// A change to a single entry of the index.
+pub enum Change {
+    /// Adds a version of a crate, replacing its entry if there already is one.
+    Add(Crate),
+    /// Sets the `yanked` flag of a crate's version, which must already be in
+    /// the index.
+    Yank(String, String, bool),
+}
+
+impl Change {
+    // Makes the change to the checkout at `repo_path`, returning a line
+    // describing it and the file it touched.
+    fn apply(&self, repo_path: &Path) -> CargoResult<(String, Path)> {
+        match *self {
+            Change::Add(ref krate) => {
+                let dst = index_file(repo_path, krate.name.as_slice());
+                try!(fs::mkdir_recursive(&dst.dir_path(), old_io::USER_RWX));
+                let prev = if dst.exists() {
+                    try!(File::open(&dst).read_to_string())
+                } else {
+                    String::new()
+                };
+                let s = json::encode(krate).unwrap();
+                let mut found = false;
+                let mut new = prev.as_slice().lines().map(|line| {
+                    match json::decode::<Crate>(line) {
+                        Ok(ref c) if c.vers == krate.vers => {
+                            found = true;
+                            s.clone()
+                        }
+                        _ => line.to_string(),
+                    }
+                }).collect::<Vec<String>>();
+                if !found { new.push(s) }
+                let new = new.as_slice().connect("\n");
+                try!(File::create(&dst).write_line(new.as_slice()));
+
+                Ok((format!("Updating crate `{}#{}`", krate.name, krate.vers),
+                    dst))
+            }
+            Change::Yank(ref krate, ref version, yanked) => {
+                let dst = index_file(repo_path, krate.as_slice());
+                let prev = try!(File::open(&dst).read_to_string());
+                let mut found = false;
+                let new = prev.as_slice().lines().map(|line| {
+                    let git_crate = json::decode::<Crate>(line);
+                    let mut git_crate = try!(git_crate.map_err(|_| {
+                        internal(format!("couldn't decode: `{}`", line))
+                    }));
+                    if git_crate.name != *krate || git_crate.vers != *version {
+                        return Ok(line.to_string())
+                    }
+                    found = true;
+                    git_crate.yanked = Some(yanked);
+                    Ok(json::encode(&git_crate).unwrap())
+                }).collect::<CargoResult<Vec<String>>>();
+                let new = try!(new).as_slice().connect("\n");
+                if !found {
+                    return Err(internal(format!("`{}#{}` is not in the index",
+                                                krate, version)))
+                }
+                try!(File::create(&dst).write_line(new.as_slice()));
+
+                Ok((format!("{} crate `{}#{}`",
+                            if yanked {"Yanking"} else {"Unyanking"},
+                            krate, version),
+                    dst))
+            }
+        }
     }
 }
 
-pub fn add_crate(app: &App, krate: &Crate) -> CargoResult<()> {
-    let repo = app.git_repo.lock().unwrap();
-    let repo = &*repo;
+/// Makes all of `changes` to the index, and pushes them as a single commit
+/// whose message lists each one.
+///
+/// A change which can't be made doesn't stop the others, so the outcome of
+/// each is returned separately, in the same order. An error for the whole
+/// batch means nothing was pushed.
+pub fn apply(repo: &git2::Repository, changes: &[Change])
+             -> CargoResult<Vec<CargoResult<()>>> {
     let repo_path = repo.path().dir_path();
-    let dst = index_file(&repo_path, krate.name.as_slice());
-
-    commit_and_push(repo, || {
-        // Add the crate to its relevant file
-        try!(fs::mkdir_recursive(&dst.dir_path(), old_io::USER_RWX));
-        let prev = if dst.exists() {
-            try!(File::open(&dst).read_to_string())
+    let mut results = Vec::new();
+    try!(commit_and_push(repo, || {
+        results = Vec::new();
+        let mut msgs = Vec::new();
+        let mut dsts = Vec::new();
+        for change in changes.iter() {
+            match change.apply(&repo_path) {
+                Ok((msg, dst)) => {
+                    msgs.push(msg);
+                    dsts.push(dst);
+                    results.push(Ok(()));
+                }
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        let msg = if msgs.len() == 1 {
+            msgs.pop().unwrap()
         } else {
-            String::new()
+            format!("Updating {} crate versions\n\n{}", msgs.len(),
+                    msgs.connect("\n"))
         };
-        let s = json::encode(krate).unwrap();
-        let new = prev + s.as_slice();
-        try!(File::create(&dst).write_line(new.as_slice()));
+        Ok((msg, dsts))
+    }));
+    Ok(results)
+}
 
-        Ok((format!("Updating crate `{}#{}`", krate.name, krate.vers),
-            dst.clone()))
-    })
+/// Overwrites the index files at `files`, given relative to the root of the
+/// index, and deletes those without any contents. The changes are committed
+/// with the message `msg` and pushed.
+pub fn replace_files(repo: &git2::Repository, msg: &str,
+                     files: &[(String, Option<String>)]) -> CargoResult<()> {
+    replace_files_with(repo, msg, || Ok(files.to_vec()))
 }
 
-pub fn yank(app: &App, krate: &str, version: &semver::Version,
-            yanked: bool) -> CargoResult<()> {
-    let repo = app.git_repo.lock().unwrap();
-    let repo = &*repo;
+/// Like `replace_files`, but the files are worked out by calling `files`
+/// each time the commit is tried. It's tried again whenever the index has
+/// moved on, so `files` sees the checkout as it is after each update.
+pub fn replace_files_with<F>(repo: &git2::Repository, msg: &str,
+                             mut files: F) -> CargoResult<()>
+    where F: FnMut() -> CargoResult<Vec<(String, Option<String>)>>
+{
     let repo_path = repo.path().dir_path();
-    let dst = index_file(&repo_path, krate);
-
     commit_and_push(repo, || {
-        let prev = try!(File::open(&dst).read_to_string());
-        let new = prev.as_slice().lines().map(|line| {
-            let mut git_crate = try!(json::decode::<Crate>(line).map_err(|_| {
-                internal(format!("couldn't decode: `{}`", line))
-            }));
-            if git_crate.name.as_slice() != krate ||
-               git_crate.vers.to_string() != version.to_string() {
-                return Ok(line.to_string())
+        let mut paths = Vec::new();
+        for &(ref file, ref contents) in try!(files()).iter() {
+            let dst = repo_path.join(file.as_slice());
+            match *contents {
+                Some(ref contents) => {
+                    try!(fs::mkdir_recursive(&dst.dir_path(),
+                                             old_io::USER_RWX));
+                    try!(File::create(&dst).write_str(contents.as_slice()));
+                }
+                None if dst.exists() => try!(fs::unlink(&dst)),
+                None => {}
             }
-            git_crate.yanked = Some(yanked);
-            Ok(json::encode(&git_crate).unwrap())
-        }).collect::<CargoResult<Vec<String>>>();
-        let new = try!(new).as_slice().connect("\n");
-        try!(File::create(&dst).write_line(new.as_slice()));
-
-        Ok((format!("{} crate `{}#{}`",
-                    if yanked {"Yanking"} else {"Unyanking"},
-                    krate, version),
-            dst.clone()))
+            paths.push(dst);
+        }
+        Ok((msg.to_string(), paths))
     })
 }
 
+/// Returns what the index's `config.json` should contain, which tells clients
+/// where to download crates from and where the API is.
+pub fn config_json(config: &Config) -> Json {
+    let mut map = BTreeMap::new();
+    map.insert("dl".to_string(), Json::String(config.dl_url.clone()));
+    map.insert("api".to_string(), Json::String(config.api_url.clone()));
+    if config.index_auth_required {
+        map.insert("auth-required".to_string(), Json::Boolean(true));
+    }
+    Json::Object(map)
+}
+
+/// Makes sure the index's `config.json` matches `config`, committing and
+/// pushing a new one if it doesn't. It's an error for the file to still
+/// disagree once the index has been fetched again afterwards, which means
+/// something else is pushing a different one.
+pub fn sync_config(repo: &git2::Repository,
+                   config: &Config) -> CargoResult<()> {
+    let expected = config_json(config);
+    let dst = repo.path().dir_path().join("config.json");
+
+    // Compare against what's in the index, not a stale checkout.
+    try!(fetch(repo));
+
+    try!(commit_and_push(repo, || {
+        if try!(read_config(&dst)).as_ref() == Some(&expected) {
+            return Ok((String::new(), Vec::new()))
+        }
+        let contents = format!("{}\n", json::as_pretty_json(&expected));
+        try!(File::create(&dst).write_str(contents.as_slice()));
+        Ok(("Updating config.json".to_string(), vec![dst.clone()]))
+    }));
+
+    try!(fetch(repo));
+    match try!(read_config(&dst)) {
+        Some(ref json) if *json == expected => Ok(()),
+        _ => Err(internal("the index's config.json doesn't agree with the \
+                           registry's configuration")),
+    }
+}
+
+// Reads the `config.json` at `path`, if there's one there which is valid.
+fn read_config(path: &Path) -> CargoResult<Option<Json>> {
+    if !path.exists() { return Ok(None) }
+    let contents = try!(File::open(path).read_to_string());
+    Ok(Json::from_str(contents.as_slice()).ok())
+}
+
+/// Squashes the history of the index into a single root commit with the same
+/// contents, and force-pushes it. The old history is pushed to the branch
+/// `archive` first, so nothing is lost.
+///
+/// Nothing pushed by anything else is thrown away: if the index has moved on
+/// by the time the squashed commit is ready, it isn't pushed and this fails,
+/// so it can be tried again later.
+pub fn squash(repo: &git2::Repository, archive: &str) -> CargoResult<()> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    let mut origin = try!(repo.find_remote("origin"));
+    origin.set_callbacks(callbacks.credentials(credentials));
+
+    // Start from the latest history there is.
+    try!(fetch(repo));
+    let head = try!(repo.head()).target().unwrap();
+
+    // git push --force origin master:$archive
+    {
+        let mut push = try!(origin.push());
+        try!(push.add_refspec(format!("+refs/heads/master:refs/heads/{}",
+                                      archive).as_slice()));
+        try!(push.finish());
+        if try!(push.statuses()).iter().any(|s| s.message.is_some()) {
+            return Err(internal(format!("failed to push the archive branch \
+                                         `{}`", archive)))
+        }
+    }
+
+    // Commit the same tree without any parents, and move master to it.
+    let tree = try!(try!(repo.find_commit(head)).tree());
+    let msg = format!("Collapse index into one commit\n\n\
+                       Previous HEAD was {}, now on the `{}` branch",
+                      head, archive);
+    let root = try!(commit(repo, msg.as_slice(), &tree, &[]));
+    let obj = try!(repo.find_object(root, None));
+    try!(repo.reset(&obj, git2::ResetType::Hard, None, None, None));
+
+    // Only replace the history which was squashed. libgit2 can't make the
+    // push itself conditional, so this is checked just before it instead.
+    if try!(remote_master(&mut origin)) != Some(head) {
+        let obj = try!(repo.find_object(head, None));
+        try!(repo.reset(&obj, git2::ResetType::Hard, None, None, None));
+        return Err(internal("the index changed while it was being squashed"))
+    }
+
+    // git push --force origin master
+    let mut push = try!(origin.push());
+    try!(push.add_refspec("+refs/heads/master"));
+    try!(push.finish());
+    if try!(push.statuses()).iter().any(|s| s.message.is_some()) {
+        return Err(internal("failed to push the squashed index"))
+    }
+    try!(push.update_tips(None, None));
+    Ok(())
+}
+
+// Asks `origin` where its master branch is.
+fn remote_master(origin: &mut git2::Remote) -> CargoResult<Option<git2::Oid>> {
+    try!(origin.connect(git2::Direction::Fetch));
+    let head = try!(origin.list()).iter().find(|head| {
+        head.name() == "refs/heads/master"
+    }).map(|head| head.oid());
+    origin.disconnect();
+    Ok(head)
+}
+
+/// Brings the checkout up to date with the index, throwing away anything in it
+/// which hasn't been pushed.
+pub fn fetch(repo: &git2::Repository) -> CargoResult<()> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    let mut origin = try!(repo.find_remote("origin"));
+    origin.set_callbacks(callbacks.credentials(credentials));
+
+    // git fetch origin && git reset --hard
+    try!(origin.add_fetch("refs/heads/*:refs/heads/*"));
+    try!(origin.fetch(&[], None, None));
+    let head = try!(repo.head()).target().unwrap();
+    let obj = try!(repo.find_object(head, None));
+    try!(repo.reset(&obj, git2::ResetType::Hard, None, None, None));
+    Ok(())
+}
+
 fn commit_and_push<F>(repo: &git2::Repository, mut f: F) -> CargoResult<()>
-    where F: FnMut() -> CargoResult<(String, Path)>
+    where F: FnMut() -> CargoResult<(String, Vec<Path>)>
 {
     let repo_path = repo.path().dir_path();
 
@@ -190,11 +437,19 @@ fn commit_and_push<F>(repo: &git2::Repository, mut f: F) -> CargoResult<()>
     // race to commit the changes. For now we just cap out the maximum number of
     // retries at a fixed number.
     for _ in range(0, 20) {
-        let (msg, dst) = try!(f());
+        let (msg, dsts) = try!(f());
+        if dsts.len() == 0 { return Ok(()) }
 
-        // git add $file
+        // git add $files, or git rm for the ones which are gone
         let mut index = try!(repo.index());
-        try!(index.add_path(&dst.path_relative_from(&repo_path).unwrap()));
+        for dst in dsts.iter() {
+            let path = dst.path_relative_from(&repo_path).unwrap();
+            if dst.exists() {
+                try!(index.add_path(&path));
+            } else {
+                try!(index.remove_path(&path));
+            }
+        }
         try!(index.write());
         let tree_id = try!(index.write_tree());
         let tree = try!(repo.find_tree(tree_id));
@@ -202,9 +457,9 @@ fn commit_and_push<F>(repo: &git2::Repository, mut f: F) -> CargoResult<()>
         // git commit -m "..."
         let head = try!(repo.head());
         let parent = try!(repo.find_commit(head.target().unwrap()));
-        let sig = try!(repo.signature());
-        try!(repo.commit(Some("HEAD"), &sig, &sig, msg.as_slice(),
-                         &tree, &[&parent]));
+        let id = try!(commit(repo, msg.as_slice(), &tree, &[&parent]));
+        let obj = try!(repo.find_object(id, None));
+        try!(repo.reset(&obj, git2::ResetType::Soft, None, None, None));
 
         // git push
         let mut callbacks = git2::RemoteCallbacks::new();
@@ -228,16 +483,147 @@ fn commit_and_push<F>(repo: &git2::Repository, mut f: F) -> CargoResult<()>
         }
 
         // Ok, we need to update, so fetch and reset --hard
-        try!(origin.add_fetch("refs/heads/*:refs/heads/*"));
-        try!(origin.fetch(&[], None, None));
-        let head = try!(repo.head()).target().unwrap();
-        let obj = try!(repo.find_object(head, None));
-        try!(repo.reset(&obj, git2::ResetType::Hard, None, None, None));
+        try!(fetch(repo));
     }
 
     Err(internal("Too many rebase failures"))
 }
 
+// Creates a commit of `tree` on top of `parents`, without moving any refs to
+// it. Like `git commit`, it's signed with `user.signingkey` if the checkout has
+// `commit.gpgsign` set.
+fn commit(repo: &git2::Repository, msg: &str, tree: &git2::Tree,
+          parents: &[&git2::Commit]) -> CargoResult<git2::Oid> {
+    let sig = try!(repo.signature());
+    let cfg = try!(repo.config());
+    if !cfg.get_bool("commit.gpgsign").unwrap_or(false) {
+        return Ok(try!(repo.commit(None, &sig, &sig, msg, tree, parents)))
+    }
+
+    // libgit2 can't sign commits itself, so the commit object is put together
+    // here, and the signature of everything but its `gpgsig` header is added
+    // just before the message.
+    let who = format!("{} <{}> {}", sig.name().unwrap_or(""),
+                      sig.email().unwrap_or(""), time(sig.when()));
+    let mut headers = format!("tree {}\n", tree.id());
+    for parent in parents.iter() {
+        headers.push_str(format!("parent {}\n", parent.id()).as_slice());
+    }
+    headers.push_str(format!("author {}\ncommitter {}\n", who, who).as_slice());
+    let signature = try!(sign(repo, &cfg,
+                              format!("{}\n{}", headers, msg).as_slice()));
+
+    let mut raw = headers;
+    raw.push_str("gpgsig");
+    for line in signature.as_slice().trim_right().lines() {
+        raw.push_str(format!(" {}\n", line).as_slice());
+    }
+    raw.push_str("\n");
+    raw.push_str(msg);
+    let odb = try!(repo.odb());
+    Ok(try!(odb.write(git2::ObjectType::Commit, raw.as_bytes())))
+}
+
+// Formats a time the way it's written in a commit, e.g. `1425600000 +0100`.
+fn time(time: git2::Time) -> String {
+    let offset = time.offset_minutes();
+    let sign = if offset < 0 {'-'} else {'+'};
+    let offset = offset.abs();
+    format!("{} {}{:02}{:02}", time.seconds(), sign, offset / 60, offset % 60)
+}
+
+// Signs `payload` with the key in `user.signingkey`, using `gpg` or
+// `ssh-keygen` depending on `gpg.format`, and returns the armored signature.
+// The signature is checked to be a good one made with that key before it's
+// returned, so nothing else is ever pushed.
+fn sign(repo: &git2::Repository, cfg: &git2::Config,
+        payload: &str) -> CargoResult<String> {
+    let key = try!(cfg.get_str("user.signingkey").chain_error(|| {
+        internal("commit.gpgsign is set without a user.signingkey")
+    })).to_string();
+    let format = cfg.get_str("gpg.format").unwrap_or("openpgp").to_string();
+
+    // Both tools are given the payload and write the signature out as files
+    // next to each other, in a directory of their own which is removed again
+    // however this turns out.
+    let dir = try!(TempDir::new_in(repo.path(), "signing"));
+    let src = dir.path().join("payload");
+    let dst = dir.path().join("payload.sig");
+    try!(File::create(&src).write_str(payload));
+    match format.as_slice() {
+        "openpgp" => {
+            let home = match cfg.get_str("registry.gnupghome") {
+                Ok(home) if home.len() > 0 => Some(Path::new(home)),
+                _ => None,
+            };
+            let mut sign = gpg(home.as_ref());
+            sign.arg("--batch").arg("--armor").arg("--detach-sign")
+                .arg("--local-user").arg(key.as_slice())
+                .arg("--output").arg(&dst).arg(&src);
+            try!(exec(&sign));
+
+            // `gpg --verify` accepts a signature by any key in the keyring,
+            // so the fingerprint of the key which made it is checked too.
+            let mut verify = gpg(home.as_ref());
+            verify.arg("--batch").arg("--status-fd").arg("1")
+                  .arg("--verify").arg(&dst).arg("-");
+            let status = try!(exec_with_input(&verify, payload).chain_error(|| {
+                internal("the signature of a commit to the index didn't verify")
+            }));
+            let signer = status.as_slice().lines().filter_map(|line| {
+                let mut fields = line.split(' ');
+                match (fields.next(), fields.next()) {
+                    (Some("[GNUPG:]"), Some("VALIDSIG")) => fields.nth(9),
+                    _ => None,
+                }
+            }).next();
+            if signer != Some(key.as_slice()) {
+                return Err(internal(format!("the signature of a commit to the \
+                                             index was made by {:?}, not `{}`",
+                                            signer, key)))
+            }
+        }
+        "ssh" => {
+            let mut sign = Command::new("ssh-keygen");
+            sign.arg("-q").arg("-Y").arg("sign").arg("-n").arg("git")
+                .arg("-f").arg(key.as_slice()).arg(&src);
+            try!(exec(&sign));
+
+            // Only the key which was signed with is allowed to have made the
+            // signature.
+            let mut public = Command::new("ssh-keygen");
+            public.arg("-y").arg("-f").arg(key.as_slice());
+            let public = try!(exec(&public)).output;
+            let public = String::from_utf8_lossy(public.as_slice());
+            let allowed = dir.path().join("allowed_signers");
+            let line = format!("registry namespaces=\"git\" {}", public);
+            try!(File::create(&allowed).write_str(line.as_slice()));
+            let mut verify = Command::new("ssh-keygen");
+            verify.arg("-Y").arg("verify").arg("-f").arg(&allowed)
+                  .arg("-I").arg("registry").arg("-n").arg("git")
+                  .arg("-s").arg(&dst);
+            try!(exec_with_input(&verify, payload).chain_error(|| {
+                internal("the signature of a commit to the index didn't verify")
+            }));
+        }
+        _ => return Err(internal(format!("unknown signature format `{}`",
+                                         format))),
+    }
+    Ok(try!(File::open(&dst).read_to_string()))
+}
+
+// Runs `cmd` with `input` as its standard input, returning what it printed.
+fn exec_with_input(cmd: &Command, input: &str) -> CargoResult<String> {
+    let mut process = try!(cmd.spawn());
+    try!(process.stdin.take().unwrap().write_str(input));
+    let output = try!(process.wait_with_output());
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(output.error.as_slice());
+        return Err(internal(format!("`{:?}` failed: {}", cmd, error)))
+    }
+    Ok(String::from_utf8_lossy(output.output.as_slice()).into_owned())
+}
+
 pub fn credentials(_user: &str, _user_from_url: Option<&str>,
                    _cred: git2::CredentialType)
                    -> Result<git2::Cred, git2::Error> {
@@ -0,0 +1,115 @@
+//! The registry index, served over plain HTTP straight from the database.
+//!
+//! Every crate's file lives at the same path as it does in the git index (see
+//! `git::index_path`) and holds the same lines, so clients can fetch just the
+//! crates they need rather than cloning the whole repository. Responses carry
+//! `ETag` and `Last-Modified` headers, which lets the conditional GET
+//! middleware answer a client that already has the latest copy with a 304.
+
+use std::collections::HashMap;
+use std::old_io::MemReader;
+use openssl::crypto::hash::{self, Type};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+use time::{self, Timespec};
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
+
+use {Model, Crate, Version};
+use app::RequestApp;
+use db::{Connection, RequestTransaction};
+use git;
+use user::RequestUser;
+use util::{CargoError, CargoResult, ChainError, internal};
+use util::errors::NotFound;
+
+/// Returns the entries of the index file for `krate`, one for each of its
+/// versions in the order they were published, along with the last time any
+/// of them changed. Versions which are still pending aren't included, as
+/// their tarballs may not be in storage yet.
+pub fn entries(conn: &Connection, krate: &Crate)
+               -> CargoResult<(Vec<git::Crate>, Timespec)> {
+    let stmt = try!(conn.prepare("SELECT * FROM versions \
+                                  WHERE crate_id = $1 AND NOT pending \
+                                  ORDER BY id ASC"));
+    let mut entries = Vec::new();
+    let mut modified = krate.created_at;
+    for row in try!(stmt.query(&[&krate.id])) {
+        let version: Version = Model::from_row(&row);
+        let cksum: Option<String> = row.get("checksum");
+        let cksum = try!(cksum.chain_error(|| {
+            internal(format!("no checksum recorded for `{}#{}`",
+                             krate.name, version.num))
+        }));
+        if version.updated_at > modified {
+            modified = version.updated_at;
+        }
+        let deps = try!(version.dependencies(conn)).into_iter()
+                                                   .map(|(dep, name)| {
+            dep.git_encode(name.as_slice())
+        }).collect();
+        entries.push(git::Crate {
+            name: krate.name.clone(),
+            vers: version.num.to_string(),
+            deps: deps,
+            cksum: cksum,
+            features: version.features.into_iter().collect(),
+            yanked: Some(version.yanked),
+        });
+    }
+    Ok((entries, modified))
+}
+
+/// Renders `entries` the way they're laid out in an index file.
+pub fn render(entries: &[git::Crate]) -> String {
+    let mut ret = String::new();
+    for entry in entries.iter() {
+        ret.push_str(json::encode(entry).unwrap().as_slice());
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Fails unless the request is allowed to read the index. That's anyone,
+/// unless the index's `config.json` tells clients they need a token, in which
+/// case it's only logged in users.
+pub fn authorize(req: &Request) -> CargoResult<()> {
+    if req.app().config.index_auth_required {
+        try!(req.user());
+    }
+    Ok(())
+}
+
+pub fn serve(req: &mut Request) -> CargoResult<Response> {
+    try!(authorize(req));
+    let path = req.params()["path"].clone();
+
+    // Only the canonical path of a crate's file is served, and crates without
+    // any versions don't have one.
+    let name = path.as_slice().split('/').last().unwrap_or("");
+    if git::index_path(name) != path {
+        return Err(Box::new(NotFound) as Box<CargoError>)
+    }
+    let conn = try!(req.tx());
+    let krate = try!(Crate::find_by_name(conn, name));
+    let (entries, modified) = try!(entries(conn, &krate));
+    if entries.len() == 0 {
+        return Err(Box::new(NotFound) as Box<CargoError>)
+    }
+
+    let body = render(entries.as_slice());
+    let etag = hash::hash(Type::SHA256, body.as_bytes()).to_hex();
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(),
+                   vec!["text/plain; charset=utf-8".to_string()]);
+    headers.insert("Content-Length".to_string(), vec![body.len().to_string()]);
+    headers.insert("ETag".to_string(), vec![format!("\"{}\"", etag)]);
+    headers.insert("Last-Modified".to_string(),
+                   vec![time::at_utc(modified).rfc822().to_string()]);
+    Ok(Response {
+        status: (200, "OK"),
+        headers: headers,
+        body: Box::new(MemReader::new(body.into_bytes())),
+    })
+}
@@ -0,0 +1,294 @@
+//! A queue of background jobs, stored in the `jobs` table.
+//!
+//! Publishing and yanking need to update the git index and storage, which is
+//! slow and has to be serialized, so requests only queue a job in the same
+//! transaction as their other changes. The `worker` binary then runs the jobs,
+//! pushing changes to the index in batches. A job which fails is retried later
+//! with an exponential backoff, and after `MAX_ATTEMPTS` failures it's marked
+//! as dead and left in the table for someone to look at.
+//!
+//! A new version stays pending until its `AddCrate` job has worked, and if
+//! either that or the upload of its tarball dies the version is deleted
+//! again.
+
+use std::old_io::BufReader;
+use std::time::Duration;
+use git2;
+use pg::types::ToSql;
+use rustc_serialize::json;
+use semver;
+use time::Timespec;
+
+use {Crate, Version};
+use db::Connection;
+use git;
+use storage::Storage;
+use util::{CargoResult, ChainError, internal};
+
+/// How many times a job is attempted before it's marked as dead.
+pub const MAX_ATTEMPTS: i32 = 10;
+
+/// The most changes to the index which are pushed in a single commit.
+pub const BATCH_SIZE: i64 = 50;
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum Job {
+    /// Uploads the job's payload to storage at the given path, and then
+    /// queues an `AddCrate` job for the index entry.
+    UploadTarball(String, git::Crate),
+    /// Adds an entry to the index. The path of its tarball is deleted from
+    /// storage if the job dies.
+    AddCrate(String, git::Crate),
+    /// Copies the `yanked` flag of a crate's version from the database to
+    /// the index. It's read when the job is run, so that yanking and then
+    /// unyanking leaves the index agreeing with the database whichever of
+    /// the two jobs ends up running last.
+    Yank(String, String),
+    /// Deletes a path from storage.
+    DeleteTarball(String),
+    /// Squashes the history of the index, archiving it on the given branch.
+    SquashIndex(String),
+}
+
+impl Job {
+    // The change this job makes to the index, if it's one of the jobs which
+    // are run in batches.
+    fn index_change(&self,
+                    conn: &Connection) -> CargoResult<Option<git::Change>> {
+        match *self {
+            Job::AddCrate(_, ref krate) => {
+                Ok(Some(git::Change::Add(krate.clone())))
+            }
+            Job::Yank(ref krate, ref num) => {
+                let (_, version) = try!(find_version(conn, krate.as_slice(),
+                                                     num.as_slice()));
+                Ok(Some(git::Change::Yank(krate.clone(), num.clone(),
+                                          version.yanked)))
+            }
+            Job::UploadTarball(..) |
+            Job::DeleteTarball(..) |
+            Job::SquashIndex(..) => Ok(None),
+        }
+    }
+}
+
+/// Queues `job`, with an optional blob of data for it to work on. Nothing is
+/// run until the worker gets to it.
+pub fn enqueue(conn: &Connection, job: &Job,
+               payload: Option<&[u8]>) -> CargoResult<()> {
+    let now = ::now();
+    try!(conn.execute("INSERT INTO jobs (job, payload, run_at, created_at)
+                       VALUES ($1, $2, $3, $3)",
+                      &[&json::encode(job).unwrap() as &ToSql,
+                        &payload, &now]));
+    Ok(())
+}
+
+/// Runs jobs until none are left which are due, without waiting for batches
+/// of index changes to fill up.
+pub fn run_pending(conn: &Connection, repo: &git2::Repository,
+                   storage: &Storage) -> CargoResult<()> {
+    while try!(run_next(conn, repo, storage, Duration::zero())) {}
+    Ok(())
+}
+
+// A row of the `jobs` table.
+struct Queued {
+    id: i32,
+    data: String,
+    attempts: i32,
+    run_at: Timespec,
+}
+
+/// Runs the next job, or batch of jobs, which is due, returning whether there
+/// was one.
+///
+/// Changes to the index are run in batches: all of those among the next
+/// `BATCH_SIZE` jobs are made together and pushed in a single commit. To give
+/// a batch the chance to fill up it isn't run until its oldest job has been
+/// due for `window`, and in the meantime the oldest job of some other kind is
+/// run instead.
+///
+/// The rows of the jobs which are run stay locked until `conn`'s transaction
+/// finishes, so this should be called in a transaction of its own.
+pub fn run_next(conn: &Connection, repo: &git2::Repository,
+                storage: &Storage, window: Duration) -> CargoResult<bool> {
+    let now = ::now();
+    let stmt = try!(conn.prepare("SELECT id, job, attempts, run_at FROM jobs
+                                  WHERE NOT dead AND run_at <= $1
+                                  ORDER BY id ASC LIMIT $2
+                                  FOR UPDATE"));
+    let queued = try!(stmt.query(&[&now as &ToSql, &BATCH_SIZE])).map(|row| {
+        Queued {
+            id: row.get("id"),
+            data: row.get("job"),
+            attempts: row.get("attempts"),
+            run_at: row.get("run_at"),
+        }
+    }).collect::<Vec<_>>();
+
+    let mut batch = Vec::new();
+    let mut changes = Vec::new();
+    let mut other = None;
+    for queued in queued.iter() {
+        match decode(queued).and_then(|job| job.index_change(conn)) {
+            Ok(Some(change)) => {
+                batch.push(queued);
+                changes.push(change);
+            }
+            _ if other.is_none() => other = Some(queued),
+            _ => {}
+        }
+    }
+    let ready = batch.len() == BATCH_SIZE as usize ||
+                batch.first().map_or(false, |q| q.run_at + window <= now);
+    if ready {
+        match git::apply(repo, changes.as_slice()) {
+            Ok(results) => {
+                let results = batch.into_iter().zip(results.into_iter());
+                for (queued, res) in results {
+                    try!(finish(conn, queued, res));
+                }
+            }
+            // None of the jobs are to blame for the push failing, so this
+            // doesn't count towards them dying. Otherwise a long enough outage
+            // would end up deleting everything which was published during it.
+            Err(e) => {
+                let msg = format!("couldn't push the index: {}", e);
+                warn!("{}", msg);
+                let run_at = ::now() + backoff(1);
+                for queued in batch.into_iter() {
+                    try!(conn.execute("UPDATE jobs SET last_error = $1,
+                                                       run_at = $2
+                                       WHERE id = $3",
+                                      &[&msg as &ToSql, &run_at,
+                                        &queued.id]));
+                }
+            }
+        }
+        return Ok(true)
+    }
+
+    match other {
+        Some(queued) => {
+            // Tarballs are only loaded for the job which is being run.
+            let stmt = try!(conn.prepare("SELECT payload FROM jobs
+                                          WHERE id = $1"));
+            let mut rows = try!(stmt.query(&[&queued.id]));
+            let payload: Option<Vec<u8>> = rows.next().unwrap().get("payload");
+            let res = decode(queued).and_then(|job| {
+                run(conn, repo, storage, &job, payload.as_ref())
+            });
+            try!(finish(conn, queued, res));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn decode(queued: &Queued) -> CargoResult<Job> {
+    json::decode(queued.data.as_slice()).map_err(|e| {
+        internal(format!("couldn't decode job {}: {:?}", queued.id, e))
+    })
+}
+
+// Records the outcome of running a job. It's deleted if it worked, and
+// otherwise it's retried later, or marked as dead if it's failed too many
+// times.
+fn finish(conn: &Connection, queued: &Queued,
+          res: CargoResult<()>) -> CargoResult<()> {
+    let e = match res {
+        Ok(()) => {
+            try!(succeeded(conn, queued));
+            try!(conn.execute("DELETE FROM jobs WHERE id = $1", &[&queued.id]));
+            return Ok(())
+        }
+        Err(e) => e,
+    };
+    warn!("job {} failed: {}", queued.id, e);
+    let attempts = queued.attempts + 1;
+    let dead = attempts >= MAX_ATTEMPTS;
+    let run_at = ::now() + backoff(attempts);
+    try!(conn.execute("UPDATE jobs SET attempts = $1, last_error = $2,
+                                       run_at = $3, dead = $4
+                       WHERE id = $5",
+                      &[&attempts as &ToSql, &e.to_string(), &run_at, &dead,
+                        &queued.id]));
+    if dead {
+        try!(died(conn, queued));
+    }
+    Ok(())
+}
+
+/// How long to wait before retrying a job which has failed `attempts` times:
+/// a minute at first, doubling after every failure.
+fn backoff(attempts: i32) -> Duration {
+    Duration::seconds(30 << attempts as usize)
+}
+
+fn run(conn: &Connection, repo: &git2::Repository, storage: &Storage,
+       job: &Job, payload: Option<&Vec<u8>>) -> CargoResult<()> {
+    match *job {
+        Job::UploadTarball(ref path, ref krate) => {
+            let payload = try!(payload.chain_error(|| {
+                internal(format!("no tarball to upload to `{}`", path))
+            }));
+            let mut body = BufReader::new(payload.as_slice());
+            try!(storage.put(path.as_slice(), &mut body, payload.len() as u64,
+                             "application/x-tar"));
+            // The job for the index entry is only queued now so there's never
+            // a version in the git index whose tarball can't be downloaded.
+            insert(conn, &Job::AddCrate(path.clone(), krate.clone()), None)
+        }
+        Job::AddCrate(..) | Job::Yank(..) => {
+            let change = try!(job.index_change(conn)).unwrap();
+            try!(git::apply(repo, &[change])).pop().unwrap()
+        }
+        Job::DeleteTarball(ref path) => storage.delete(path.as_slice()),
+        Job::SquashIndex(ref archive) => git::squash(repo, archive.as_slice()),
+    }
+}
+
+fn find_version(conn: &Connection, name: &str,
+                num: &str) -> CargoResult<(Crate, Version)> {
+    let krate = try!(Crate::find_by_name(conn, name));
+    let num = try!(semver::Version::parse(num).map_err(|_| {
+        internal(format!("invalid version `{}` of `{}`", num, name))
+    }));
+    let version = try!(try!(Version::find_by_num(conn, krate.id, &num))
+                           .chain_error(|| {
+        internal(format!("`{}#{}` isn't in the database", name, num))
+    }));
+    Ok((krate, version))
+}
+
+// Follows up on a job which has worked.
+fn succeeded(conn: &Connection, queued: &Queued) -> CargoResult<()> {
+    match decode(queued) {
+        // The version is in the index now, so it's ready to be used.
+        Ok(Job::AddCrate(_, ref krate)) => {
+            let (_, version) = try!(find_version(conn, krate.name.as_slice(),
+                                                 krate.vers.as_slice()));
+            version.set_pending(conn, false)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Cleans up after a job which won't be retried any more.
+fn died(conn: &Connection, queued: &Queued) -> CargoResult<()> {
+    match decode(queued) {
+        // If the crate never made it into the index, we shouldn't keep it on
+        // the server, or in the database.
+        Ok(Job::UploadTarball(path, krate)) |
+        Ok(Job::AddCrate(path, krate)) => {
+            if let Ok((mut c, version)) = find_version(conn,
+                                                       krate.name.as_slice(),
+                                                       krate.vers.as_slice()) {
+                try!(c.remove_version(conn, &version));
+            }
+            insert(conn, &Job::DeleteTarball(path), None)
+        }
+        _ => Ok(()),
+    }
+}
@@ -1,31 +1,34 @@
 use std::ascii::AsciiExt;
 use std::cmp;
 use std::collections::HashMap;
-use std::sync::Arc;
 use std::time::Duration;
+use openssl::crypto::hash::{self, Type};
 use rustc_serialize::hex::ToHex;
 use rustc_serialize::json;
 use time::Timespec;
 
 use conduit::{Request, Response};
 use conduit_router::RequestParams;
-use curl::http;
 use pg;
 use pg::types::ToSql;
 use semver;
 use url::{self, Url};
 
 use {Model, User, Keyword, Version};
-use app::{App, RequestApp};
+use app::RequestApp;
 use db::{Connection, RequestTransaction};
 use dependency::{Dependency, EncodableDependency};
 use download::{VersionDownload, EncodableVersionDownload};
 use git;
+use jobs::{self, Job};
 use keyword::EncodableKeyword;
+use render;
+use spdx;
+use tarball;
 use upload;
-use user::{RequestUser, EncodableUser};
+use user::{RequestUser, EncodableUser, Scope};
 use util::errors::{NotFound, CargoError};
-use util::{LimitErrorReader, HashingReader, CommaSep};
+use util::{LimitErrorReader, CommaSep};
 use util::{RequestUtils, CargoResult, internal, ChainError, human};
 use version::EncodableVersion;
 
@@ -47,6 +50,12 @@ pub struct Crate {
     pub repository: Option<String>,
 }
 
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct EncodableLicense {
+    pub id: String,
+    pub crates_cnt: i64,
+}
+
 #[derive(RustcEncodable, RustcDecodable)]
 pub struct EncodableCrate {
     pub id: String,
@@ -57,6 +66,10 @@ pub struct EncodableCrate {
     pub downloads: i32,
     pub max_version: String,
     pub description: Option<String>,
+    /// The rendered description and readme, which are only filled in when a
+    /// single crate is shown.
+    pub description_html: Option<String>,
+    pub readme_html: Option<String>,
     pub homepage: Option<String>,
     pub documentation: Option<String>,
     pub keywords: Vec<String>,
@@ -79,11 +92,16 @@ impl Crate {
     }
 
     pub fn find_by_name(conn: &Connection, name: &str) -> CargoResult<Crate> {
+        let krate = try!(Crate::lookup(conn, name));
+        krate.chain_error(|| NotFound)
+    }
+
+    /// Returns the crate named `name`, ignoring case, if there is one.
+    pub fn lookup(conn: &Connection, name: &str) -> CargoResult<Option<Crate>> {
         let stmt = try!(conn.prepare("SELECT * FROM crates \
                                       WHERE lower(name) = lower($1) LIMIT 1"));
-        let row = try!(stmt.query(&[&name as &ToSql])).next();
-        let row = try!(row.chain_error(|| NotFound));
-        Ok(Model::from_row(&row))
+        let mut rows = try!(stmt.query(&[&name as &ToSql]));
+        Ok(rows.next().map(|r| Model::from_row(&r)))
     }
 
     pub fn find_or_insert(conn: &Connection, name: &str,
@@ -101,27 +119,25 @@ impl Crate {
         let documentation = documentation.as_ref().map(|s| s.as_slice());
         let readme = readme.as_ref().map(|s| s.as_slice());
         let repository = repository.as_ref().map(|s| s.as_slice());
-        let mut license = license.as_ref().map(|s| s.as_slice());
         let license_file = license_file.as_ref().map(|s| s.as_slice());
         let keywords = keywords.connect(",");
         try!(validate_url(homepage));
         try!(validate_url(documentation));
         try!(validate_url(repository));
 
-        match license {
-            // If a license is given, validate it to make sure it's actually a
-            // valid license
-            Some(..) => try!(validate_license(license)),
+        let license = match *license {
+            // If a license is given, parse it to make sure it's actually a
+            // valid license expression, and store it in its canonical form.
+            Some(ref license) => {
+                Some(try!(spdx::parse(license.as_slice())).to_string())
+            }
 
             // If no license is given, but a license file is given, flag this
-            // crate as having a nonstandard license. Note that we don't
-            // actually do anything else with license_file currently.
-            None if license_file.is_some() => {
-                license = Some("non-standard");
-            }
+            // crate as having a nonstandard license.
+            None if license_file.is_some() => Some("non-standard".to_string()),
 
-            None => {}
-        }
+            None => None,
+        };
 
         // TODO: like with users, this is sadly racy
         let stmt = try!(conn.prepare("UPDATE crates
@@ -139,7 +155,11 @@ impl Crate {
                                          &license, &repository,
                                          &name as &ToSql]));
         match rows.next() {
-            Some(row) => return Ok(Model::from_row(&row)),
+            Some(row) => {
+                let krate: Crate = Model::from_row(&row);
+                try!(krate.update_licenses(conn));
+                return Ok(krate)
+            }
             None => {}
         }
 
@@ -171,6 +191,7 @@ impl Crate {
                            (crate_id, user_id, created_at, updated_at, deleted)
                            VALUES ($1, $2, $3, $3, FALSE)",
                           &[&ret.id, &user_id, &now]));
+        try!(ret.update_licenses(conn));
         return Ok(ret);
 
         fn validate_url(url: Option<&str>) -> CargoResult<()> {
@@ -194,26 +215,29 @@ impl Crate {
             }
             Ok(())
         }
+    }
 
-        fn validate_license(license: Option<&str>) -> CargoResult<()> {
-            use licenses::KNOWN_LICENSES;
-            match license {
-                Some(license) => {
-                    let ok = license.split('/').all(|l| {
-                        KNOWN_LICENSES.binary_search(&l.trim()).is_ok()
-                    });
-                    if ok {
-                        Ok(())
-                    } else {
-                        Err(human(format!("unknown license `{}`, \
-                                           see http://opensource.org/licenses \
-                                           for options, and http://spdx.org/licenses/ \
-                                           for their identifiers", license)))
-                    }
-                }
-                None => Ok(()),
-            }
+    /// Records each license mentioned in this crate's license expression, and
+    /// whether the crate may be used under that license alone, so crates can
+    /// be searched and counted by license.
+    pub fn update_licenses(&self, conn: &Connection) -> CargoResult<()> {
+        try!(conn.execute("DELETE FROM crate_licenses WHERE crate_id = $1",
+                          &[&self.id]));
+        // Crates with a nonstandard license have nothing to record.
+        let expr = self.license.as_ref().and_then(|l| {
+            spdx::parse(l.as_slice()).ok()
+        });
+        let expr = match expr { Some(expr) => expr, None => return Ok(()) };
+        let mut ids = expr.licenses();
+        ids.sort();
+        ids.dedup();
+        for id in ids.iter() {
+            try!(conn.execute("INSERT INTO crate_licenses \
+                               (crate_id, license, allowed) \
+                               VALUES ($1, $2, $3)",
+                              &[&self.id, id as &ToSql, &expr.allows(*id)]));
         }
+        Ok(())
     }
 
     pub fn valid_name(name: &str) -> bool {
@@ -223,6 +247,25 @@ impl Crate {
             name.chars().all(|c| c.is_ascii())
     }
 
+    /// Like `encodable`, but with the description and readme rendered as
+    /// HTML as well.
+    pub fn encodable_with_html(self,
+                               versions: Option<Vec<i32>>) -> EncodableCrate {
+        let (description_html, readme_html) = {
+            let repository = self.repository.as_ref().map(|s| s.as_slice());
+            let render = |s: &String| {
+                render::markdown_to_html(s.as_slice(), repository)
+            };
+            (self.description.as_ref().map(|s| render(s)),
+             self.readme.as_ref().map(|s| render(s)))
+        };
+        EncodableCrate {
+            description_html: description_html,
+            readme_html: readme_html,
+            .. self.encodable(versions)
+        }
+    }
+
     pub fn valid_feature_name(name: &str) -> bool {
         let mut parts = name.split('/');
         match parts.next() {
@@ -241,7 +284,7 @@ impl Crate {
         let Crate {
             name, created_at, updated_at, downloads, max_version, description,
             homepage, documentation, keywords, license, repository,
-            readme: _, id: _, user_id: _,
+            id: _, user_id: _, readme: _,
         } = self;
         let versions_link = match versions {
             Some(..) => None,
@@ -258,6 +301,8 @@ impl Crate {
             documentation: documentation,
             homepage: homepage,
             description: description,
+            description_html: None,
+            readme_html: None,
             keywords: keywords,
             license: license,
             repository: repository,
@@ -320,7 +365,7 @@ impl Crate {
 
     pub fn add_version(&mut self, conn: &Connection, ver: &semver::Version,
                        features: &HashMap<String, Vec<String>>,
-                       authors: &[String])
+                       authors: &[String], license: Option<&str>)
                        -> CargoResult<Version> {
         match try!(Version::find_by_num(conn, self.id, ver)) {
             Some(..) => {
@@ -338,7 +383,44 @@ impl Crate {
                            WHERE id = $3",
                           &[&self.updated_at, &self.max_version.to_string(),
                             &self.id]));
-        Version::insert(conn, self.id, ver, features, authors)
+        Version::insert(conn, self.id, ver, features, authors, license)
+    }
+
+    /// Deletes `version`, and then the crate itself if that was its only
+    /// version, which frees up its name again. A crate which other crates
+    /// depend on is kept, without any versions.
+    pub fn remove_version(&mut self, conn: &Connection,
+                          version: &Version) -> CargoResult<()> {
+        try!(version.delete(conn));
+        let versions = try!(self.versions(conn));
+        if versions.len() == 0 {
+            let stmt = try!(conn.prepare("SELECT 1 FROM dependencies
+                                          WHERE crate_id = $1 LIMIT 1"));
+            if try!(stmt.query(&[&self.id])).next().is_none() {
+                return self.delete(conn)
+            }
+        }
+        // `versions` is sorted with the highest first.
+        self.max_version = match versions.first() {
+            Some(v) => v.num.clone(),
+            None => semver::Version::parse("0.0.0").unwrap(),
+        };
+        try!(conn.execute("UPDATE crates SET max_version = $1 WHERE id = $2",
+                          &[&self.max_version.to_string(), &self.id]));
+        Ok(())
+    }
+
+    /// Deletes the crate along with everything which refers to it, other than
+    /// its versions, which have to be deleted first.
+    pub fn delete(&self, conn: &Connection) -> CargoResult<()> {
+        try!(Keyword::update_crate(conn, self, &[]));
+        for table in ["follows", "crate_downloads", "crate_owners",
+                      "crate_licenses"].iter() {
+            let sql = format!("DELETE FROM {} WHERE crate_id = $1", table);
+            try!(conn.execute(sql.as_slice(), &[&self.id]));
+        }
+        try!(conn.execute("DELETE FROM crates WHERE id = $1", &[&self.id]));
+        Ok(())
     }
 
     pub fn keywords(&self, conn: &Connection) -> CargoResult<Vec<Keyword>> {
@@ -418,6 +500,12 @@ pub fn index(req: &mut Request) -> CargoResult<Response> {
         _ => "ORDER BY crates.name ASC",
     };
 
+    // Licenses are filtered on their canonical identifier.
+    let license = match query.get("license") {
+        Some(license) => Some(try!(license_filter(license.as_slice()))),
+        None => None,
+    };
+
     // Different queries for different parameters.
     //
     // Sure wish we had an arel-like thing here...
@@ -456,6 +544,17 @@ pub fn index(req: &mut Request) -> CargoResult<Response> {
             (format!("SELECT crates.* {} {} LIMIT $2 OFFSET $3", base, sort_sql),
              format!("SELECT COUNT(crates.*) {}", base))
         })
+    }).or_else(|| {
+        license.as_ref().map(|license| {
+            args.insert(0, license as &ToSql);
+            let base = "FROM crates
+                        INNER JOIN crate_licenses
+                                ON crates.id = crate_licenses.crate_id
+                        WHERE crate_licenses.license = $1
+                          AND crate_licenses.allowed";
+            (format!("SELECT crates.* {} {} LIMIT $2 OFFSET $3", base, sort_sql),
+             format!("SELECT COUNT(crates.*) {}", base))
+        })
     }).or_else(|| {
         query.get("user_id").and_then(|s| s.parse::<i32>().ok()).map(|user_id| {
             id = user_id;
@@ -524,6 +623,16 @@ pub fn index(req: &mut Request) -> CargoResult<Response> {
     }))
 }
 
+fn license_filter(license: &str) -> CargoResult<&'static str> {
+    match try!(spdx::parse(license)) {
+        spdx::Expr::License(ref l) if !l.or_later && l.exception.is_none() => {
+            Ok(l.id)
+        }
+        _ => Err(human(format!("the license filter must be a single license \
+                                identifier, not `{}`", license))),
+    }
+}
+
 pub fn summary(req: &mut Request) -> CargoResult<Response> {
     let tx = try!(req.tx());
     let num_crates = {
@@ -585,7 +694,7 @@ pub fn show(req: &mut Request) -> CargoResult<Response> {
         keywords: Vec<EncodableKeyword>,
     }
     Ok(req.json(&R {
-        krate: krate.clone().encodable(Some(ids)),
+        krate: krate.clone().encodable_with_html(Some(ids)),
         versions: versions.into_iter().map(|v| {
             v.encodable(krate.name.as_slice())
         }).collect(),
@@ -627,9 +736,16 @@ pub fn new(req: &mut Request) -> CargoResult<Response> {
         return Err(human(format!("crate was previously named `{}`", krate.name)))
     }
 
-    // Persist the new version of this crate
+    // Persist the new version of this crate. `find_or_insert` has stored the
+    // canonical form of its license expression, if it was given one.
+    let license = match new_crate.license {
+        Some(..) => krate.license.clone(),
+        None => None,
+    };
     let mut version = try!(krate.add_version(try!(req.tx()), vers, &features,
-                                             new_crate.authors.as_slice()));
+                                             new_crate.authors.as_slice(),
+                                             license.as_ref()
+                                                    .map(|s| s.as_slice())));
 
     // Link this new version to all dependencies
     let mut deps = Vec::new();
@@ -641,65 +757,50 @@ pub fn new(req: &mut Request) -> CargoResult<Response> {
     // Update all keywords for this crate
     try!(Keyword::update_crate(try!(req.tx()), &krate, keywords.as_slice()));
 
-    // Upload the crate to S3
-    let handle = http::handle();
-    let mut handle = match req.app().s3_proxy {
-        Some(ref proxy) => handle.proxy(proxy.as_slice()),
-        None => handle,
-    };
-    let path = krate.s3_path(vers.to_string().as_slice());
-    let (resp, cksum) = {
-        let length = try!(req.body().read_le_u32());
-        let body = LimitErrorReader::new(req.body(), app.config.max_upload_size);
-        let mut body = HashingReader::new(body);
-        let resp = {
-            let s3req = app.bucket.put(&mut handle, path.as_slice(), &mut body,
-                                       "application/x-tar")
-                                  .content_length(length as usize)
-                                  .header("Content-Encoding", "gzip");
-            try!(s3req.exec().chain_error(|| {
-                internal(format!("failed to upload to S3: `{}`", path))
-            }))
-        };
-        (resp, body.finalize())
-    };
-    if resp.get_code() != 200 {
-        return Err(internal(format!("failed to get a 200 response from S3: {}",
-                                    resp)))
-    }
-
-    // If the git commands fail below, we shouldn't keep the crate on the
-    // server.
-    struct Bomb { app: Arc<App>, path: Option<String>, handle: http::Handle }
-    impl Drop for Bomb {
-        fn drop(&mut self) {
-            match self.path {
-                Some(ref path) => {
-                    let _ = self.app.bucket.delete(&mut self.handle,
-                                                   path.as_slice())
-                                .exec();
-                }
-                None => {}
-            }
+    // Read the tarball and make sure that it contains what the metadata above
+    // says it does before storing it anywhere.
+    let tarball = {
+        let max = app.config.max_upload_size;
+        let length = try!(req.body().read_le_u32()) as usize;
+        if length > max {
+            return Err(human(format!("max upload size is: {}", max)))
         }
+        let mut body = LimitErrorReader::new(req.body(), max);
+        try!(body.read_exact(length))
+    };
+    let package = try!(tarball::verify(&new_crate, tarball.as_slice(),
+                                       app.config.max_unpack_size));
+    if let Some((ref path, ref content)) = package.readme {
+        let repository = krate.repository.as_ref().map(|s| s.as_slice());
+        try!(version.add_readme(try!(req.tx()), path.as_slice(),
+                                content.as_slice(), repository));
+    }
+    if let Some((ref path, ref content)) = package.license_file {
+        try!(version.add_license_file(try!(req.tx()), path.as_slice(),
+                                      content.as_slice()));
     }
-    let mut bomb = Bomb { app: app.clone(), path: Some(path), handle: handle };
 
-    // Register this crate in our local git repo.
+    // The index served over HTTP is built from the database, so it needs the
+    // checksum too.
+    let cksum = hash::hash(Type::SHA256, tarball.as_slice()).to_hex();
+    try!(version.set_checksum(try!(req.tx()), cksum.as_slice()));
+
+    // Queue the upload of the crate to storage, which is followed by
+    // registering it in the git index. Both only happen if this transaction
+    // is committed, and until the latter has the version stays pending, so
+    // it's left out of the index and can't be downloaded.
+    try!(version.set_pending(try!(req.tx()), true));
+    let path = krate.s3_path(vers.to_string().as_slice());
     let git_crate = git::Crate {
         name: name.to_string(),
         vers: vers.to_string(),
-        cksum: cksum.as_slice().to_hex(),
-        features: features,
+        cksum: cksum,
+        features: features.into_iter().collect(),
         deps: deps,
         yanked: Some(false),
     };
-    try!(git::add_crate(&**req.app(), &git_crate).chain_error(|| {
-        internal(format!("could not add crate `{}` to the git repo", name))
-    }));
-
-    // Now that we've come this far, we're committed!
-    bomb.path = None;
+    try!(jobs::enqueue(try!(req.tx()), &Job::UploadTarball(path, git_crate),
+                       Some(tarball.as_slice())));
 
     #[derive(RustcEncodable)]
     struct R { krate: EncodableCrate }
@@ -746,7 +847,13 @@ fn parse_new_headers(req: &mut Request) -> CargoResult<(upload::NewCrate, User)>
             how to upload metadata", missing.connect(", "))));
     }
 
-    let user = try!(req.user());
+    // Publishing a crate's first version and any later ones are different
+    // scopes of API tokens.
+    let scope = match try!(Crate::lookup(try!(req.tx()), new.name.as_slice())) {
+        Some(..) => Scope::PublishUpdate,
+        None => Scope::PublishNew,
+    };
+    let user = try!(req.authorize(scope, new.name.as_slice()));
     Ok((new, user.clone()))
 }
 
@@ -760,6 +867,7 @@ pub fn download(req: &mut Request) -> CargoResult<Response> {
                                     crates.id = versions.crate_id
                                 WHERE lower(crates.name) = lower($1)
                                   AND versions.num = $2
+                                  AND NOT versions.pending
                                 LIMIT 1"));
     let mut rows = try!(stmt.query(&[&crate_name as &ToSql, &version as &ToSql]));
     let row = try!(rows.next().chain_error(|| human("crate or version not found")));
@@ -789,9 +897,8 @@ pub fn download(req: &mut Request) -> CargoResult<Response> {
     }
 
     // Now that we've done our business, redirect to the actual data.
-    let redirect_url = format!("https://{}/crates/{}/{}-{}.crate",
-                               req.app().bucket.host(),
-                               crate_name, crate_name, version);
+    let path = format!("/crates/{}/{}-{}.crate", crate_name, crate_name, version);
+    let redirect_url = req.app().storage.url(path.as_slice());
 
     if req.wants_json() {
         #[derive(RustcEncodable)]
@@ -935,7 +1042,8 @@ pub fn remove_owners(req: &mut Request) -> CargoResult<Response> {
 
 fn modify_owners(req: &mut Request, add: bool) -> CargoResult<Response> {
     let body = try!(req.body().read_to_string());
-    let (user, krate) = try!(user_and_crate(req));
+    let (_, krate) = try!(user_and_crate(req));
+    let user = try!(req.authorize(Scope::ChangeOwners, krate.name.as_slice()));
     let tx = try!(req.tx());
     let owners = try!(krate.owners(tx));
     if !owners.iter().any(|u| u.id == user.id) {
@@ -949,12 +1057,12 @@ fn modify_owners(req: &mut Request, add: bool) -> CargoResult<Response> {
 
     for login in request.users.iter() {
         if add {
-            if owners.iter().any(|u| u.gh_login == *login) {
+            if owners.iter().any(|u| u.login == *login) {
                 return Err(human(format!("user `{}` is already an owner", login)))
             }
             try!(krate.owner_add(tx, user.id, login.as_slice()));
         } else {
-            if login.as_slice() == user.gh_login.as_slice() {
+            if login.as_slice() == user.login.as_slice() {
                 return Err(human("cannot remove yourself as an owner"))
             }
             try!(krate.owner_remove(tx, user.id, login.as_slice()));
@@ -983,3 +1091,25 @@ pub fn reverse_dependencies(req: &mut Request) -> CargoResult<Response> {
     struct Meta { total: i64 }
     Ok(req.json(&R{ dependencies: rev_deps, meta: Meta { total: total } }))
 }
+
+/// Lists every license which some crate may be used under, along with the
+/// number of such crates, the same ones the `license` filter of the crates
+/// index would return.
+pub fn licenses(req: &mut Request) -> CargoResult<Response> {
+    let conn = try!(req.tx());
+    let stmt = try!(conn.prepare("SELECT license, COUNT(*) AS crates_cnt
+                                    FROM crate_licenses
+                                   WHERE allowed
+                                   GROUP BY license
+                                   ORDER BY crates_cnt DESC, license ASC"));
+    let licenses = try!(stmt.query(&[])).map(|row| {
+        EncodableLicense {
+            id: row.get("license"),
+            crates_cnt: row.get("crates_cnt"),
+        }
+    }).collect();
+
+    #[derive(RustcEncodable)]
+    struct R { licenses: Vec<EncodableLicense> }
+    Ok(req.json(&R { licenses: licenses }))
+}
@@ -16,6 +16,7 @@ extern crate r2d2_postgres;
 extern crate rand;
 extern crate s3;
 extern crate semver;
+extern crate toml;
 extern crate url;
 
 extern crate "conduit-router" as conduit_router;
@@ -52,10 +53,17 @@ pub mod dependency;
 pub mod dist;
 pub mod download;
 pub mod git;
+pub mod index;
+pub mod jobs;
 pub mod keyword;
 pub mod krate;
 pub mod model;
+pub mod render;
+pub mod spdx;
+pub mod storage;
+pub mod tarball;
 pub mod upload;
+pub mod upload_pack;
 pub mod user;
 pub mod util;
 pub mod version;
@@ -79,6 +87,9 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     api_router.get("/crates/:crate_id/:version/dependencies", C(version::dependencies));
     api_router.get("/crates/:crate_id/:version/downloads", C(version::downloads));
     api_router.get("/crates/:crate_id/:version/authors", C(version::authors));
+    api_router.get("/crates/:crate_id/:version/readme", C(version::readme));
+    api_router.get("/crates/:crate_id/:version/license", C(version::license));
+    api_router.get("/crates/:crate_id/:version/cksum", C(version::cksum));
     api_router.get("/crates/:crate_id/downloads", C(krate::downloads));
     api_router.get("/crates/:crate_id/versions", C(krate::versions));
     api_router.put("/crates/:crate_id/follow", C(krate::follow));
@@ -90,6 +101,7 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     api_router.delete("/crates/:crate_id/:version/yank", C(version::yank));
     api_router.put("/crates/:crate_id/:version/unyank", C(version::unyank));
     api_router.get("/crates/:crate_id/reverse_dependencies", C(krate::reverse_dependencies));
+    api_router.get("/licenses", C(krate::licenses));
     api_router.get("/versions", C(version::index));
     api_router.get("/versions/:version_id", C(version::show));
     api_router.get("/keywords", C(keyword::index));
@@ -106,20 +118,34 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     router.head("/api/v1/*path", R(api_router.clone()));
     router.delete("/api/v1/*path", R(api_router));
 
-    router.get("/authorize_url", C(user::github_authorize));
-    router.get("/authorize", C(user::github_access_token));
+    router.get("/authorize_url", C(user::identity::authorize_url));
+    router.get("/authorize_url/:provider", C(user::identity::authorize_url));
+    router.get("/authorize", C(user::identity::authorize));
+    router.get("/authorize/:provider", C(user::identity::authorize));
+    router.put("/authorize/:provider", C(user::identity::authorize));
+    router.put("/register", C(user::identity::register));
+    router.put("/signup", C(user::identity::signup));
     router.get("/logout", C(user::logout));
     router.get("/me", C(user::me));
     router.put("/me/reset_token", C(user::reset_token));
+    router.get("/me/tokens", C(user::token::list));
+    router.put("/me/tokens", C(user::token::new));
+    router.delete("/me/tokens/:token_id", C(user::token::revoke));
+    router.get("/me/identities", C(user::identity::list));
+    router.delete("/me/identities/:identity_id", C(user::identity::unlink));
     router.get("/me/updates", C(user::updates));
     router.get("/summary", C(krate::summary));
+    router.get("/index/*path", C(index::serve));
 
-    let env = app.config.env;
-    if env == Env::Development {
-        router.get("/git/index/*path", C(git::serve_index));
-        router.post("/git/index/*path", C(git::serve_index));
+    if let storage::Backend::Local(..) = app.config.storage {
+        router.get("/storage/*path", C(storage::serve));
     }
 
+    router.get("/git/index/*path", C(upload_pack::serve));
+    router.post("/git/index/*path", C(upload_pack::serve));
+
+    let env = app.config.env;
+
     let mut m = MiddlewareBuilder::new(R404(router));
     if env == Env::Development {
         m.add(DebugMiddleware);
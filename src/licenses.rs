@@ -310,3 +310,25 @@ pub const KNOWN_LICENSES: &'static [&'static str] = &[
     "xpp",
     "zlib-acknowledgement",
 ];
+
+// Exceptions which may follow a license with `WITH`, also kept sorted.
+pub const KNOWN_EXCEPTIONS: &'static [&'static str] = &[
+    "Autoconf-exception-2.0",
+    "Autoconf-exception-3.0",
+    "Bison-exception-2.2",
+    "CLISP-exception-2.0",
+    "Classpath-exception-2.0",
+    "FLTK-exception",
+    "Font-exception-2.0",
+    "GCC-exception-2.0",
+    "GCC-exception-3.1",
+    "LZMA-exception",
+    "Libtool-exception",
+    "Nokia-Qt-exception-1.1",
+    "Qwt-exception-1.0",
+    "WxWindows-exception-3.1",
+    "eCos-exception-2.0",
+    "freertos-exception-2.0",
+    "gnu-javamail-exception",
+    "u-boot-exception-2.0",
+];
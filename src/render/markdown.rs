@@ -0,0 +1,758 @@
+//! A renderer for the common subset of Markdown: headings, paragraphs, block
+//! quotes, lists, code blocks, rules, emphasis, code spans, links, images and
+//! raw HTML.
+//!
+//! Nothing here tries to make the output safe, that's up to the sanitizer.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+
+use super::{escape, entity, sanitize};
+
+/// How deeply quotes and lists can be nested in each other. Anything deeper
+/// is left as the text of a paragraph.
+const MAX_NESTING: usize = 32;
+
+/// Link reference definitions, keyed by their normalized label.
+type Refs = HashMap<String, (String, Option<String>)>;
+
+enum Block<'a> {
+    Heading(usize, Vec<&'a str>),
+    Paragraph(Vec<&'a str>),
+    Code(Option<&'a str>, Vec<&'a str>),
+    Quote(Vec<Block<'a>>),
+    List(Option<u32>, bool, Vec<Vec<Block<'a>>>),
+    Rule,
+    Html(Vec<&'a str>),
+}
+
+struct Marker {
+    /// The bullet character, or the delimiter after the number of an
+    /// ordered list.
+    kind: char,
+    /// The number of an ordered list item.
+    start: Option<u32>,
+    /// The length of the marker, including the space after it.
+    width: usize,
+}
+
+/// Renders the Markdown `text` as HTML.
+pub fn to_html(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace("\t", "    ");
+    let lines = text.as_slice().split('\n').collect::<Vec<_>>();
+    let mut refs = HashMap::new();
+    let blocks = blocks(lines.as_slice(), &mut refs, 0);
+    let mut out = String::new();
+    render_blocks(blocks.as_slice(), &refs, false, &mut out);
+    out
+}
+
+fn blocks<'a>(lines: &[&'a str], refs: &mut Refs,
+              depth: usize) -> Vec<Block<'a>> {
+    let mut ret = Vec::new();
+    if depth > MAX_NESTING {
+        let text = lines.iter().map(|l| l.trim()).filter(|l| l.len() > 0)
+                        .collect::<Vec<_>>();
+        if text.len() > 0 { ret.push(Block::Paragraph(text)); }
+        return ret
+    }
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if is_blank(line) { i += 1; continue }
+        let (indent, rest) = indentation(line);
+
+        if indent >= 4 {
+            let mut code = Vec::new();
+            while i < lines.len() &&
+                  (is_blank(lines[i]) || indentation(lines[i]).0 >= 4) {
+                code.push(if is_blank(lines[i]) {""} else {&lines[i][4..]});
+                i += 1;
+            }
+            while code.last() == Some(&"") { code.pop(); }
+            ret.push(Block::Code(None, code));
+        } else if let Some((c, n, info)) = fence(rest) {
+            let mut code = Vec::new();
+            i += 1;
+            while i < lines.len() {
+                let line = lines[i];
+                i += 1;
+                let (close_indent, close) = indentation(line);
+                let close = close.trim_right();
+                if close_indent < 4 && close.len() >= n &&
+                   close.chars().all(|ch| ch == c) {
+                    break
+                }
+                // Content is unindented by as much as the opening fence was.
+                let strip = ::std::cmp::min(close_indent, indent);
+                code.push(&line[strip..]);
+            }
+            let lang = info.split(' ').next().unwrap();
+            ret.push(Block::Code(if lang.len() == 0 {None} else {Some(lang)},
+                                 code));
+        } else if let Some(level) = atx_heading(rest) {
+            let content = rest[level..].trim();
+            let stripped = content.trim_right_matches('#');
+            let content = if stripped.len() == 0 || stripped.ends_with(" ") {
+                stripped.trim()
+            } else {
+                content
+            };
+            ret.push(Block::Heading(level, vec![content]));
+            i += 1;
+        } else if is_rule(rest) {
+            ret.push(Block::Rule);
+            i += 1;
+        } else if html_block(rest) {
+            let mut html = Vec::new();
+            while i < lines.len() && !is_blank(lines[i]) {
+                html.push(lines[i]);
+                i += 1;
+            }
+            ret.push(Block::Html(html));
+        } else if rest.starts_with(">") {
+            let mut quote = Vec::new();
+            while i < lines.len() && !is_blank(lines[i]) {
+                let (indent, rest) = indentation(lines[i]);
+                if indent < 4 && rest.starts_with(">") {
+                    let skip = if rest[1..].starts_with(" ") {2} else {1};
+                    quote.push(&rest[skip..]);
+                } else if !starts_block(lines[i]) {
+                    quote.push(lines[i]);
+                } else {
+                    break
+                }
+                i += 1;
+            }
+            ret.push(Block::Quote(blocks(quote.as_slice(), refs, depth + 1)));
+        } else if let Some(marker) = list_marker(rest) {
+            i = list(lines, i, marker, refs, depth, &mut ret);
+        } else {
+            i = paragraph(lines, i, refs, &mut ret);
+        }
+    }
+    ret
+}
+
+/// Parses the list starting at `lines[i]`, `depth` levels deep, returning the
+/// index of the first line after it.
+fn list<'a>(lines: &[&'a str], mut i: usize, first: Marker, refs: &mut Refs,
+            depth: usize, out: &mut Vec<Block<'a>>) -> usize {
+    let mut items = Vec::new();
+    let mut tight = true;
+    loop {
+        let (indent, rest) = indentation(lines[i]);
+        let marker = list_marker(rest).unwrap();
+        let content_indent = indent + marker.width;
+        let mut item = vec![if rest.len() > marker.width {
+            &rest[marker.width..]
+        } else {
+            ""
+        }];
+        i += 1;
+        while i < lines.len() {
+            let line = lines[i];
+            if is_blank(line) {
+                // Blank lines only belong to the item if it carries on after
+                // them.
+                let mut j = i;
+                while j < lines.len() && is_blank(lines[j]) { j += 1; }
+                if j == lines.len() ||
+                   indentation(lines[j]).0 < content_indent {
+                    break
+                }
+                for _ in range(i, j) { item.push(""); }
+                i = j;
+            } else if indentation(line).0 >= content_indent {
+                item.push(&line[content_indent..]);
+                i += 1;
+            } else if !is_blank(item[item.len() - 1]) && !starts_block(line) {
+                item.push(line.trim_left());
+                i += 1;
+            } else {
+                break
+            }
+        }
+        if item.iter().any(|l| is_blank(*l)) { tight = false; }
+        items.push(blocks(item.as_slice(), refs, depth + 1));
+
+        let mut j = i;
+        while j < lines.len() && is_blank(lines[j]) { j += 1; }
+        if j == lines.len() { break }
+        let (indent, rest) = indentation(lines[j]);
+        match list_marker(rest) {
+            Some(ref m) if indent < 4 && m.kind == first.kind => {
+                if j > i { tight = false; }
+                i = j;
+            }
+            _ => break,
+        }
+    }
+    out.push(Block::List(first.start, tight, items));
+    i
+}
+
+/// Parses the paragraph starting at `lines[i]`, returning the index of the
+/// first line after it. Link reference definitions at the start of the
+/// paragraph are added to `refs` instead.
+fn paragraph<'a>(lines: &[&'a str], mut i: usize, refs: &mut Refs,
+                 out: &mut Vec<Block<'a>>) -> usize {
+    let mut para: Vec<&str> = Vec::new();
+    while i < lines.len() && !is_blank(lines[i]) {
+        let line = lines[i];
+        if para.len() == 0 {
+            if let Some((label, def)) = reference(line) {
+                if !refs.contains_key(&label) { refs.insert(label, def); }
+                i += 1;
+                continue
+            }
+        } else {
+            // A line of `=` or `-` turns the paragraph into a heading.
+            let (indent, rest) = indentation(line);
+            let rest = rest.trim_right();
+            if indent < 4 && rest.len() > 0 &&
+               (rest.chars().all(|c| c == '=') ||
+                rest.chars().all(|c| c == '-')) {
+                let level = if rest.starts_with("=") {1} else {2};
+                if let Some(last) = para.pop() { para.push(last.trim_right()); }
+                out.push(Block::Heading(level, para));
+                return i + 1
+            }
+            if starts_block(line) { break }
+        }
+        // Trailing spaces are kept, as two of them make a line break.
+        para.push(line.trim_left());
+        i += 1;
+    }
+    if let Some(last) = para.pop() { para.push(last.trim_right()); }
+    if para.len() > 0 { out.push(Block::Paragraph(para)); }
+    i
+}
+
+fn is_blank(line: &str) -> bool {
+    line.chars().all(|c| c == ' ')
+}
+
+/// Returns the number of leading spaces in `line` and the rest of it.
+fn indentation(line: &str) -> (usize, &str) {
+    let rest = line.trim_left_matches(' ');
+    (line.len() - rest.len(), rest)
+}
+
+/// Tests whether `line` interrupts a paragraph.
+fn starts_block(line: &str) -> bool {
+    let (indent, rest) = indentation(line);
+    indent < 4 && (fence(rest).is_some() || atx_heading(rest).is_some() ||
+                   is_rule(rest) || rest.starts_with(">") ||
+                   list_marker(rest).is_some() || html_block(rest))
+}
+
+/// Tests whether `rest` starts a block of raw HTML, which lasts until the
+/// next blank line.
+fn html_block(rest: &str) -> bool {
+    if rest.starts_with("<!--") { return true }
+    let name = match sanitize::tag(rest) {
+        Some((tag, _)) => tag.name,
+        None => return false,
+    };
+    ["address", "article", "aside", "blockquote", "center", "details", "div",
+     "dl", "figure", "footer", "h1", "h2", "h3", "h4", "h5", "h6", "header",
+     "hr", "ol", "p", "pre", "script", "section", "style", "summary", "table",
+     "ul"].iter().any(|s| *s == name.as_slice())
+}
+
+/// Returns the character and length of a code fence along with its info
+/// string.
+fn fence(rest: &str) -> Option<(char, usize, &str)> {
+    let c = match rest.chars().next() {
+        Some(c @ '`') | Some(c @ '~') => c,
+        _ => return None,
+    };
+    let n = rest.len() - rest.trim_left_matches(c).len();
+    let info = rest[n..].trim();
+    if n < 3 || (c == '`' && info.contains("`")) { return None }
+    Some((c, n, info))
+}
+
+fn atx_heading(rest: &str) -> Option<usize> {
+    let level = rest.len() - rest.trim_left_matches('#').len();
+    if level == 0 || level > 6 { return None }
+    if rest.len() > level && !rest[level..].starts_with(" ") { return None }
+    Some(level)
+}
+
+fn is_rule(rest: &str) -> bool {
+    let mut chars = rest.chars().filter(|c| *c != ' ');
+    let first = match chars.next() {
+        Some(c @ '*') | Some(c @ '-') | Some(c @ '_') => c,
+        _ => return false,
+    };
+    let mut n = 1;
+    for c in chars {
+        if c != first { return false }
+        n += 1;
+    }
+    n >= 3
+}
+
+fn list_marker(rest: &str) -> Option<Marker> {
+    let digits = rest.len() - rest.trim_left_matches(|c: char| {
+        c.is_digit(10)
+    }).len();
+    let (kind, start, len) = if digits == 0 {
+        match rest.chars().next() {
+            Some(c @ '*') | Some(c @ '-') | Some(c @ '+') => (c, None, 1),
+            _ => return None,
+        }
+    } else if digits <= 9 {
+        match rest[digits..].chars().next() {
+            Some(c @ '.') | Some(c @ ')') => {
+                (c, rest[..digits].parse().ok(), digits + 1)
+            }
+            _ => return None,
+        }
+    } else {
+        return None
+    };
+    let after = &rest[len..];
+    if after.len() > 0 && !after.starts_with(" ") { return None }
+    // Content indented by more than four spaces is an indented code block
+    // inside of the item, so only one of those spaces belongs to the marker.
+    let spaces = after.len() - after.trim_left_matches(' ').len();
+    let spaces = if spaces == 0 || spaces > 4 || spaces == after.len() {
+        1
+    } else {
+        spaces
+    };
+    Some(Marker { kind: kind, start: start, width: len + spaces })
+}
+
+/// Parses a link reference definition such as `[label]: url "title"`.
+fn reference(line: &str) -> Option<(String, (String, Option<String>))> {
+    let (indent, rest) = indentation(line);
+    if indent >= 4 || !rest.starts_with("[") { return None }
+    let close = match rest.find("]:") { Some(i) => i, None => return None };
+    let label = normalize(&rest[1..close]);
+    if label.len() == 0 { return None }
+    let rest = rest[close + 2..].trim();
+    let (url, rest) = match destination(rest) {
+        Some(pair) => pair,
+        None => return None,
+    };
+    if url.len() == 0 { return None }
+    let rest = rest.trim();
+    let (title, rest) = if rest.len() == 0 {
+        (None, rest)
+    } else {
+        match title(rest) {
+            Some((title, rest)) => (Some(title), rest),
+            None => return None,
+        }
+    };
+    if rest.trim().len() > 0 { return None }
+    Some((label, (url, title)))
+}
+
+/// Parses a link destination from the start of `s`, returning it along with
+/// the rest of `s`.
+fn destination(s: &str) -> Option<(String, &str)> {
+    if s.starts_with("<") {
+        let end = match s.find('>') { Some(i) => i, None => return None };
+        let url = &s[1..end];
+        if url.contains("<") || url.contains("\n") { return None }
+        return Some((unescape(url), &s[end + 1..]))
+    }
+    let mut depth = 0;
+    let mut end = s.len();
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '(' => depth += 1,
+            ')' if depth == 0 => { end = i; break }
+            ')' => depth -= 1,
+            c if c == ' ' || (c as u32) < 0x20 => { end = i; break }
+            _ => {}
+        }
+    }
+    Some((unescape(&s[..end]), &s[end..]))
+}
+
+/// Parses a link title from the start of `s`, returning it along with the
+/// rest of `s`.
+fn title(s: &str) -> Option<(String, &str)> {
+    let close = match s.chars().next() {
+        Some('"') => '"',
+        Some('\'') => '\'',
+        Some('(') => ')',
+        _ => return None,
+    };
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == close => {
+                return Some((unescape(&s[1..i]), &s[i + 1..]))
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Normalizes a link label so that labels differing only in case and
+/// whitespace match each other.
+fn normalize(label: &str) -> String {
+    label.split(|c: char| c.is_whitespace()).filter(|s| s.len() > 0)
+         .collect::<Vec<_>>().connect(" ").to_ascii_lowercase()
+}
+
+/// Removes backslash escapes from `s`.
+fn unescape(s: &str) -> String {
+    let mut ret = String::new();
+    let mut escaped = false;
+    for c in s.chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+            continue
+        }
+        if escaped && !is_punctuation(c) { ret.push('\\'); }
+        escaped = false;
+        ret.push(c);
+    }
+    if escaped { ret.push('\\'); }
+    ret
+}
+
+fn is_punctuation(c: char) -> bool {
+    "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~".chars().any(|p| p == c)
+}
+
+fn render_blocks(blocks: &[Block], refs: &Refs, tight: bool,
+                 out: &mut String) {
+    for block in blocks.iter() {
+        match *block {
+            Block::Heading(level, ref lines) => {
+                out.push_str(format!("<h{}>", level).as_slice());
+                inline(lines.connect("\n").as_slice(), refs, out);
+                out.push_str(format!("</h{}>\n", level).as_slice());
+            }
+            // Paragraphs in tight lists aren't wrapped in `<p>`.
+            Block::Paragraph(ref lines) if tight => {
+                inline(lines.connect("\n").as_slice(), refs, out);
+            }
+            Block::Paragraph(ref lines) => {
+                out.push_str("<p>");
+                inline(lines.connect("\n").as_slice(), refs, out);
+                out.push_str("</p>\n");
+            }
+            Block::Code(lang, ref lines) => {
+                out.push_str("<pre><code");
+                if let Some(lang) = lang {
+                    out.push_str(" class=\"language-");
+                    out.push_str(escape(unescape(lang).as_slice()).as_slice());
+                    out.push_str("\"");
+                }
+                out.push_str(">");
+                for line in lines.iter() {
+                    out.push_str(escape(*line).as_slice());
+                    out.push_str("\n");
+                }
+                out.push_str("</code></pre>\n");
+            }
+            Block::Quote(ref blocks) => {
+                out.push_str("<blockquote>\n");
+                render_blocks(blocks.as_slice(), refs, false, out);
+                out.push_str("</blockquote>\n");
+            }
+            Block::List(start, tight, ref items) => {
+                match start {
+                    None => out.push_str("<ul>\n"),
+                    Some(1) => out.push_str("<ol>\n"),
+                    Some(n) => {
+                        out.push_str(format!("<ol start=\"{}\">\n", n)
+                                        .as_slice())
+                    }
+                }
+                for item in items.iter() {
+                    out.push_str("<li>");
+                    render_blocks(item.as_slice(), refs, tight, out);
+                    out.push_str("</li>\n");
+                }
+                out.push_str(if start.is_none() {"</ul>\n"} else {"</ol>\n"});
+            }
+            Block::Rule => out.push_str("<hr>\n"),
+            Block::Html(ref lines) => {
+                out.push_str(lines.connect("\n").as_slice());
+                out.push_str("\n");
+            }
+        }
+    }
+}
+
+/// Renders the inline elements in `text`.
+fn inline(text: &str, refs: &Refs, out: &mut String) {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if i + 1 < bytes.len() && bytes[i + 1] == b'\n' => {
+                out.push_str("<br>\n");
+                i += 2;
+            }
+            b'\\' if i + 1 < bytes.len() &&
+                     is_punctuation(bytes[i + 1] as char) => {
+                out.push_str(escape(&text[i + 1..i + 2]).as_slice());
+                i += 2;
+            }
+            b'\n' => {
+                // Two spaces at the end of a line make a hard line break.
+                if text[..i].ends_with("  ") {
+                    let len = out.trim_right_matches(' ').len();
+                    out.truncate(len);
+                    out.push_str("<br>");
+                }
+                out.push_str("\n");
+                i += 1;
+            }
+            b'`' => i = code_span(text, i, out),
+            b'*' | b'_' => i = emphasis(text, i, refs, out),
+            b'!' if i + 1 < bytes.len() && bytes[i + 1] == b'[' => {
+                match link(text, i + 1, refs) {
+                    Some((alt, url, title, end)) => {
+                        out.push_str("<img src=\"");
+                        out.push_str(escape(url.as_slice()).as_slice());
+                        out.push_str("\" alt=\"");
+                        out.push_str(escape(unescape(alt).as_slice())
+                                         .as_slice());
+                        out.push_str("\"");
+                        push_title(title, out);
+                        out.push_str(">");
+                        i = end;
+                    }
+                    None => { out.push_str("!"); i += 1; }
+                }
+            }
+            b'[' => {
+                match link(text, i, refs) {
+                    Some((content, url, title, end)) => {
+                        out.push_str("<a href=\"");
+                        out.push_str(escape(url.as_slice()).as_slice());
+                        out.push_str("\"");
+                        push_title(title, out);
+                        out.push_str(">");
+                        inline(content, refs, out);
+                        out.push_str("</a>");
+                        i = end;
+                    }
+                    None => { out.push_str("["); i += 1; }
+                }
+            }
+            b'<' => {
+                // Raw HTML is passed through as it is.
+                let len = if text[i..].starts_with("<!--") {
+                    text[i..].find("-->").map(|end| end + 3).unwrap_or(0)
+                } else {
+                    sanitize::tag(&text[i..]).map(|(_, len)| len).unwrap_or(0)
+                };
+                if len > 0 {
+                    out.push_str(&text[i..i + len]);
+                    i += len;
+                } else {
+                    i = autolink(text, i, out);
+                }
+            }
+            b'&' => {
+                // Entity references are passed through as they are.
+                let len = entity(&text[i..]);
+                if len > 0 {
+                    out.push_str(&text[i..i + len]);
+                    i += len;
+                } else {
+                    out.push_str("&amp;");
+                    i += 1;
+                }
+            }
+            b'>' => { out.push_str("&gt;"); i += 1; }
+            b'"' => { out.push_str("&quot;"); i += 1; }
+            _ => {
+                let end = bytes[i + 1..].iter().position(|b| {
+                    b"\\\n`*_![]<&>\"".contains(b)
+                }).map(|p| i + 1 + p).unwrap_or(bytes.len());
+                out.push_str(&text[i..end]);
+                i = end;
+            }
+        }
+    }
+}
+
+fn code_span(text: &str, i: usize, out: &mut String) -> usize {
+    let n = run(text, i, '`');
+    let mut j = i + n;
+    while j < text.len() {
+        if text.as_bytes()[j] != b'`' { j += 1; continue }
+        let m = run(text, j, '`');
+        if m == n {
+            let code = text[i + n..j].replace("\n", " ");
+            out.push_str("<code>");
+            out.push_str(escape(code.as_slice().trim()).as_slice());
+            out.push_str("</code>");
+            return j + m
+        }
+        j += m;
+    }
+    // An unmatched run of backticks is just text.
+    out.push_str(&text[i..i + n]);
+    i + n
+}
+
+fn emphasis(text: &str, i: usize, refs: &Refs, out: &mut String) -> usize {
+    let bytes = text.as_bytes();
+    let c = bytes[i] as char;
+    let n = run(text, i, c);
+    let next = text[i + n..].chars().next();
+    let opens = n <= 3 && next.map(|c| !c.is_whitespace()).unwrap_or(false) &&
+                // Underscores inside of words don't count.
+                !(c == '_' && text[..i].chars().rev().next()
+                                       .map(|c| c.is_alphanumeric())
+                                       .unwrap_or(false));
+    if opens {
+        let mut j = i + n;
+        while j < bytes.len() {
+            if bytes[j] != bytes[i] { j += 1; continue }
+            let m = run(text, j, c);
+            let before = text[..j].chars().rev().next().unwrap();
+            let after = text[j + m..].chars().next();
+            let closes = m == n && !before.is_whitespace() &&
+                         !(c == '_' && after.map(|c| c.is_alphanumeric())
+                                            .unwrap_or(false));
+            if closes {
+                let (open, close) = match n {
+                    1 => ("<em>", "</em>"),
+                    2 => ("<strong>", "</strong>"),
+                    _ => ("<strong><em>", "</em></strong>"),
+                };
+                out.push_str(open);
+                inline(&text[i + n..j], refs, out);
+                out.push_str(close);
+                return j + m
+            }
+            j += m;
+        }
+    }
+    out.push_str(&text[i..i + n]);
+    i + n
+}
+
+/// Returns the length of the run of `c` starting at `text[i]`.
+fn run(text: &str, i: usize, c: char) -> usize {
+    text[i..].len() - text[i..].trim_left_matches(c).len()
+}
+
+/// Parses the link starting with the `[` at `text[i]`, returning its content,
+/// destination, title and end.
+fn link<'a>(text: &'a str, i: usize, refs: &Refs)
+            -> Option<(&'a str, String, Option<String>, usize)> {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut close = None;
+    let mut j = i;
+    while j < bytes.len() {
+        match bytes[j] {
+            b'\\' => j += 1,
+            b'`' => {
+                // Brackets inside of code spans don't count.
+                let n = run(text, j, '`');
+                match text[j + n..].find(&text[j..j + n]) {
+                    Some(end) => j += n + end + n - 1,
+                    None => j += n - 1,
+                }
+            }
+            b'[' => depth += 1,
+            b']' => {
+                depth -= 1;
+                if depth == 0 { close = Some(j); break }
+            }
+            _ => {}
+        }
+        j += 1;
+    }
+    let close = match close { Some(close) => close, None => return None };
+    let content = &text[i + 1..close];
+    let rest = &text[close + 1..];
+
+    if rest.starts_with("(") {
+        let inner = rest[1..].trim_left();
+        if let Some((url, after)) = destination(inner) {
+            let after = after.trim_left();
+            let (title, after) = match title(after) {
+                Some((title, after)) => (Some(title), after.trim_left()),
+                None => (None, after),
+            };
+            if after.starts_with(")") {
+                let end = text.len() - after.len() + 1;
+                return Some((content, url, title, end))
+            }
+        }
+    }
+    let (label, end) = if rest.starts_with("[") {
+        match rest.find(']') {
+            Some(0) | None => (content, close + 1),
+            Some(1) => (content, close + 3),
+            Some(n) => (&rest[1..n], close + 2 + n),
+        }
+    } else {
+        (content, close + 1)
+    };
+    match refs.get(&normalize(label)) {
+        Some(&(ref url, ref title)) => {
+            Some((content, url.clone(), title.clone(), end))
+        }
+        None => None,
+    }
+}
+
+fn push_title(title: Option<String>, out: &mut String) {
+    if let Some(title) = title {
+        out.push_str(" title=\"");
+        out.push_str(escape(title.as_slice()).as_slice());
+        out.push_str("\"");
+    }
+}
+
+/// Renders an autolink such as `<http://example.com>` starting at `text[i]`,
+/// or an escaped `<` if there isn't one there.
+fn autolink(text: &str, i: usize, out: &mut String) -> usize {
+    let rest = &text[i + 1..];
+    if let Some(end) = rest.find('>') {
+        let url = &rest[..end];
+        let valid = url.len() > 0 && !url.contains("<") &&
+                    !url.chars().any(|c| c.is_whitespace());
+        let scheme = url.find(':').map(|i| &url[..i]).unwrap_or("");
+        let href = if !valid {
+            None
+        } else if scheme.len() > 1 &&
+                  scheme.chars().next().unwrap().is_alphabetic() &&
+                  scheme.chars().all(|c| {
+                      c.is_alphanumeric() || c == '+' || c == '.' || c == '-'
+                  }) {
+            Some(url.to_string())
+        } else if !url.contains("/") && url.contains("@") {
+            Some(format!("mailto:{}", url))
+        } else {
+            None
+        };
+        if let Some(href) = href {
+            out.push_str("<a href=\"");
+            out.push_str(escape(href.as_slice()).as_slice());
+            out.push_str("\">");
+            out.push_str(escape(url).as_slice());
+            out.push_str("</a>");
+            return i + end + 2
+        }
+    }
+    out.push_str("&lt;");
+    i + 1
+}
@@ -0,0 +1,67 @@
+//! Rendering of the Markdown in readmes and descriptions into HTML.
+//!
+//! Everything which comes out of here has been through an allow-list
+//! sanitizer, so it can be included in a page as is. Relative links and
+//! images are rewritten to point into the crate's repository, where the
+//! files they refer to actually live.
+
+use std::ascii::AsciiExt;
+
+pub mod markdown;
+pub mod sanitize;
+
+/// Renders the readme at `path` in a tarball as HTML. Markdown files are
+/// rendered as such, anything else is shown as plain text.
+pub fn readme_to_html(path: &str, text: &str,
+                      repository: Option<&str>) -> String {
+    let ext = match path.rfind('.') {
+        Some(i) => path[i + 1..].to_ascii_lowercase(),
+        None => String::new(),
+    };
+    match ext.as_slice() {
+        "md" | "markdown" | "mdown" | "mkdn" | "mkd" => {
+            markdown_to_html(text, repository)
+        }
+        _ => format!("<pre>{}</pre>\n", escape(text)),
+    }
+}
+
+/// Renders the Markdown `text` as sanitized HTML, with relative URLs
+/// resolved against `repository`.
+pub fn markdown_to_html(text: &str, repository: Option<&str>) -> String {
+    sanitize::clean(markdown::to_html(text).as_slice(), repository)
+}
+
+/// Escapes `s` for use in HTML text or attribute values.
+pub fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Returns the length of the entity reference at the start of `s`, or 0 if
+/// there isn't one.
+fn entity(s: &str) -> usize {
+    let end = match s.find(';') { Some(end) => end, None => return 0 };
+    let name = &s[1..end];
+    let valid = if name.starts_with("#x") || name.starts_with("#X") {
+        name.len() > 2 && name.len() <= 8 &&
+            name[2..].chars().all(|c| c.is_digit(16))
+    } else if name.starts_with("#") {
+        name.len() > 1 && name.len() <= 8 &&
+            name[1..].chars().all(|c| c.is_digit(10))
+    } else {
+        name.len() > 0 && name.len() <= 32 &&
+            name.chars().all(|c| (c as u32) < 0x80 && c.is_alphanumeric())
+    };
+    if valid {end + 1} else {0}
+}
@@ -0,0 +1,348 @@
+//! An allow-list HTML sanitizer.
+//!
+//! The input is broken up into text and tags, and only the tags and
+//! attributes listed below make it into the output, written back out in a
+//! normalized form. Anything inside of tags such as `<script>` is dropped
+//! along with them, URLs have to use one of a few safe schemes, and every tag
+//! which is opened is closed again so the output can't break the page around
+//! it.
+
+use std::ascii::AsciiExt;
+use std::char;
+use std::num;
+
+use super::{escape, entity};
+
+/// A start or end tag.
+pub struct Tag {
+    /// The name of the tag, in lower case.
+    pub name: String,
+    /// The names of the attributes, in lower case, and their decoded values.
+    pub attrs: Vec<(String, String)>,
+    pub end: bool,
+}
+
+static TAGS: &'static [&'static str] = &[
+    "a", "abbr", "b", "blockquote", "br", "code", "dd", "del", "details",
+    "div", "dl", "dt", "em", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i",
+    "img", "ins", "kbd", "li", "ol", "p", "pre", "q", "s", "samp", "span",
+    "strike", "strong", "sub", "summary", "sup", "table", "tbody", "td",
+    "tfoot", "th", "thead", "tr", "tt", "ul", "var",
+];
+
+/// Tags which never have any content or an end tag.
+static VOID_TAGS: &'static [&'static str] = &["br", "hr", "img"];
+
+/// Tags which are dropped along with everything inside of them.
+static DROPPED_TAGS: &'static [&'static str] = &[
+    "applet", "embed", "frame", "frameset", "iframe", "math", "noembed",
+    "noscript", "object", "script", "style", "svg", "template", "textarea",
+    "title", "xmp",
+];
+
+/// Sanitizes `html`, resolving relative URLs in it against `repository`.
+pub fn clean(html: &str, repository: Option<&str>) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut open = Vec::new();
+    let mut i = 0;
+    while i < html.len() {
+        let rest = &html[i..];
+        if rest.starts_with("<!--") {
+            i += rest[4..].find("-->").map(|end| end + 7).unwrap_or(rest.len());
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            i += rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        } else if let Some((tag, len)) = tag(rest) {
+            i += len;
+            if tag.end {
+                close(tag.name.as_slice(), &mut open, &mut out);
+            } else if contains(DROPPED_TAGS, tag.name.as_slice()) {
+                i += skip(&html[i..], tag.name.as_slice());
+            } else if contains(TAGS, tag.name.as_slice()) {
+                start(tag, repository, &mut open, &mut out);
+            }
+        } else if rest.starts_with("<") {
+            out.push_str("&lt;");
+            i += 1;
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            text(&rest[..end], &mut out);
+            i += end;
+        }
+    }
+    while let Some(name) = open.pop() {
+        out.push_str(format!("</{}>", name).as_slice());
+    }
+    out
+}
+
+/// Parses the tag at the start of `s`, returning it along with its length.
+pub fn tag(s: &str) -> Option<(Tag, usize)> {
+    let bytes = s.as_bytes();
+    if bytes.len() < 2 || bytes[0] != b'<' { return None }
+    let end = bytes[1] == b'/';
+    let mut i = if end {2} else {1};
+    if i >= bytes.len() || !(bytes[i] as char).is_alphabetic() ||
+       bytes[i] >= 0x80 {
+        return None
+    }
+    let len = s[i..].find(|c: char| {
+        (c as u32) >= 0x80 || !(c.is_alphanumeric() || c == '-')
+    }).unwrap_or(s.len() - i);
+    let name = s[i..i + len].to_ascii_lowercase();
+    i += len;
+
+    let mut attrs = Vec::new();
+    loop {
+        let space = whitespace(&s[i..]);
+        i += space;
+        if i >= bytes.len() { return None }
+        match bytes[i] {
+            b'>' => i += 1,
+            b'/' if bytes.get(i + 1) == Some(&b'>') => i += 2,
+            // Attributes have to be separated from what comes before them,
+            // and end tags can't have any at all.
+            _ if space == 0 || end => return None,
+            _ => {
+                let (attr, len) = match attribute(&s[i..]) {
+                    Some(pair) => pair,
+                    None => return None,
+                };
+                attrs.push(attr);
+                i += len;
+                continue
+            }
+        }
+        let tag = Tag { name: name, attrs: attrs, end: end };
+        return Some((tag, i))
+    }
+}
+
+/// Parses the attribute at the start of `s`, returning its name and decoded
+/// value along with its length.
+fn attribute(s: &str) -> Option<((String, String), usize)> {
+    let special = |c: char| {
+        c.is_whitespace() || "/>=\"'<`".chars().any(|d| d == c)
+    };
+    let len = s.find(|c: char| special(c)).unwrap_or(s.len());
+    if len == 0 { return None }
+    let name = s[..len].to_ascii_lowercase();
+    let mut i = len;
+    let space = whitespace(&s[i..]);
+    if !s[i + space..].starts_with("=") {
+        return Some(((name, String::new()), i))
+    }
+    i += space + 1;
+    i += whitespace(&s[i..]);
+    let quote = match s[i..].chars().next() {
+        Some(c @ '"') | Some(c @ '\'') => c,
+        Some(_) => {
+            let len = s[i..].find(|c: char| special(c)).unwrap_or(s.len() - i);
+            if len == 0 { return None }
+            return Some(((name, decode(&s[i..i + len])), i + len))
+        }
+        None => return None,
+    };
+    let len = match s[i + 1..].find(quote) {
+        Some(len) => len,
+        None => return None,
+    };
+    Some(((name, decode(&s[i + 1..i + 1 + len])), i + len + 2))
+}
+
+fn whitespace(s: &str) -> usize {
+    s.find(|c: char| !c.is_whitespace()).unwrap_or(s.len())
+}
+
+/// Decodes the character references in an attribute value.
+fn decode(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        ret.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let (c, len) = reference(rest);
+        match c {
+            Some(c) => ret.push(c),
+            None => ret.push_str(&rest[..len]),
+        }
+        rest = &rest[len..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/// Decodes the character reference at the start of `s`, returning the
+/// character it stands for along with its length. Browsers don't insist on
+/// the `;` after numeric references, so neither does this.
+fn reference(s: &str) -> (Option<char>, usize) {
+    let named = [("amp", '&'), ("lt", '<'), ("gt", '>'), ("quot", '"'),
+                 ("apos", '\''), ("nbsp", '\u{a0}')];
+    for &(name, c) in named.iter() {
+        if s[1..].starts_with(name) && s[1 + name.len()..].starts_with(";") {
+            return (Some(c), name.len() + 2)
+        }
+    }
+    let (radix, start) = if s.starts_with("&#x") || s.starts_with("&#X") {
+        (16, 3)
+    } else if s.starts_with("&#") {
+        (10, 2)
+    } else {
+        return (None, 1)
+    };
+    let len = s[start..].find(|c: char| !c.is_digit(radix))
+                        .unwrap_or(s.len() - start);
+    if len == 0 { return (None, 1) }
+    let n = num::from_str_radix::<u32>(&s[start..start + len], radix).ok();
+    let c = n.and_then(char::from_u32).unwrap_or('\u{fffd}');
+    let end = start + len;
+    (Some(c), if s[end..].starts_with(";") {end + 1} else {end})
+}
+
+/// Writes out `text`, escaping anything which isn't already.
+fn text(text: &str, out: &mut String) {
+    for (i, c) in text.char_indices() {
+        match c {
+            '&' if entity(&text[i..]) == 0 => out.push_str("&amp;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Returns the length of everything up to and including the end tag which
+/// matches the `name` tag that `s` follows.
+fn skip(s: &str, name: &str) -> usize {
+    let lower = s.to_ascii_lowercase();
+    let end = match lower.as_slice().find(format!("</{}", name).as_slice()) {
+        Some(end) => end,
+        None => return s.len(),
+    };
+    s[end..].find('>').map(|i| end + i + 1).unwrap_or(s.len())
+}
+
+fn start(tag: Tag, repository: Option<&str>, open: &mut Vec<String>,
+         out: &mut String) {
+    let Tag { name, attrs, .. } = tag;
+    let name = name.as_slice();
+    let mut seen = Vec::new();
+    out.push_str("<");
+    out.push_str(name);
+    for (attr, value) in attrs.into_iter() {
+        if !allowed_attribute(name, attr.as_slice()) ||
+           seen.contains(&attr) {
+            continue
+        }
+        let value = match attr.as_slice() {
+            "href" | "src" => {
+                match url(value.as_slice(), repository, name == "img") {
+                    Some(url) => url,
+                    None => continue,
+                }
+            }
+            // Only the classes used for syntax highlighting are allowed.
+            "class" if !value.starts_with("language-") ||
+                       value.contains(" ") => continue,
+            _ => value,
+        };
+        out.push_str(format!(" {}=\"{}\"", attr,
+                             escape(value.as_slice())).as_slice());
+        seen.push(attr);
+    }
+    if name == "a" { out.push_str(" rel=\"nofollow\""); }
+    out.push_str(">");
+    if !contains(VOID_TAGS, name) { open.push(name.to_string()); }
+}
+
+/// Closes the most recently opened `name` tag, along with any tags opened
+/// inside of it. End tags without a matching start tag are dropped.
+fn close(name: &str, open: &mut Vec<String>, out: &mut String) {
+    let pos = match open.iter().rposition(|n| n.as_slice() == name) {
+        Some(pos) => pos,
+        None => return,
+    };
+    while open.len() > pos {
+        out.push_str(format!("</{}>", open.pop().unwrap()).as_slice());
+    }
+}
+
+fn allowed_attribute(tag: &str, attr: &str) -> bool {
+    match (tag, attr) {
+        (_, "title") => true,
+        ("a", "href") => true,
+        ("img", "src") | ("img", "alt") |
+        ("img", "width") | ("img", "height") => true,
+        ("code", "class") => true,
+        ("ol", "start") => true,
+        ("td", "colspan") | ("td", "rowspan") |
+        ("th", "colspan") | ("th", "rowspan") => true,
+        ("details", "open") => true,
+        (_, "align") => {
+            contains(&["div", "h1", "h2", "h3", "h4", "h5", "h6", "img", "p",
+                       "table", "td", "th"], tag)
+        }
+        _ => false,
+    }
+}
+
+/// Checks that `url` is safe to link to, resolving it against `repository`
+/// if it's relative. Only a handful of schemes are allowed, which rules out
+/// `javascript:` and friends.
+fn url(url: &str, repository: Option<&str>, image: bool) -> Option<String> {
+    let url = url.trim();
+    // Browsers ignore control characters in URLs, so `java\tscript:` would
+    // get past the check below.
+    if url.chars().any(|c| (c as u32) < 0x20 || c as u32 == 0x7f) {
+        return None
+    }
+    match url.find(':') {
+        Some(i) if !url[..i].contains("/") && !url[..i].contains("?") &&
+                   !url[..i].contains("#") => {
+            let scheme = url[..i].to_ascii_lowercase();
+            if contains(&["http", "https", "mailto"], scheme.as_slice()) {
+                Some(url.to_string())
+            } else {
+                None
+            }
+        }
+        _ => Some(resolve(url, repository, image)),
+    }
+}
+
+/// Resolves the relative `url` against the root of `repository`. Links to
+/// files in a GitHub repository go to GitHub's page for the file, while
+/// images go to the raw file.
+fn resolve(url: &str, repository: Option<&str>, image: bool) -> String {
+    let repository = match repository {
+        Some(r) if r.starts_with("https://") || r.starts_with("http://") => r,
+        _ => return url.to_string(),
+    };
+    if url.starts_with("#") || url.starts_with("//") {
+        return url.to_string()
+    }
+    let mut path = url.trim_left_matches('/');
+    while path.starts_with("./") { path = &path[2..]; }
+    let mut repository = repository.trim_right_matches('/');
+    if repository.ends_with(".git") {
+        repository = &repository[..repository.len() - 4];
+    }
+    let github = ["https://github.com/", "http://github.com/"].iter()
+                                                             .map(|p| *p)
+                                                             .find(|p| {
+        repository.starts_with(*p)
+    });
+    match github {
+        Some(prefix) if image => {
+            format!("https://raw.githubusercontent.com/{}/master/{}",
+                    &repository[prefix.len()..], path)
+        }
+        Some(prefix) => {
+            format!("https://github.com/{}/blob/master/{}",
+                    &repository[prefix.len()..], path)
+        }
+        None => format!("{}/{}", repository, path),
+    }
+}
+
+fn contains(list: &[&str], s: &str) -> bool {
+    list.iter().any(|t| *t == s)
+}
@@ -0,0 +1,251 @@
+//! Just enough of an XML parser to read the responses that S3 sends back.
+//!
+//! Attributes, comments, processing instructions and doctypes are all skipped
+//! over, as S3 responses only carry data in elements and their text.
+
+use std::str;
+
+pub struct Element {
+    pub name: String,
+    pub children: Vec<Node>,
+}
+
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    /// Returns the first child element named `name`.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children(name).next()
+    }
+
+    /// Returns an iterator over all child elements named `name`.
+    pub fn children<'a>(&'a self, name: &'a str)
+                        -> Box<Iterator<Item=&'a Element> + 'a> {
+        Box::new(self.children.iter().filter_map(move |n| {
+            match *n {
+                Node::Element(ref e) if e.name.as_slice() == name => Some(e),
+                _ => None,
+            }
+        }))
+    }
+
+    /// Returns the concatenation of all text directly inside this element.
+    pub fn text(&self) -> String {
+        let mut ret = String::new();
+        for node in self.children.iter() {
+            match *node {
+                Node::Text(ref s) => ret.push_str(s.as_slice()),
+                Node::Element(..) => {}
+            }
+        }
+        ret
+    }
+
+    /// Returns the text of the child element `name`, if there is one.
+    pub fn child_text(&self, name: &str) -> Option<String> {
+        self.child(name).map(|e| e.text())
+    }
+}
+
+/// Parses `s` and returns its root element.
+pub fn parse(s: &str) -> Result<Element, String> {
+    let mut parser = Parser { s: s, pos: 0 };
+    try!(parser.skip_misc());
+    let root = try!(parser.element());
+    try!(parser.skip_misc());
+    if parser.pos != s.len() {
+        return Err(format!("trailing data at byte {}", parser.pos))
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str { &self.s[self.pos..] }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        let trimmed = rest.trim_left();
+        self.pos += rest.len() - trimmed.len();
+    }
+
+    /// Skips whitespace along with any prolog, comments and doctypes.
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            let end = if rest.starts_with("<?") {
+                "?>"
+            } else if rest.starts_with("<!--") {
+                "-->"
+            } else if rest.starts_with("<!") {
+                ">"
+            } else {
+                return Ok(())
+            };
+            try!(self.skip_past(end));
+        }
+    }
+
+    fn skip_past(&mut self, pat: &str) -> Result<(), String> {
+        match self.rest().find_str(pat) {
+            Some(i) => { self.pos += i + pat.len(); Ok(()) }
+            None => Err(format!("unterminated markup, expected `{}`", pat)),
+        }
+    }
+
+    fn element(&mut self) -> Result<Element, String> {
+        if !self.rest().starts_with("<") {
+            return Err(format!("expected an element at byte {}", self.pos))
+        }
+        self.pos += 1;
+        let name_len = self.rest().find(|&: c: char| {
+            c.is_whitespace() || c == '>' || c == '/'
+        }).unwrap_or(self.rest().len());
+        let name = self.rest()[..name_len].to_string();
+        if name.len() == 0 {
+            return Err(format!("missing element name at byte {}", self.pos))
+        }
+        self.pos += name_len;
+
+        // Skip over attributes, taking care of the closing `>` or `/>`.
+        let tag_len = try!(self.rest().find('>').ok_or_else(|| {
+            format!("unterminated tag `{}`", name)
+        }));
+        let empty = self.rest()[..tag_len].ends_with("/");
+        self.pos += tag_len + 1;
+        let mut element = Element { name: name, children: Vec::new() };
+        if empty { return Ok(element) }
+
+        loop {
+            try!(self.skip_comments());
+            let rest = self.rest();
+            if rest.len() == 0 {
+                return Err(format!("unclosed element `{}`", element.name))
+            } else if rest.starts_with("</") {
+                // The name must be followed by `>`, perhaps after some
+                // whitespace, so that `</ab>` doesn't close `<a>`.
+                let end = format!("</{}", element.name);
+                let after = if rest.starts_with(end.as_slice()) {
+                    rest[end.len()..].trim_left()
+                } else {
+                    ""
+                };
+                if !after.starts_with(">") {
+                    return Err(format!("mismatched closing tag for `{}`",
+                                       element.name))
+                }
+                self.pos += rest.len() - after.len() + 1;
+                return Ok(element)
+            } else if rest.starts_with("<![CDATA[") {
+                let end = try!(rest.find_str("]]>").ok_or_else(|| {
+                    "unterminated CDATA section".to_string()
+                }));
+                element.children.push(Node::Text(rest[9..end].to_string()));
+                self.pos += end + 3;
+            } else if rest.starts_with("<") {
+                element.children.push(Node::Element(try!(self.element())));
+            } else {
+                let len = rest.find('<').unwrap_or(rest.len());
+                let text = try!(unescape(&rest[..len]));
+                element.children.push(Node::Text(text));
+                self.pos += len;
+            }
+        }
+    }
+
+    fn skip_comments(&mut self) -> Result<(), String> {
+        while self.rest().starts_with("<!--") {
+            try!(self.skip_past("-->"));
+        }
+        Ok(())
+    }
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut ret = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        ret.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = try!(rest.find(';').ok_or_else(|| {
+            format!("unterminated entity in `{}`", s)
+        }));
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ if entity.starts_with("#x") => {
+                try!(parse_char(&entity[2..], 16))
+            }
+            _ if entity.starts_with("#") => try!(parse_char(&entity[1..], 10)),
+            _ => return Err(format!("unknown entity `&{};`", entity)),
+        };
+        ret.push(c);
+        rest = &rest[end + 1..];
+    }
+    ret.push_str(rest);
+    return Ok(ret);
+
+    fn parse_char(s: &str, radix: u32) -> Result<char, String> {
+        let n = try!(::std::num::from_str_radix::<u32>(s, radix).map_err(|_| {
+            format!("invalid character reference `{}`", s)
+        }));
+        ::std::char::from_u32(n).ok_or_else(|| {
+            format!("invalid character reference `{}`", s)
+        })
+    }
+}
+
+/// Parses `bytes` as UTF-8 encoded XML.
+pub fn parse_bytes(bytes: &[u8]) -> Result<Element, String> {
+    let s = try!(str::from_utf8(bytes).map_err(|_| {
+        "response was not valid utf-8".to_string()
+    }));
+    parse(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn elements_and_text() {
+        let root = parse("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                          <a xmlns=\"http://example.com/\">\
+                            <b>one &amp; &quot;two&quot;</b>\
+                            <!-- comment -->\
+                            <c/>\
+                            <b>&#51;&#x34;</b>\
+                          </a>").unwrap();
+        assert_eq!(root.name.as_slice(), "a");
+        let bs = root.children("b").map(|b| b.text()).collect::<Vec<_>>();
+        assert_eq!(bs, vec!["one & \"two\"".to_string(), "34".to_string()]);
+        assert_eq!(root.child_text("c"), Some(String::new()));
+        assert!(root.child("d").is_none());
+
+        let root = parse("<a><ab>x</ab ></a\n>").unwrap();
+        assert_eq!(root.child_text("ab"), Some("x".to_string()));
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(parse("").is_err());
+        assert!(parse("<a>").is_err());
+        assert!(parse("<a></b>").is_err());
+        assert!(parse("<a></ab>").is_err());
+        assert!(parse("<a><ab></a></ab>").is_err());
+        assert!(parse("<a>&bogus;</a>").is_err());
+        assert!(parse("<a></a><b></b>").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Parsing of SPDX license expressions.
+//!
+//! Crates declare their license as an expression such as
+//! `MIT OR Apache-2.0` or `GPL-2.0+ WITH Classpath-exception-2.0`, following
+//! appendix IV of the SPDX specification. The older `MIT/Apache-2.0` syntax
+//! is still accepted and is read as `OR`. Expressions are normalized into a
+//! canonical form before being stored, so the same set of licenses is always
+//! spelled the same way.
+
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::iter::repeat;
+
+use licenses::{KNOWN_LICENSES, KNOWN_EXCEPTIONS};
+use util::{CargoError, CargoResult, human};
+
+/// How deeply parentheses can be nested in an expression.
+const MAX_NESTING: usize = 32;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Expr {
+    License(License),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct License {
+    pub id: &'static str,
+    pub or_later: bool,
+    pub exception: Option<&'static str>,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Kind { Word, Open, Close, Slash, Invalid, End }
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+    start: usize,
+}
+
+struct Parser<'a> {
+    expr: &'a str,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    depth: usize,
+}
+
+/// Parses `s` as a license expression, returning an error which points at
+/// the offending token if it isn't one.
+pub fn parse(s: &str) -> CargoResult<Expr> {
+    let mut parser = Parser {
+        expr: s,
+        tokens: tokenize(s),
+        pos: 0,
+        depth: 0,
+    };
+    let expr = try!(parser.or());
+    let token = parser.next();
+    match token.kind {
+        Kind::End => Ok(expr),
+        Kind::Close => Err(parser.error(token, "unmatched `)`".to_string())),
+        _ => Err(parser.error(token, format!("expected `AND`, `OR` or the \
+                                              end of the expression, found {}",
+                                             describe(token)))),
+    }
+}
+
+impl Expr {
+    /// Returns the identifiers of all licenses mentioned in this expression,
+    /// in the order they appear.
+    pub fn licenses(&self) -> Vec<&'static str> {
+        match *self {
+            Expr::License(ref l) => vec![l.id],
+            Expr::And(ref terms) | Expr::Or(ref terms) => {
+                terms.iter().flat_map(|t| t.licenses().into_iter()).collect()
+            }
+        }
+    }
+
+    /// Returns whether the terms of the license `id` alone are enough to
+    /// satisfy this expression. `MIT OR Apache-2.0` allows `MIT`, for
+    /// example, but `MIT AND Zlib` does not.
+    pub fn allows(&self, id: &str) -> bool {
+        match *self {
+            Expr::License(ref l) => l.id == id,
+            Expr::And(ref terms) => terms.iter().all(|t| t.allows(id)),
+            Expr::Or(ref terms) => terms.iter().any(|t| t.allows(id)),
+        }
+    }
+
+    fn and(terms: Vec<Expr>) -> Expr {
+        if terms.len() == 1 { return terms.into_iter().next().unwrap() }
+        let mut ret = Vec::new();
+        for term in terms.into_iter() {
+            match term {
+                Expr::And(inner) => ret.extend(inner.into_iter()),
+                term => ret.push(term),
+            }
+        }
+        Expr::And(ret)
+    }
+
+    fn or(terms: Vec<Expr>) -> Expr {
+        if terms.len() == 1 { return terms.into_iter().next().unwrap() }
+        let mut ret = Vec::new();
+        for term in terms.into_iter() {
+            match term {
+                Expr::Or(inner) => ret.extend(inner.into_iter()),
+                term => ret.push(term),
+            }
+        }
+        Expr::Or(ret)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::License(ref l) => {
+                try!(write!(f, "{}", l.id));
+                if l.or_later { try!(write!(f, "+")); }
+                match l.exception {
+                    Some(e) => write!(f, " WITH {}", e),
+                    None => Ok(()),
+                }
+            }
+            Expr::And(ref terms) => {
+                for (i, term) in terms.iter().enumerate() {
+                    if i > 0 { try!(write!(f, " AND ")); }
+                    // `AND` binds tighter than `OR`, so an `OR` inside of it
+                    // is the only thing which needs parentheses.
+                    match *term {
+                        Expr::Or(..) => try!(write!(f, "({})", term)),
+                        _ => try!(write!(f, "{}", term)),
+                    }
+                }
+                Ok(())
+            }
+            Expr::Or(ref terms) => {
+                for (i, term) in terms.iter().enumerate() {
+                    if i > 0 { try!(write!(f, " OR ")); }
+                    try!(write!(f, "{}", term));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Token<'a> { self.tokens[self.pos] }
+
+    fn next(&mut self) -> Token<'a> {
+        let token = self.tokens[self.pos];
+        if token.kind != Kind::End { self.pos += 1; }
+        token
+    }
+
+    // or := and (("OR" | "/") and)*
+    fn or(&mut self) -> CargoResult<Expr> {
+        let mut terms = vec![try!(self.and())];
+        loop {
+            let token = self.peek();
+            if !is_operator(token, "OR") && token.kind != Kind::Slash { break }
+            self.next();
+            terms.push(try!(self.and()));
+        }
+        Ok(Expr::or(terms))
+    }
+
+    // and := term ("AND" term)*
+    fn and(&mut self) -> CargoResult<Expr> {
+        let mut terms = vec![try!(self.term())];
+        while is_operator(self.peek(), "AND") {
+            self.next();
+            terms.push(try!(self.term()));
+        }
+        Ok(Expr::and(terms))
+    }
+
+    // term := "(" or ")" | license ["+"] ["WITH" exception]
+    fn term(&mut self) -> CargoResult<Expr> {
+        let token = self.next();
+        match token.kind {
+            Kind::Open => {
+                if self.depth == MAX_NESTING {
+                    return Err(self.error(token, format!("parentheses can \
+                        only be nested {} deep", MAX_NESTING)))
+                }
+                self.depth += 1;
+                let expr = try!(self.or());
+                self.depth -= 1;
+                let close = self.next();
+                match close.kind {
+                    Kind::Close => Ok(expr),
+                    Kind::End => {
+                        Err(self.error(token, "unclosed `(`".to_string()))
+                    }
+                    _ => Err(self.error(close, format!("expected `)`, \
+                                                        found {}",
+                                                       describe(close)))),
+                }
+            }
+            Kind::Word if !is_keyword(token) => {
+                let (id, or_later) = if token.text.ends_with("+") {
+                    (&token.text[..token.text.len() - 1], true)
+                } else {
+                    (token.text, false)
+                };
+                let id = match lookup(KNOWN_LICENSES, id) {
+                    Some(id) => id,
+                    None => {
+                        return Err(self.error(token, format!("unknown \
+                            license `{}`, see http://opensource.org/licenses \
+                            for options, and http://spdx.org/licenses/ for \
+                            their identifiers", id)))
+                    }
+                };
+                let mut exception = None;
+                if is_operator(self.peek(), "WITH") {
+                    self.next();
+                    let token = self.next();
+                    if token.kind != Kind::Word || is_keyword(token) {
+                        return Err(self.error(token, format!("expected a \
+                            license exception, found {}", describe(token))))
+                    }
+                    exception = match lookup(KNOWN_EXCEPTIONS, token.text) {
+                        Some(e) => Some(e),
+                        None => {
+                            return Err(self.error(token, format!("unknown \
+                                license exception `{}`, see \
+                                http://spdx.org/licenses/exceptions-index.html \
+                                for their identifiers", token.text)))
+                        }
+                    };
+                }
+                Ok(Expr::License(License {
+                    id: id,
+                    or_later: or_later,
+                    exception: exception,
+                }))
+            }
+            _ => Err(self.error(token, format!("expected a license, found {}",
+                                               describe(token)))),
+        }
+    }
+
+    fn error(&self, token: Token, msg: String) -> Box<CargoError> {
+        // The caret is lined up by characters, not bytes, so that it still
+        // points at the right place after any non-ASCII text.
+        let offset = self.expr[..token.start].chars().count();
+        let width = match token.text.chars().count() { 0 => 1, n => n };
+        human(format!("{} in the license expression `{}`\n\n    {}\n    {}{}",
+                      msg, self.expr, self.expr,
+                      repeat(' ').take(offset).collect::<String>(),
+                      repeat('^').take(width).collect::<String>()))
+    }
+}
+
+fn tokenize(s: &str) -> Vec<Token> {
+    let mut ret = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    loop {
+        let (start, c) = match chars.next() {
+            Some(pair) => pair,
+            None => break,
+        };
+        let kind = match c {
+            ' ' | '\t' | '\n' | '\r' => continue,
+            '(' => Kind::Open,
+            ')' => Kind::Close,
+            '/' => Kind::Slash,
+            c if is_idchar(c) => {
+                let mut end = start + 1;
+                loop {
+                    match chars.peek() {
+                        Some(&(i, c)) if is_idchar(c) => end = i + 1,
+                        Some(&(i, '+')) => { end = i + 1; chars.next(); break }
+                        _ => break,
+                    }
+                    chars.next();
+                }
+                ret.push(Token { kind: Kind::Word, text: &s[start..end],
+                                 start: start });
+                continue
+            }
+            _ => Kind::Invalid,
+        };
+        let end = start + c.len_utf8();
+        ret.push(Token { kind: kind, text: &s[start..end], start: start });
+    }
+    ret.push(Token { kind: Kind::End, text: "", start: s.len() });
+    return ret;
+
+    fn is_idchar(c: char) -> bool {
+        c.is_ascii() && (c.is_alphanumeric() || c == '.' || c == '-')
+    }
+}
+
+// Operators are written either all in upper case or all in lower case.
+fn is_operator(token: Token, op: &str) -> bool {
+    token.kind == Kind::Word &&
+        (token.text == op || token.text == op.to_ascii_lowercase())
+}
+
+fn is_keyword(token: Token) -> bool {
+    is_operator(token, "AND") || is_operator(token, "OR") ||
+        is_operator(token, "WITH")
+}
+
+// Identifiers are matched case-insensitively, and the canonical spelling is
+// returned.
+fn lookup(list: &[&'static str], id: &str) -> Option<&'static str> {
+    list.iter().map(|s| *s).find(|s| s.eq_ignore_ascii_case(id))
+}
+
+fn describe(token: Token) -> String {
+    match token.kind {
+        Kind::End => "the end of the expression".to_string(),
+        _ => format!("`{}`", token.text),
+    }
+}
@@ -0,0 +1,106 @@
+use std::old_io::fs::PathExtensions;
+use std::old_io::{self, fs, util, File};
+
+use rand::{thread_rng, Rng};
+
+use storage::Storage;
+use util::{CargoResult, ChainError, human, internal};
+
+/// Storage backed by a directory on the local filesystem.
+pub struct Local {
+    root: Path,
+}
+
+impl Local {
+    pub fn new(root: Path) -> Local {
+        Local { root: root }
+    }
+
+    /// Maps `path` to a file underneath the root, refusing anything which
+    /// could name a file elsewhere such as `//etc/passwd` or `a/../../b`.
+    fn path(&self, path: &str) -> CargoResult<Path> {
+        let rel = if path.starts_with("/") {&path[1..]} else {path};
+        let invalid = rel.len() > 0 && rel.split('/').any(|c| {
+            c.len() == 0 || c == "." || c == ".." || c.contains_char('\0')
+        });
+        if invalid {
+            return Err(human(format!("invalid storage path: `{}`", path)))
+        }
+        let ret = self.root.join(rel);
+        if !self.root.is_ancestor_of(&ret) {
+            return Err(human(format!("invalid storage path: `{}`", path)))
+        }
+        Ok(ret)
+    }
+}
+
+impl Storage for Local {
+    fn put(&self, path: &str, content: &mut Reader, _length: u64,
+           _content_type: &str) -> CargoResult<()> {
+        let dst = try!(self.path(path));
+        try!(fs::mkdir_recursive(&dst.dir_path(), old_io::USER_RWX));
+
+        // Write to a temporary file first so a failed upload never leaves a
+        // truncated tarball where a client could download it. Each upload
+        // gets its own file in case the same path is uploaded concurrently.
+        let suffix: String = thread_rng().gen_ascii_chars().take(16).collect();
+        let tmp = dst.with_extension(format!("{}.tmp", suffix));
+        let res = File::create(&tmp).and_then(|mut file| {
+            util::copy(content, &mut file)
+        }).and_then(|()| fs::rename(&tmp, &dst));
+        if res.is_err() {
+            let _ = fs::unlink(&tmp);
+        }
+        res.chain_error(|| {
+            internal(format!("failed to write `{}`", dst.display()))
+        })
+    }
+
+    fn get(&self, path: &str) -> CargoResult<Vec<u8>> {
+        let path = try!(self.path(path));
+        Ok(try!(File::open(&path).read_to_end()))
+    }
+
+    fn delete(&self, path: &str) -> CargoResult<()> {
+        let path = try!(self.path(path));
+        if path.exists() {
+            try!(fs::unlink(&path));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> CargoResult<bool> {
+        Ok(try!(self.path(path)).exists())
+    }
+
+    fn list(&self, prefix: &str) -> CargoResult<Vec<String>> {
+        let dir = match prefix.rfind('/') {
+            Some(i) => &prefix[..i],
+            None => "",
+        };
+        let dir = try!(self.path(dir));
+        if !dir.is_dir() { return Ok(Vec::new()) }
+
+        let mut ret = Vec::new();
+        for file in try!(fs::walk_dir(&dir)) {
+            if !file.is_file() || file.extension_str() == Some("tmp") {
+                continue
+            }
+            let rel = file.path_relative_from(&self.root).unwrap();
+            let rel = format!("/{}", rel.as_str().unwrap());
+            let matches = if prefix.starts_with("/") {
+                rel.starts_with(prefix)
+            } else {
+                rel[1..].starts_with(prefix)
+            };
+            if matches { ret.push(rel) }
+        }
+        ret.sort();
+        Ok(ret)
+    }
+
+    fn url(&self, path: &str) -> String {
+        let path = if path.starts_with("/") {&path[1..]} else {path};
+        format!("/storage/{}", path)
+    }
+}
@@ -0,0 +1,86 @@
+//! Backends for storing the `.crate` tarballs which are uploaded to the
+//! registry.
+//!
+//! The registry only needs a very small set of operations from its storage, so
+//! the `Storage` trait is the interface that `App` uses to talk to whichever
+//! backend has been configured. Production uses S3, but a registry can also be
+//! run entirely on local disk, in which case the tarballs are served by the
+//! registry itself via the `serve` handler below.
+
+use std::collections::HashMap;
+use std::old_io::MemReader;
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
+
+use Config;
+use app::RequestApp;
+use util::{CargoResult, ChainError};
+use util::errors::NotFound;
+
+pub use self::local::Local;
+pub use self::s3::S3;
+
+mod local;
+mod s3;
+
+/// The type of backend to store crates in, as selected by `Config`.
+#[derive(Clone)]
+pub enum Backend {
+    /// Store crates in the S3 bucket described by the `s3_*` fields of the
+    /// configuration.
+    S3,
+    /// Store crates on the local filesystem underneath the given directory.
+    Local(Path),
+}
+
+pub trait Storage: Send + Sync {
+    /// Uploads `length` bytes read from `content` to `path`.
+    fn put(&self, path: &str, content: &mut Reader, length: u64,
+           content_type: &str) -> CargoResult<()>;
+
+    /// Downloads the full contents of the file at `path`.
+    fn get(&self, path: &str) -> CargoResult<Vec<u8>>;
+
+    /// Removes the file at `path`.
+    fn delete(&self, path: &str) -> CargoResult<()>;
+
+    /// Tests whether a file is present at `path`.
+    fn exists(&self, path: &str) -> CargoResult<bool>;
+
+    /// Returns the paths of all files whose path starts with `prefix`.
+    fn list(&self, prefix: &str) -> CargoResult<Vec<String>>;
+
+    /// Returns the URL that clients should use to download the file at
+    /// `path`.
+    fn url(&self, path: &str) -> String;
+}
+
+/// Creates the storage backend that `config` asks for.
+pub fn new(config: &Config) -> Box<Storage + Send + Sync> {
+    match config.storage {
+        Backend::S3 => Box::new(S3::new(config)) as Box<Storage + Send + Sync>,
+        Backend::Local(ref root) => {
+            Box::new(Local::new(root.clone())) as Box<Storage + Send + Sync>
+        }
+    }
+}
+
+/// Handler for serving files out of storage, used when the registry is
+/// configured to hand out files itself rather than redirecting to S3.
+pub fn serve(req: &mut Request) -> CargoResult<Response> {
+    let path = format!("/{}", req.params()["path"]);
+    let data = try!(req.app().storage.get(path.as_slice()).chain_error(|| {
+        NotFound
+    }));
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(),
+                   vec!["application/x-tar".to_string()]);
+    headers.insert("Content-Length".to_string(), vec![data.len().to_string()]);
+    Ok(Response {
+        status: (200, "OK"),
+        headers: headers,
+        body: Box::new(MemReader::new(data)),
+    })
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use curl::http;
+use s3::{self, Bucket};
+
+use Config;
+use storage::Storage;
+use util::{CargoResult, ChainError, internal};
+
+/// Storage backed by an S3 bucket.
+pub struct S3 {
+    bucket: Bucket,
+    proxy: Option<String>,
+    part_size: usize,
+    url_expiry: Option<Duration>,
+}
+
+impl S3 {
+    pub fn new(config: &Config) -> S3 {
+        if config.s3_part_size < s3::MIN_PART_SIZE {
+            panic!("the S3 part size has to be at least {} bytes, not {}",
+                   s3::MIN_PART_SIZE, config.s3_part_size)
+        }
+        let mut bucket = Bucket::new(config.s3_bucket.clone(),
+                                     config.s3_region.clone(),
+                                     config.s3_access_key.clone(),
+                                     config.s3_secret_key.clone(),
+                                     if config.env == ::Env::Test {"http"} else {"https"});
+        bucket.set_signature(config.s3_signature);
+        bucket.set_addressing(config.s3_addressing);
+        match config.s3_endpoint {
+            Some(ref endpoint) => bucket.set_endpoint(endpoint.as_slice()),
+            None => {}
+        }
+        S3 {
+            bucket: bucket,
+            proxy: config.s3_proxy.clone(),
+            part_size: config.s3_part_size,
+            url_expiry: config.s3_url_expiry,
+        }
+    }
+
+    fn handle(&self) -> http::Handle {
+        let handle = http::handle();
+        match self.proxy {
+            Some(ref proxy) => handle.proxy(proxy.as_slice()),
+            None => handle,
+        }
+    }
+}
+
+impl Storage for S3 {
+    fn put(&self, path: &str, content: &mut Reader, length: u64,
+           content_type: &str) -> CargoResult<()> {
+        let mut handle = self.handle();
+        let headers = [("Content-Encoding", "gzip")];
+        // Large uploads are split up so a failure partway through only
+        // requires the failed part to be sent again. Anything smaller is read
+        // in first so it can be sent again as a whole.
+        let res = if length > self.part_size as u64 {
+            self.bucket.put_multipart(&mut handle, path, content,
+                                      self.part_size, content_type, &headers)
+        } else {
+            let data = try!(content.read_to_end());
+            self.bucket.put_bytes(&mut handle, path, data.as_slice(),
+                                  content_type, &headers)
+        };
+        res.chain_error(|| {
+            internal(format!("failed to upload to S3: `{}`", path))
+        })
+    }
+
+    fn get(&self, path: &str) -> CargoResult<Vec<u8>> {
+        let mut handle = self.handle();
+        self.bucket.get(&mut handle, path).chain_error(|| {
+            internal(format!("failed to download from S3: `{}`", path))
+        })
+    }
+
+    fn delete(&self, path: &str) -> CargoResult<()> {
+        let mut handle = self.handle();
+        self.bucket.delete(&mut handle, path).chain_error(|| {
+            internal(format!("failed to delete from S3: `{}`", path))
+        })
+    }
+
+    fn exists(&self, path: &str) -> CargoResult<bool> {
+        let mut handle = self.handle();
+        let obj = try!(self.bucket.head(&mut handle, path).chain_error(|| {
+            internal(format!("failed to query S3: `{}`", path))
+        }));
+        Ok(obj.is_some())
+    }
+
+    fn list(&self, prefix: &str) -> CargoResult<Vec<String>> {
+        let mut handle = self.handle();
+        let objects = try!(self.bucket.list_all(&mut handle, prefix)
+                               .chain_error(|| {
+            internal(format!("failed to list S3 objects under `{}`", prefix))
+        }));
+        Ok(objects.into_iter().map(|o| format!("/{}", o.key)).collect())
+    }
+
+    fn url(&self, path: &str) -> String {
+        // A private bucket can only be downloaded from with a signed URL.
+        match self.url_expiry {
+            Some(expiry) => self.bucket.presigned_url(path, expiry),
+            None => self.bucket.url(path),
+        }
+    }
+}
@@ -0,0 +1,463 @@
+//! Inspection of the `.crate` tarballs uploaded by `cargo publish`.
+//!
+//! Tarballs are gzipped tar archives with everything inside a single
+//! `name-version/` directory. Only the handful of tar features which show up
+//! in practice are understood: plain ustar headers along with the GNU and pax
+//! extensions for long paths.
+
+use std::collections::HashMap;
+use std::old_io::{self, BufReader};
+use std::str;
+
+use flate2::reader::GzDecoder;
+use semver;
+use toml;
+
+use dependency::Kind;
+use upload::NewCrate;
+use util::{CargoResult, ChainError, human};
+
+/// The type of an entry in a tar archive.
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum EntryType {
+    File,
+    Directory,
+    Symlink,
+    Hardlink,
+    Device,
+    Fifo,
+    Other(u8),
+}
+
+/// A single entry of a tar archive.
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryType,
+    /// The target of a symlink or hardlink.
+    pub link: String,
+    pub data: Vec<u8>,
+}
+
+/// What the server keeps from a verified tarball.
+pub struct Package {
+    /// The path of the readme named in `Cargo.toml`, relative to the root of
+    /// the package, and its contents.
+    pub readme: Option<(String, String)>,
+    /// The path of the license file named in the metadata, relative to the
+    /// root of the package, and its contents.
+    pub license_file: Option<(String, String)>,
+}
+
+/// Checks that `tarball`, the gzipped `.crate` file uploaded alongside
+/// `krate`, actually contains the package which `krate` describes, and that
+/// nothing in it could harm someone unpacking it.
+///
+/// Tarballs which decompress to more than `max_size` bytes are rejected.
+pub fn verify(krate: &NewCrate, tarball: &[u8],
+              max_size: usize) -> CargoResult<Package> {
+    let prefix = format!("{}-{}", &*krate.name, &*krate.vers);
+    let mut files = HashMap::new();
+    for entry in try!(entries(tarball, max_size)).into_iter() {
+        try!(check_entry(prefix.as_slice(), &entry));
+        if entry.kind == EntryType::File {
+            files.insert(entry.path, entry.data);
+        }
+    }
+    let manifest_path = format!("{}/Cargo.toml", prefix);
+    let manifest = try!(files.get(&manifest_path).chain_error(|| {
+        human(format!("uploaded tarball is missing `{}`", manifest_path))
+    }));
+    let package = try!(verify_manifest(krate, manifest.as_slice()));
+
+    // A readme which didn't make it into the tarball is simply skipped.
+    let readme = package.get("readme").and_then(|s| s.as_str());
+    let readme = readme.and_then(|path| {
+        let path = relative(path);
+        files.get(&format!("{}/{}", prefix, path)).map(|data| {
+            (path.to_string(), text(data.as_slice()))
+        })
+    });
+
+    // The license file is the only statement of the terms a crate without a
+    // license expression is published under, so it has to be there.
+    let license_file = match krate.license_file {
+        Some(ref path) => {
+            let path = relative(path.as_slice());
+            let data = try!(files.get(&format!("{}/{}", prefix, path))
+                                 .chain_error(|| {
+                human(format!("license file `{}` is missing from the \
+                               uploaded tarball", path))
+            }));
+            Some((path.to_string(), text(data.as_slice())))
+        }
+        None => None,
+    };
+    return Ok(Package { readme: readme, license_file: license_file });
+
+    fn relative(path: &str) -> &str {
+        if path.starts_with("./") {&path[2..]} else {path}
+    }
+
+    fn text(data: &[u8]) -> String {
+        String::from_utf8_lossy(data).into_owned()
+    }
+}
+
+/// Decompresses `tarball` and returns all of the entries in it.
+///
+/// The archive is read one entry at a time, and if it turns out to be larger
+/// than `max_size` once decompressed, reading stops at the entry which went
+/// over the limit.
+pub fn entries(tarball: &[u8], max_size: usize) -> CargoResult<Vec<Entry>> {
+    let mut rdr = GzDecoder::new(BufReader::new(tarball));
+    let mut entries = Vec::new();
+    let mut total = 0;
+    let mut long_path = None;
+    let mut long_link = None;
+    loop {
+        let header = try!(read(&mut rdr, 512));
+        if header.iter().all(|b| *b == 0) { break }
+        try!(verify_checksum(header.as_slice()));
+
+        let path = match long_path.take() {
+            Some(path) => path,
+            None => {
+                let name = try!(string(field(&header[..100])));
+                let prefix = if &header[257..262] == b"ustar" {
+                    try!(string(field(&header[345..500])))
+                } else {
+                    String::new()
+                };
+                if prefix.len() == 0 {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                }
+            }
+        };
+
+        let size = try!(octal(&header[124..136]).chain_error(|| {
+            human(format!("tarball entry `{}` has an invalid size", path))
+        }));
+        let padded = (size + 511) / 512 * 512;
+        total += 512 + padded;
+        if total > max_size {
+            return Err(human(format!("tarball entry `{}` takes the uploaded \
+                                      tarball over the limit of {} bytes \
+                                      when decompressed", path, max_size)))
+        }
+        let body = try!(read(&mut rdr, size));
+        try!(read(&mut rdr, padded - size));
+
+        match header[156] {
+            // GNU extensions holding the long path or link name of the entry
+            // which follows.
+            b'L' => {
+                long_path = Some(try!(string(field(body.as_slice()))));
+                continue
+            }
+            b'K' => {
+                long_link = Some(try!(string(field(body.as_slice()))));
+                continue
+            }
+            // pax extended headers, which can do the same.
+            b'x' => {
+                let records = try!(pax_records(body.as_slice()));
+                for (key, value) in records.into_iter() {
+                    match key.as_slice() {
+                        "path" => long_path = Some(value),
+                        "linkpath" => long_link = Some(value),
+                        _ => {}
+                    }
+                }
+                continue
+            }
+            b'g' => continue,
+            _ => {}
+        }
+
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => try!(string(field(&header[157..257]))),
+        };
+        let kind = match header[156] {
+            b'0' | b'\0' | b'7' => EntryType::File,
+            b'1' => EntryType::Hardlink,
+            b'2' => EntryType::Symlink,
+            b'3' | b'4' => EntryType::Device,
+            b'5' => EntryType::Directory,
+            b'6' => EntryType::Fifo,
+            b => EntryType::Other(b),
+        };
+        entries.push(Entry {
+            path: path,
+            kind: kind,
+            link: link,
+            data: body,
+        });
+    }
+    Ok(entries)
+}
+
+fn read<R: Reader>(rdr: &mut R, amt: usize) -> CargoResult<Vec<u8>> {
+    rdr.read_exact(amt).map_err(|e| {
+        if e.kind == old_io::EndOfFile {
+            human("uploaded tarball is truncated")
+        } else {
+            human("uploaded tarball is not a valid gzip file")
+        }
+    })
+}
+
+/// Returns `bytes` up to the first nul byte.
+fn field(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|b| *b == 0) {
+        Some(i) => &bytes[..i],
+        None => bytes,
+    }
+}
+
+fn string(bytes: &[u8]) -> CargoResult<String> {
+    str::from_utf8(bytes).map(|s| s.to_string()).map_err(|_| {
+        human("uploaded tarball contains a path which is not utf-8")
+    })
+}
+
+fn octal(bytes: &[u8]) -> CargoResult<usize> {
+    // GNU tar switches to a binary encoding for sizes too large for octal,
+    // which no crate should ever need.
+    if bytes[0] & 0x80 != 0 {
+        return Err(human("uploaded tarball contains an entry which is too \
+                          large"))
+    }
+    let s = try!(str::from_utf8(field(bytes)).ok().chain_error(|| {
+        human("uploaded tarball has a corrupt header")
+    }));
+    let s = s.trim_matches(' ');
+    if s.len() == 0 { return Ok(0) }
+    ::std::num::from_str_radix(s, 8).ok().chain_error(|| {
+        human("uploaded tarball has a corrupt header")
+    })
+}
+
+fn verify_checksum(header: &[u8]) -> CargoResult<()> {
+    let expected = try!(octal(&header[148..156]));
+    let actual = header.iter().enumerate().fold(0, |sum, (i, b)| {
+        sum + if i >= 148 && i < 156 {b' ' as usize} else {*b as usize}
+    });
+    if expected != actual {
+        return Err(human("uploaded tarball has a corrupt header"))
+    }
+    Ok(())
+}
+
+/// Parses the `len key=value\n` records of a pax extended header.
+fn pax_records(mut body: &[u8]) -> CargoResult<Vec<(String, String)>> {
+    let corrupt = || human("uploaded tarball has a corrupt pax header");
+    let mut ret = Vec::new();
+    while body.len() > 0 && body[0] != 0 {
+        let space = try!(body.iter().position(|b| *b == b' ')
+                             .chain_error(|| corrupt()));
+        let len = try!(str::from_utf8(&body[..space]).ok().and_then(|s| {
+            s.parse::<usize>().ok()
+        }).chain_error(|| corrupt()));
+        if len <= space + 1 || len > body.len() || body[len - 1] != b'\n' {
+            return Err(corrupt())
+        }
+        let record = try!(str::from_utf8(&body[space + 1..len - 1]).ok()
+                              .chain_error(|| corrupt()));
+        let eq = try!(record.find('=').chain_error(|| corrupt()));
+        ret.push((record[..eq].to_string(), record[eq + 1..].to_string()));
+        body = &body[len..];
+    }
+    Ok(ret)
+}
+
+/// Makes sure that unpacking `entry` can only create plain files and
+/// directories, and only inside of the `prefix` directory.
+fn check_entry(prefix: &str, entry: &Entry) -> CargoResult<()> {
+    let path = entry.path.as_slice();
+    if path.starts_with("/") {
+        return Err(human(format!("tarball entry `{}` has an absolute path",
+                                 path)))
+    }
+    if path.split('/').any(|c| c == "..") {
+        return Err(human(format!("tarball entry `{}` has a `..` component in \
+                                  its path", path)))
+    }
+    if !inside(prefix, path.split('/')) {
+        return Err(human(format!("tarball entry `{}` is not inside `{}/`",
+                                 path, prefix)))
+    }
+
+    let escapes = || {
+        human(format!("tarball entry `{}` links to `{}`, which is outside of \
+                       the package", path, entry.link))
+    };
+    // A `..` in a link is resolved through whatever symlinks come before it,
+    // so `d -> .` and then `e -> d/..` would end up outside of the package
+    // even though each looks fine on its own. Links can only point down.
+    let link = entry.link.as_slice();
+    let climbs = link.starts_with("/") || link.split('/').any(|c| c == "..");
+    match entry.kind {
+        EntryType::File | EntryType::Directory => Ok(()),
+        // Symlinks are relative to the directory they're in, while hardlinks
+        // are relative to the root of the archive.
+        EntryType::Symlink => {
+            if climbs { return Err(escapes()) }
+            Ok(())
+        }
+        EntryType::Hardlink => {
+            if climbs || !inside(prefix, link.split('/')) {
+                return Err(escapes())
+            }
+            Ok(())
+        }
+        EntryType::Device | EntryType::Fifo => {
+            Err(human(format!("tarball entry `{}` is a special file", path)))
+        }
+        EntryType::Other(b) => {
+            Err(human(format!("tarball entry `{}` has an unsupported type \
+                               `{}`", path, b as char)))
+        }
+    }
+}
+
+/// Tests whether the path made up of `components` stays within the `prefix`
+/// directory, taking any `..` components into account.
+fn inside<'a, I: Iterator<Item=&'a str>>(prefix: &str, components: I) -> bool {
+    let mut depth = 0;
+    for (i, component) in components.enumerate() {
+        match component {
+            c if i == 0 => {
+                if c != prefix { return false }
+                depth = 1;
+            }
+            "" | "." => {}
+            ".." => { depth -= 1; if depth == 0 { return false } }
+            _ => depth += 1,
+        }
+    }
+    depth > 0
+}
+
+/// Checks that the name, version and dependencies listed in `Cargo.toml` are
+/// the same as those in the upload's metadata, returning its `[package]`
+/// section.
+fn verify_manifest(krate: &NewCrate,
+                   manifest: &[u8]) -> CargoResult<toml::Table> {
+    let manifest = try!(str::from_utf8(manifest).ok().chain_error(|| {
+        human("`Cargo.toml` in the uploaded tarball is not valid utf-8")
+    }));
+    let mut parser = toml::Parser::new(manifest);
+    let toml = match parser.parse() {
+        Some(toml) => toml,
+        None => {
+            return Err(human(format!("failed to parse `Cargo.toml` in the \
+                                      uploaded tarball: {}",
+                                     parser.errors[0].desc)))
+        }
+    };
+
+    let package = try!(toml.get("package").or(toml.get("project"))
+                           .and_then(|p| p.as_table()).chain_error(|| {
+        human("`Cargo.toml` in the uploaded tarball has no `[package]` section")
+    }));
+    let name = package.get("name").and_then(|s| s.as_str()).unwrap_or("");
+    if name != &*krate.name {
+        return Err(human(format!("`Cargo.toml` is for the crate `{}`, but \
+                                  the upload is for `{}`", name, &*krate.name)))
+    }
+    let version = package.get("version").and_then(|s| s.as_str())
+                         .unwrap_or("");
+    if semver::Version::parse(version).ok().as_ref() != Some(&*krate.vers) {
+        return Err(human(format!("`Cargo.toml` is for version `{}`, but the \
+                                  upload is for `{}`", version, &*krate.vers)))
+    }
+
+    let mut declared = HashMap::new();
+    try!(declared_deps(&toml, None, &mut declared));
+    match toml.get("target").and_then(|t| t.as_table()) {
+        Some(targets) => {
+            for (target, table) in targets.iter() {
+                match table.as_table() {
+                    Some(table) => {
+                        try!(declared_deps(table, Some(target.as_slice()),
+                                           &mut declared));
+                    }
+                    None => {}
+                }
+            }
+        }
+        None => {}
+    }
+
+    for dep in krate.deps.iter() {
+        let section = match dep.kind.unwrap_or(Kind::Normal) {
+            Kind::Normal => "dependencies",
+            Kind::Build => "build-dependencies",
+            Kind::Dev => "dev-dependencies",
+        };
+        let key = (dep.name.to_string(), section, dep.target.clone());
+        let req = match declared.remove(&key) {
+            Some(req) => req,
+            None => {
+                return Err(human(format!("dependency `{}` is not in the \
+                                          `[{}]` of `Cargo.toml`",
+                                         &*dep.name, section)))
+            }
+        };
+        // Path and git dependencies aren't required to list a version.
+        let req = match req { Some(req) => req, None => continue };
+        let parsed = try!(semver::VersionReq::parse(req.as_slice()).ok()
+                                 .chain_error(|| {
+            human(format!("invalid version requirement `{}` for dependency \
+                           `{}` in `Cargo.toml`", req, &*dep.name))
+        }));
+        if parsed.to_string() != dep.version_req.to_string() {
+            return Err(human(format!("dependency `{}` requires `{}` in \
+                                      `Cargo.toml`, but `{}` in the upload",
+                                     &*dep.name, req, *dep.version_req)))
+        }
+    }
+    match declared.into_iter().next() {
+        Some(((name, section, _), _)) => {
+            Err(human(format!("dependency `{}` in the `[{}]` of `Cargo.toml` \
+                               is missing from the upload", name, section)))
+        }
+        None => Ok(package.clone()),
+    }
+}
+
+type DeclaredDeps = HashMap<(String, &'static str, Option<String>),
+                            Option<String>>;
+
+/// Collects the dependencies declared in `table`, along with the version
+/// requirement of each if it has one.
+fn declared_deps(table: &toml::Table, target: Option<&str>,
+                 deps: &mut DeclaredDeps) -> CargoResult<()> {
+    let sections = ["dependencies", "dev-dependencies", "build-dependencies"];
+    for section in sections.iter() {
+        let list = match table.get(*section).and_then(|t| t.as_table()) {
+            Some(list) => list,
+            None => continue,
+        };
+        for (name, dep) in list.iter() {
+            let req = match *dep {
+                toml::Value::String(ref s) => Some(s.clone()),
+                toml::Value::Table(ref t) => {
+                    t.get("version").and_then(|v| v.as_str())
+                     .map(|s| s.to_string())
+                }
+                _ => {
+                    return Err(human(format!("invalid specification for \
+                                              dependency `{}` in \
+                                              `Cargo.toml`", name)))
+                }
+            };
+            let target = target.map(|s| s.to_string());
+            deps.insert((name.clone(), *section, target), req);
+        }
+    }
+    Ok(())
+}
@@ -5,12 +5,16 @@ extern crate "cargo-registry" as cargo_registry;
 extern crate "conduit-middleware" as conduit_middleware;
 extern crate "conduit-test" as conduit_test;
 extern crate "rustc-serialize" as rustc_serialize;
+extern crate civet;
 extern crate conduit;
 extern crate curl;
+extern crate flate2;
 extern crate git2;
+extern crate openssl;
 extern crate time;
 extern crate url;
 extern crate semver;
+extern crate s3;
 
 use std::collections::HashMap;
 use std::error::Error as StdError;
@@ -22,10 +26,12 @@ use rustc_serialize::json::{self, Json};
 
 use conduit::Request;
 use conduit_test::MockRequest;
-use cargo_registry::app::App;
+use cargo_registry::app::{App, RequestApp};
 use cargo_registry::db::{self, RequestTransaction};
 use cargo_registry::dependency::Kind;
+use cargo_registry::jobs;
 use cargo_registry::{User, Crate, Version, Keyword, Dependency};
+use cargo_registry::user::{identity, ApiToken, Profile, Scope};
 
 macro_rules! t{ ($e:expr) => (
     match $e {
@@ -60,44 +66,61 @@ struct Bad { errors: Vec<Error> }
 mod middleware;
 mod keyword;
 mod krate;
+mod fake_s3;
 mod user;
-mod record;
 mod git;
+mod jobs;
+mod render;
+mod spdx;
+mod upload_pack;
 mod version;
 
-fn app() -> (record::Bomb, Arc<App>, conduit_middleware::MiddlewareBuilder) {
-    struct NoCommit;
-    static INIT: Once = ONCE_INIT;
-    git::init();
+fn app() -> (Arc<App>, conduit_middleware::MiddlewareBuilder) {
+    app_with(config())
+}
 
-    let (proxy, bomb) = record::proxy();
-    let config = cargo_registry::Config {
+fn config() -> cargo_registry::Config {
+    cargo_registry::Config {
         s3_bucket: env::var_string("S3_BUCKET").unwrap_or(String::new()),
         s3_access_key: env::var_string("S3_ACCESS_KEY").unwrap_or(String::new()),
         s3_secret_key: env::var_string("S3_SECRET_KEY").unwrap_or(String::new()),
         s3_region: env::var_string("S3_REGION").ok(),
-        s3_proxy: Some(proxy),
+        s3_proxy: None,
+        s3_signature: s3::Signature::V2,
+        s3_endpoint: None,
+        s3_addressing: s3::Addressing::VirtualHost,
         session_key: "test".to_string(),
         git_repo_checkout: git::checkout(),
+        git_signing_key: None,
+        api_url: "http://localhost:8888".to_string(),
+        dl_url: "http://localhost:8888/api/v1/crates".to_string(),
+        index_auth_required: false,
         gh_client_id: "".to_string(),
         gh_client_secret: "".to_string(),
+        oidc: None,
+        local_accounts: true,
         db_url: env("TEST_DATABASE_URL"),
         env: cargo_registry::Env::Test,
         max_upload_size: 1000,
-    };
+        max_unpack_size: 100_000,
+        s3_part_size: 5 * 1024 * 1024,
+        s3_url_expiry: None,
+        storage: cargo_registry::storage::Backend::Local(git::storage()),
+    }
+}
+
+fn app_with(config: cargo_registry::Config)
+            -> (Arc<App>, conduit_middleware::MiddlewareBuilder) {
+    struct NoCommit;
+    static INIT: Once = ONCE_INIT;
+    git::init();
+
     INIT.call_once(|| db_setup(config.db_url.as_slice()));
     let app = App::new(&config);
     let app = Arc::new(app);
     let mut middleware = cargo_registry::middleware(app.clone());
     middleware.add(NoCommit);
-    return (bomb, app, middleware);
-
-    fn env(s: &str) -> String {
-        match env::var_string(s).ok() {
-            Some(s) => s,
-            None => panic!("must have `{}` defined", s),
-        }
-    }
+    return (app, middleware);
 
     fn db_setup(db: &str) {
         let migrate = env::current_exe().unwrap().join("../migrate");
@@ -119,6 +142,13 @@ fn app() -> (record::Bomb, Arc<App>, conduit_middleware::MiddlewareBuilder) {
     }
 }
 
+fn env(s: &str) -> String {
+    match env::var_string(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
+
 fn req(app: Arc<App>, method: conduit::Method, path: &str) -> MockRequest {
     let mut req = MockRequest::new(method, path);
     req.mut_extensions().insert(db::Transaction::new(app));
@@ -173,12 +203,10 @@ fn json<T: rustc_serialize::Decodable>(r: &mut conduit::Response) -> T {
 fn user(login: &str) -> User {
     User {
         id: 10000,
-        gh_login: login.to_string(),
+        login: login.to_string(),
         email: None,
         name: None,
         avatar: None,
-        gh_access_token: User::new_api_token(), // just randomize it
-        api_token: User::new_api_token(),
     }
 }
 
@@ -202,17 +230,26 @@ fn krate(name: &str) -> Crate {
 }
 
 fn mock_user(req: &mut Request, u: User) -> User {
-    let u = User::find_or_insert(req.tx().unwrap(),
-                                 u.gh_login.as_slice(),
-                                 u.email.as_ref().map(|s| s.as_slice()),
-                                 u.name.as_ref().map(|s| s.as_slice()),
-                                 u.avatar.as_ref().map(|s| s.as_slice()),
-                                 u.gh_access_token.as_slice(),
-                                 u.api_token.as_slice()).unwrap();
+    let profile = Profile {
+        email: u.email.clone(),
+        name: u.name.clone(),
+        avatar: u.avatar.clone(),
+        .. Profile::new(u.login.as_slice())
+    };
+    let u = User::find_or_insert(req.tx().unwrap(), identity::GITHUB,
+                                 &profile).unwrap();
     req.mut_extensions().insert(u.clone());
     return u;
 }
 
+// Gives the mocked user a new API token, returning the token itself.
+fn mock_token(req: &mut Request, name: &str, scopes: &[Scope],
+              crate_pattern: Option<&str>) -> String {
+    let user = req.extensions().find::<User>().unwrap().clone();
+    ApiToken::insert(req.tx().unwrap(), user.id, name, scopes, crate_pattern,
+                     None).unwrap().1
+}
+
 fn mock_crate(req: &mut Request, krate: Crate) -> (Crate, Version) {
     mock_crate_vers(req, krate, &semver::Version::parse("1.0.0").unwrap())
 }
@@ -230,7 +267,7 @@ fn mock_crate_vers(req: &mut Request, krate: Crate, v: &semver::Version)
                                       &None).unwrap();
     Keyword::update_crate(req.tx().unwrap(), &krate,
                           krate.keywords.as_slice()).unwrap();
-    let v = krate.add_version(req.tx().unwrap(), v, &HashMap::new(), &[]);
+    let v = krate.add_version(req.tx().unwrap(), v, &HashMap::new(), &[], None);
     (krate, v.unwrap())
 }
 
@@ -249,6 +286,45 @@ fn mock_keyword(req: &mut Request, name: &str) -> Keyword {
     Keyword::find_or_insert(req.tx().unwrap(), name).unwrap()
 }
 
+struct QueuedJob {
+    job: String,
+    attempts: i32,
+    last_error: Option<String>,
+    dead: bool,
+}
+
+fn queued_jobs(req: &mut Request) -> Vec<QueuedJob> {
+    let tx = req.tx().unwrap();
+    let stmt = tx.prepare("SELECT * FROM jobs ORDER BY id ASC").unwrap();
+    let rows = stmt.query(&[]).unwrap();
+    rows.map(|row| {
+        QueuedJob {
+            job: row.get("job"),
+            attempts: row.get("attempts"),
+            last_error: row.get("last_error"),
+            dead: row.get("dead"),
+        }
+    }).collect()
+}
+
+// Runs the jobs which are due, as the worker would.
+fn run_jobs(req: &mut Request) {
+    let app = req.app().clone();
+    let repo = app.git_repo.lock().unwrap();
+    jobs::run_pending(req.tx().unwrap(), &*repo, &*app.storage).unwrap();
+}
+
+// Makes every queued job due straight away, and runs them.
+fn run_jobs_now(req: &mut Request) {
+    {
+        let tx = req.tx().unwrap();
+        tx.execute("UPDATE jobs SET run_at = $1",
+                   &[&cargo_registry::now()]).unwrap();
+    }
+    run_jobs(req);
+}
+
 fn logout(req: &mut Request) {
     req.mut_extensions().pop::<User>();
+    req.mut_extensions().pop::<ApiToken>();
 }
@@ -0,0 +1,196 @@
+//! Just enough of an imitation of S3 to test uploads without talking to
+//! Amazon. Objects are kept in memory and every request is logged so tests
+//! can check how the registry talked to the server.
+
+use std::ascii::AsciiExt;
+use std::collections::{HashMap, BTreeMap};
+use std::old_io::net::tcp::{TcpListener, TcpAcceptor, TcpStream};
+use std::old_io::{Listener, Acceptor, BufferedStream};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+use openssl::crypto::hash;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::hex::ToHex;
+
+pub struct State {
+    /// Stored objects, keyed by their full path including the bucket.
+    pub objects: HashMap<String, Vec<u8>>,
+    /// The parts received so far for each multipart upload in progress.
+    pub uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+    /// The method and path of every request received, in order.
+    pub requests: Vec<String>,
+    /// How many more times an upload of each part number should fail, with 0
+    /// standing for uploads in a single request.
+    pub failures: HashMap<u32, u32>,
+    next_upload: u32,
+}
+
+// Shuts down the server when dropped, failing the test if the server thread
+// failed.
+pub struct Server {
+    pub url: String,
+    pub state: Arc<Mutex<State>>,
+    accept: TcpAcceptor,
+    rx: Receiver<()>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.accept.close_accept().unwrap();
+        if self.rx.recv().is_err() && !Thread::panicking() {
+            panic!("fake S3 server failed")
+        }
+    }
+}
+
+pub fn server() -> Server {
+    let mut l = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", l.socket_name().unwrap());
+    let mut a = l.listen().unwrap();
+    let a2 = a.clone();
+    let (tx, rx) = channel();
+    let state = Arc::new(Mutex::new(State {
+        objects: HashMap::new(),
+        uploads: HashMap::new(),
+        requests: Vec::new(),
+        failures: HashMap::new(),
+        next_upload: 0,
+    }));
+    let state2 = state.clone();
+
+    Thread::spawn(move|| {
+        for socket in a.incoming() {
+            let socket = match socket { Ok(s) => s, Err(..) => break };
+            handle(BufferedStream::new(socket), &mut *state2.lock().unwrap());
+        }
+        tx.send(()).unwrap();
+    });
+
+    Server { url: url, state: state, accept: a2, rx: rx }
+}
+
+fn handle(mut socket: BufferedStream<TcpStream>, state: &mut State) {
+    let line = socket.read_line().unwrap();
+    let mut parts = line.as_slice().trim().split(' ');
+    let method = parts.next().unwrap().to_string();
+    let target = parts.next().unwrap().to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let line = socket.read_line().unwrap();
+        let line = line.as_slice().trim();
+        if line.len() == 0 { break }
+        let mut parts = line.splitn(1, ':');
+        headers.insert(parts.next().unwrap().to_ascii_lowercase(),
+                       parts.next().unwrap().trim().to_string());
+    }
+    if headers.get("expect").map(|s| s.as_slice()) == Some("100-continue") {
+        socket.write_str("HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+        socket.flush().unwrap();
+    }
+    let len = headers.get("content-length").map(|s| s.parse().unwrap())
+                     .unwrap_or(0);
+    let body = socket.read_exact(len).unwrap();
+    state.requests.push(format!("{} {}", method, target));
+
+    let mut parts = target.as_slice().splitn(1, '?');
+    let path = parts.next().unwrap().to_string();
+    let query = parts.next().unwrap_or("").split('&').map(|pair| {
+        let mut parts = pair.splitn(1, '=');
+        (parts.next().unwrap().to_string(),
+         parts.next().unwrap_or("").to_string())
+    }).collect::<HashMap<_, _>>();
+
+    let (status, extra, body) = match (method.as_slice(),
+                                       query.get("uploadId")) {
+        ("POST", None) if query.contains_key("uploads") => {
+            state.next_upload += 1;
+            let id = format!("upload-{}", state.next_upload);
+            state.uploads.insert(id.clone(), BTreeMap::new());
+            (200, Vec::new(),
+             format!("<InitiateMultipartUploadResult>\
+                        <UploadId>{}</UploadId>\
+                      </InitiateMultipartUploadResult>", id).into_bytes())
+        }
+        ("PUT", Some(id)) => {
+            let number = query["partNumber"].parse().unwrap();
+            let md5 = hash::hash(hash::Type::MD5, body.as_slice());
+            let failures = state.failures.get(&number).map(|n| *n)
+                                .unwrap_or(0);
+            if failures > 0 {
+                state.failures.insert(number, failures - 1);
+                error(500, "InternalError")
+            } else if headers["content-md5"] != md5.to_base64(STANDARD) {
+                error(400, "BadDigest")
+            } else {
+                state.uploads.get_mut(id).unwrap().insert(number, body);
+                let etag = format!("\"{}\"", md5.to_hex());
+                (200, vec![("ETag", etag)], Vec::new())
+            }
+        }
+        ("POST", Some(id)) => {
+            let parts = state.uploads.remove(id).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            assert_eq!(body.as_slice().split_str("<PartNumber>").count(),
+                       parts.len() + 1);
+            let object = parts.into_iter().flat_map(|(_, data)| {
+                data.into_iter()
+            }).collect();
+            state.objects.insert(path, object);
+            (200, Vec::new(),
+             b"<CompleteMultipartUploadResult/>".to_vec())
+        }
+        ("DELETE", Some(id)) => {
+            state.uploads.remove(id);
+            (204, Vec::new(), Vec::new())
+        }
+        ("PUT", None) => {
+            let failures = state.failures.get(&0).map(|n| *n).unwrap_or(0);
+            if failures > 0 {
+                state.failures.insert(0, failures - 1);
+                error(500, "InternalError")
+            } else {
+                state.objects.insert(path, body);
+                (200, Vec::new(), Vec::new())
+            }
+        }
+        ("GET", None) | ("HEAD", None) => {
+            match state.objects.get(&path) {
+                Some(data) if method.as_slice() == "GET" => {
+                    (200, Vec::new(), data.clone())
+                }
+                Some(data) => {
+                    (200, vec![("Content-Length", data.len().to_string())],
+                     Vec::new())
+                }
+                None => error(404, "NoSuchKey"),
+            }
+        }
+        ("DELETE", None) => {
+            state.objects.remove(&path);
+            (204, Vec::new(), Vec::new())
+        }
+        _ => panic!("unexpected request: {} {}", method, target),
+    };
+
+    // Closing every connection keeps the parsing above simple.
+    (write!(socket, "HTTP/1.1 {} Fake\r\nConnection: close\r\n", status))
+        .unwrap();
+    if method.as_slice() != "HEAD" {
+        (write!(socket, "Content-Length: {}\r\n", body.len())).unwrap();
+    }
+    for &(k, ref v) in extra.iter() {
+        (write!(socket, "{}: {}\r\n", k, v)).unwrap();
+    }
+    socket.write_str("\r\n").unwrap();
+    socket.write_all(body.as_slice()).unwrap();
+    socket.flush().unwrap();
+
+    fn error(status: u32, code: &str)
+             -> (u32, Vec<(&'static str, String)>, Vec<u8>) {
+        (status, Vec::new(),
+         format!("<Error><Code>{}</Code><Message>fake</Message></Error>",
+                 code).into_bytes())
+    }
+}
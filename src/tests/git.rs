@@ -1,20 +1,32 @@
-use std::old_io::fs;
+use std::collections::BTreeMap;
+use std::old_io::fs::PathExtensions;
+use std::old_io::{self, fs, Command, File};
 use std::env;
+use std::str;
 use std::thread::Thread;
 
+use conduit::Handler;
 use git2;
+use rustc_serialize::json;
 use url::Url;
 
-fn root() -> Path {
+use cargo_registry::git::{apply, replace_files, squash, sync_config};
+use cargo_registry::git::{Change, SigningKey};
+
+pub fn root() -> Path {
     env::current_dir().unwrap().join("tmp").join(Thread::current().name().unwrap())
 }
 
 pub fn checkout() -> Path { root().join("checkout") }
 pub fn bare() -> Path { root().join("bare") }
+pub fn storage() -> Path { root().join("storage") }
+pub fn clone() -> Path { root().join("clone") }
 
 pub fn init() {
     let _ = fs::rmdir_recursive(&checkout());
     let _ = fs::rmdir_recursive(&bare());
+    let _ = fs::rmdir_recursive(&storage());
+    let _ = fs::rmdir_recursive(&clone());
     // Prepare a bare remote repo
     {
         let bare = git2::Repository::init_bare(&bare()).unwrap();
@@ -59,3 +71,294 @@ pub fn init() {
     branch.set_upstream(Some("origin/master")).unwrap();
 
 }
+
+#[test]
+fn git_replace_files() {
+    let (app, middle) = ::app();
+    let mut req = ::krate::new_req(app.clone(), "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+    assert!(::git::checkout().join("3/f/foo").exists());
+
+    let files = vec![("3/f/foo".to_string(), None),
+                     ("3/b/bar".to_string(), Some("bar\n".to_string()))];
+    {
+        let repo = app.git_repo.lock().unwrap();
+        t!(replace_files(&*repo, "rebuild", files.as_slice()));
+    }
+    assert!(!::git::checkout().join("3/f/foo").exists());
+    assert_eq!(File::open(&::git::checkout().join("3/b/bar"))
+                    .read_to_string().unwrap(), "bar\n");
+
+    // The commit made it to the remote, too.
+    let checkout = git2::Repository::open(&::git::checkout()).unwrap();
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let local = checkout.head().unwrap().target().unwrap();
+    let remote = bare.head().unwrap().target().unwrap();
+    assert_eq!(local, remote);
+    let commit = bare.find_commit(remote).unwrap();
+    assert_eq!(commit.message(), Some("rebuild"));
+}
+
+#[test]
+fn git_sync_config() {
+    let (app, _middle) = ::app();
+    let mut config = ::config();
+    let path = ::git::checkout().join("config.json");
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let repo = app.git_repo.lock().unwrap();
+
+    t!(sync_config(&*repo, &config));
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert_eq!(json::decode::<BTreeMap<String, String>>(contents.as_slice())
+                   .unwrap(),
+               vec![("api".to_string(), "http://localhost:8888".to_string()),
+                    ("dl".to_string(),
+                     "http://localhost:8888/api/v1/crates".to_string())]
+                   .into_iter().collect());
+    let head = bare.head().unwrap().target().unwrap();
+    let commit = bare.find_commit(head).unwrap();
+    assert_eq!(commit.message(), Some("Updating config.json"));
+
+    // Nothing is committed while it's up to date.
+    t!(sync_config(&*repo, &config));
+    assert_eq!(bare.head().unwrap().target().unwrap(), head);
+
+    config.dl_url = "https://dl.example.com/crates".to_string();
+    config.index_auth_required = true;
+    t!(sync_config(&*repo, &config));
+    assert!(bare.head().unwrap().target().unwrap() != head);
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert!(contents.as_slice().contains("https://dl.example.com/crates"));
+    assert!(contents.as_slice().contains("\"auth-required\": true"));
+
+    // It's the index which is compared against, not a checkout which is
+    // behind it.
+    let changed = bare.head().unwrap().target().unwrap();
+    let obj = repo.find_object(head, None).unwrap();
+    repo.reset(&obj, git2::ResetType::Hard, None, None, None).unwrap();
+    t!(sync_config(&*repo, &::config()));
+    let latest = bare.head().unwrap().target().unwrap();
+    assert_eq!(bare.find_commit(latest).unwrap().parent_id(0), Some(changed));
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert!(!contents.as_slice().contains("https://dl.example.com/crates"));
+}
+
+#[test]
+fn git_squash() {
+    let (app, middle) = ::app();
+    let mut req = ::krate::new_req(app.clone(), "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    let body = ::krate::new_req_body(::krate("foo"), "1.0.1", Vec::new());
+    req.with_body(body.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let old = bare.head().unwrap().target().unwrap();
+    {
+        let repo = app.git_repo.lock().unwrap();
+        t!(squash(&*repo, "archive/test"));
+    }
+
+    // The remote has a single commit with the same files, and the old history
+    // is on the archive branch.
+    let head = bare.head().unwrap().target().unwrap();
+    let commit = bare.find_commit(head).unwrap();
+    assert_eq!(commit.parent_count(), 0);
+    assert_eq!(commit.tree_id(), bare.find_commit(old).unwrap().tree_id());
+    let archive = bare.find_reference("refs/heads/archive/test").unwrap();
+    assert_eq!(archive.target(), Some(old));
+
+    // Publishing carries on from the squashed commit.
+    let body = ::krate::new_req_body(::krate("foo"), "1.0.2", Vec::new());
+    req.with_body(body.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+    let commit = bare.find_commit(bare.head().unwrap().target().unwrap())
+                     .unwrap();
+    assert_eq!(commit.parent_id(0), Some(head));
+    let path = ::git::checkout().join("3/f/foo");
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert_eq!(contents.as_slice().lines().count(), 3);
+}
+
+#[test]
+fn git_apply_batch() {
+    let (app, middle) = ::app();
+    let mut req = ::krate::new_req(app.clone(), "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    let body = ::krate::new_req_body(::krate("foo"), "1.0.1", Vec::new());
+    req.with_body(body.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let before = bare.head().unwrap().target().unwrap();
+    let changes = vec![
+        Change::Yank("foo".to_string(), "1.0.0".to_string(), true),
+        Change::Yank("foo".to_string(), "2.0.0".to_string(), true),
+        Change::Yank("foo".to_string(), "1.0.1".to_string(), true),
+    ];
+    let results = {
+        let repo = app.git_repo.lock().unwrap();
+        t!(apply(&*repo, changes.as_slice()))
+    };
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+    assert!(results[2].is_ok());
+
+    // Both versions which are there were yanked in one commit.
+    let path = ::git::checkout().join("3/f/foo");
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert!(!contents.as_slice().contains("\"yanked\":false"));
+    let commit = bare.find_commit(bare.head().unwrap().target().unwrap())
+                     .unwrap();
+    assert_eq!(commit.parent_id(0), Some(before));
+    assert_eq!(commit.message(), Some("Updating 2 crate versions\n\n\
+                                       Yanking crate `foo#1.0.0`\n\
+                                       Yanking crate `foo#1.0.1`"));
+}
+
+#[test]
+fn git_signed_commits() {
+    let (app, middle) = ::app();
+    let key = ::git::checkout().dir_path().join("signing-key");
+    assert!(Command::new("ssh-keygen").arg("-q").arg("-t").arg("ed25519")
+                    .arg("-N").arg("").arg("-f").arg(&key)
+                    .status().unwrap().success());
+    sign_with(SigningKey::Ssh(key.clone()));
+    let mut req = ::krate::new_req(app.clone(), "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let head = bare.head().unwrap().target().unwrap();
+    let commit = bare.find_commit(head).unwrap();
+    assert_eq!(commit.message(), Some("Updating crate `foo#1.0.0`"));
+    let checkout = git2::Repository::open(&::git::checkout()).unwrap();
+    assert_eq!(checkout.head().unwrap().target(), Some(head));
+
+    let (payload, signature) = commit_signature(&bare, head);
+    assert!(signature.starts_with("-----BEGIN SSH SIGNATURE-----\n"));
+
+    // The signature is of everything else in the commit.
+    let sig_path = ::git::checkout().dir_path().join("commit.sig");
+    File::create(&sig_path).write_str(signature.as_slice()).unwrap();
+    let mut verify = Command::new("ssh-keygen").arg("-Y").arg("check-novalidate")
+                                              .arg("-n").arg("git")
+                                              .arg("-s").arg(&sig_path)
+                                              .spawn().unwrap();
+    verify.stdin.take().unwrap().write_str(payload.as_slice()).unwrap();
+    assert!(verify.wait().unwrap().success());
+
+    // Nothing used to sign it is left behind in the checkout.
+    assert_eq!(fs::readdir(&::git::checkout().join(".git")).unwrap().iter()
+                 .filter(|p| p.filename_str().unwrap().starts_with("signing"))
+                 .count(), 0);
+}
+
+#[test]
+fn git_gpg_signed_commits() {
+    let (app, middle) = ::app();
+    let home = ::git::checkout().dir_path().join("gnupg");
+    t!(fs::mkdir(&home, old_io::USER_RWX));
+    gen_gpg_key(&home, "index@example.com");
+    gen_gpg_key(&home, "other@example.com");
+    sign_with(SigningKey::Gpg {
+        key: "index@example.com".to_string(),
+        home: Some(home.clone()),
+    });
+    let mut req = ::krate::new_req(app.clone(), "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let head = bare.head().unwrap().target().unwrap();
+    let (payload, signature) = commit_signature(&bare, head);
+    assert!(signature.starts_with("-----BEGIN PGP SIGNATURE-----\n"));
+
+    // It was signed by the configured key, and no other in the keyring.
+    let sig_path = ::git::checkout().dir_path().join("commit.asc");
+    File::create(&sig_path).write_str(signature.as_slice()).unwrap();
+    let mut verify = Command::new("gpg");
+    verify.env("GNUPGHOME", &home).arg("--batch").arg("--status-fd").arg("1")
+          .arg("--verify").arg(&sig_path).arg("-");
+    let mut verify = verify.spawn().unwrap();
+    verify.stdin.take().unwrap().write_str(payload.as_slice()).unwrap();
+    let output = verify.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let status = String::from_utf8(output.output).unwrap();
+    let fingerprint = gpg_fingerprint(&home, "index@example.com");
+    assert!(status.as_slice().lines().any(|line| {
+        line.starts_with("[GNUPG:] VALIDSIG ") &&
+            line.ends_with(format!(" {}", fingerprint).as_slice())
+    }), "{}", status);
+
+    // A key which could be more than one of them isn't used at all.
+    gen_gpg_key(&home, "index@example.org");
+    let mut config = ::config();
+    config.git_signing_key = Some(SigningKey::Gpg {
+        key: "index@example".to_string(),
+        home: Some(home.clone()),
+    });
+    assert!(::cargo_registry::git::checkout("", &config).is_err());
+}
+
+// Sets up the test's checkout to sign its commits with `key`, just as the
+// server and worker do.
+fn sign_with(key: SigningKey) {
+    let mut config = ::config();
+    config.git_signing_key = Some(key);
+    t!(::cargo_registry::git::checkout("", &config));
+}
+
+fn gen_gpg_key(home: &Path, uid: &str) {
+    assert!(Command::new("gpg").env("GNUPGHOME", home)
+                    .arg("--batch").arg("--passphrase").arg("")
+                    .arg("--quick-gen-key").arg(uid)
+                    .arg("ed25519").arg("sign").arg("never")
+                    .status().unwrap().success());
+}
+
+fn gpg_fingerprint(home: &Path, uid: &str) -> String {
+    let output = Command::new("gpg").env("GNUPGHOME", home)
+                         .arg("--batch").arg("--with-colons")
+                         .arg("--list-secret-keys").arg(uid)
+                         .output().unwrap();
+    let output = String::from_utf8(output.output).unwrap();
+    let line = output.as_slice().lines().find(|l| l.starts_with("fpr:"))
+                     .unwrap();
+    line.split(':').nth(9).unwrap().to_string()
+}
+
+// Pulls the signature back out of the raw commit `id`, returning what it's
+// a signature of along with it.
+fn commit_signature(repo: &git2::Repository,
+                    id: git2::Oid) -> (String, String) {
+    let odb = repo.odb().unwrap();
+    let object = odb.read(id).unwrap();
+    let raw = str::from_utf8(object.data()).unwrap();
+    let (mut payload, mut signature) = (String::new(), String::new());
+    let mut lines = raw.split('\n');
+    for line in lines.by_ref() {
+        if line.starts_with("gpgsig ") {
+            signature.push_str(&line["gpgsig ".len()..]);
+            signature.push('\n');
+        } else if line.starts_with(" ") {
+            signature.push_str(&line[1..]);
+            signature.push('\n');
+        } else {
+            payload.push_str(line);
+            payload.push('\n');
+            if line.len() == 0 { break }
+        }
+    }
+    payload.push_str(lines.collect::<Vec<_>>().connect("\n").as_slice());
+    (payload, signature)
+}
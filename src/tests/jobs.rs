@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::old_io::File;
+use std::time::Duration;
+
+use conduit::{Handler, Method};
+use conduit_test::MockRequest;
+use git2;
+
+use cargo_registry::{git, Crate, Version};
+use cargo_registry::app::RequestApp;
+use cargo_registry::db::RequestTransaction;
+use cargo_registry::jobs::{self, Job, BATCH_SIZE, MAX_ATTEMPTS};
+
+#[test]
+fn failed_jobs_are_retried_until_dead() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Delete, "/api/v1/crates/foo/1.0.0/yank");
+    ::mock_user(&mut req, ::user("foo"));
+    // The crate is only in the database, so it can't be yanked in the index.
+    ::mock_crate(&mut req, ::krate("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let jobs = ::queued_jobs(&mut req);
+    assert_eq!(jobs.len(), 1);
+    assert!(jobs[0].job.as_slice().contains("Yank"));
+    assert_eq!(jobs[0].attempts, 1);
+    assert!(jobs[0].last_error.as_ref().unwrap().as_slice()
+                   .contains("foo"));
+    assert!(!jobs[0].dead);
+
+    for _ in range(1, MAX_ATTEMPTS) {
+        ::run_jobs_now(&mut req);
+    }
+    let jobs = ::queued_jobs(&mut req);
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].attempts, MAX_ATTEMPTS);
+    assert!(jobs[0].dead);
+
+    // Dead jobs aren't run again.
+    ::run_jobs_now(&mut req);
+    assert_eq!(::queued_jobs(&mut req)[0].attempts, MAX_ATTEMPTS);
+}
+
+#[test]
+fn failed_jobs_back_off() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Delete, "/api/v1/crates/foo/1.0.0/yank");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    // Unyanking queues another job, but when the queue is run again the one
+    // which failed isn't due yet so it's left alone.
+    ok_resp!(middle.call(req.with_method(Method::Put)
+                            .with_path("/api/v1/crates/foo/1.0.0/unyank")));
+    ::run_jobs(&mut req);
+    let jobs = ::queued_jobs(&mut req);
+    assert_eq!(jobs.len(), 2);
+    assert_eq!(jobs[0].attempts, 1);
+    assert_eq!(jobs[1].attempts, 1);
+}
+
+fn git_crate(krate: &Crate, version: &Version) -> git::Crate {
+    git::Crate {
+        name: krate.name.clone(),
+        vers: version.num.to_string(),
+        deps: Vec::new(),
+        cksum: "0".to_string(),
+        features: BTreeMap::new(),
+        yanked: Some(false),
+    }
+}
+
+fn find_version(req: &mut MockRequest, krate: &Crate) -> Option<Version> {
+    let num = ::semver::Version::parse("1.0.0").unwrap();
+    t!(Version::find_by_num(req.tx().unwrap(), krate.id, &num))
+}
+
+#[test]
+fn pending_versions_are_published_by_the_index_job() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    let (krate, version) = ::mock_crate(&mut req, ::krate("foo"));
+    t!(version.set_pending(req.tx().unwrap(), true));
+
+    // Until it's in the index the version can't be downloaded, and isn't in
+    // the index served over HTTP either.
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice().contains("not found"),
+            "{:?}", json.errors);
+    let response = t_resp!(middle.call(req.with_path("/index/3/f/foo")));
+    assert_eq!(response.status.0, 404);
+
+    let path = krate.s3_path("1.0.0");
+    let job = Job::AddCrate(path, git_crate(&krate, &version));
+    t!(jobs::enqueue(req.tx().unwrap(), &job, None));
+    ::run_jobs_now(&mut req);
+    assert!(!find_version(&mut req, &krate).unwrap().pending);
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/1.0.0/download")));
+}
+
+#[test]
+fn dead_uploads_remove_the_version() {
+    let (app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    ::mock_user(&mut req, ::user("foo"));
+    let (krate, version) = ::mock_crate(&mut req, ::krate("foo"));
+    t!(version.set_pending(req.tx().unwrap(), true));
+
+    // Without a payload there's nothing to upload, so the job always fails.
+    let path = krate.s3_path("1.0.0");
+    let job = Job::UploadTarball(path, git_crate(&krate, &version));
+    t!(jobs::enqueue(req.tx().unwrap(), &job, None));
+    for _ in range(0, MAX_ATTEMPTS) {
+        assert!(find_version(&mut req, &krate).is_some());
+        ::run_jobs_now(&mut req);
+    }
+    assert!(::queued_jobs(&mut req)[0].dead);
+
+    // That was the crate's only version, so its name is free again.
+    assert!(find_version(&mut req, &krate).is_none());
+    assert!(Crate::find_by_name(req.tx().unwrap(), "foo").is_err());
+}
+
+#[test]
+fn yank_jobs_read_the_database() {
+    let (app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    ::mock_user(&mut req, ::user("foo"));
+    let (krate, version) = ::mock_crate(&mut req, ::krate("foo"));
+    let job = Job::AddCrate(krate.s3_path("1.0.0"),
+                            git_crate(&krate, &version));
+    t!(jobs::enqueue(req.tx().unwrap(), &job, None));
+    ::run_jobs_now(&mut req);
+
+    // Yanking and then unyanking leaves the version unyanked, whichever
+    // order the two jobs run in.
+    let yank = Job::Yank("foo".to_string(), "1.0.0".to_string());
+    t!(version.yank(req.tx().unwrap(), true));
+    t!(jobs::enqueue(req.tx().unwrap(), &yank, None));
+    t!(version.yank(req.tx().unwrap(), false));
+    t!(jobs::enqueue(req.tx().unwrap(), &yank, None));
+    ::run_jobs_now(&mut req);
+    let path = ::git::checkout().join("3/f/foo");
+    assert!(File::open(&path).read_to_string().unwrap().as_slice()
+                             .contains("\"yanked\":false"));
+
+    t!(version.yank(req.tx().unwrap(), true));
+    t!(jobs::enqueue(req.tx().unwrap(), &yank, None));
+    ::run_jobs_now(&mut req);
+    assert!(File::open(&path).read_to_string().unwrap().as_slice()
+                             .contains("\"yanked\":true"));
+}
+
+// Runs the next job or batch with a batching window of `window`, returning
+// whether anything was run.
+fn run_next(req: &mut MockRequest, window: Duration) -> bool {
+    let app = req.app().clone();
+    let repo = app.git_repo.lock().unwrap();
+    t!(jobs::run_next(req.tx().unwrap(), &*repo, &*app.storage, window))
+}
+
+#[test]
+fn index_jobs_are_batched() {
+    let (app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    ::mock_user(&mut req, ::user("foo"));
+    let (krate, version) = ::mock_crate(&mut req, ::krate("foo"));
+    let job = Job::AddCrate(krate.s3_path("1.0.0"),
+                            git_crate(&krate, &version));
+    t!(jobs::enqueue(req.tx().unwrap(), &job, None));
+    ::run_jobs_now(&mut req);
+
+    let bare = git2::Repository::open(&::git::bare()).unwrap();
+    let head = || bare.head().unwrap().target().unwrap();
+    let yank = Job::Yank("foo".to_string(), "1.0.0".to_string());
+    let window = Duration::minutes(5);
+
+    // Until the oldest change has waited for the window, nothing is pushed.
+    let before = head();
+    for _ in range(0, 3) {
+        t!(jobs::enqueue(req.tx().unwrap(), &yank, None));
+    }
+    assert!(!run_next(&mut req, window));
+    assert_eq!(head(), before);
+    assert_eq!(::queued_jobs(&mut req).len(), 3);
+
+    // After that they're all pushed in one commit.
+    let due = ::cargo_registry::now() + Duration::minutes(-6);
+    t!(req.tx().unwrap().execute("UPDATE jobs SET run_at = $1", &[&due]));
+    assert!(run_next(&mut req, window));
+    let commit = bare.find_commit(head()).unwrap();
+    assert_eq!(commit.parent_id(0), Some(before));
+    assert!(commit.message().unwrap().starts_with("Updating 3 crate versions"));
+    assert_eq!(::queued_jobs(&mut req).len(), 0);
+
+    // A full batch doesn't wait at all.
+    let before = head();
+    for _ in range(0, BATCH_SIZE) {
+        t!(jobs::enqueue(req.tx().unwrap(), &yank, None));
+    }
+    assert!(run_next(&mut req, window));
+    let commit = bare.find_commit(head()).unwrap();
+    assert_eq!(commit.parent_id(0), Some(before));
+    let msg = format!("Updating {} crate versions", BATCH_SIZE);
+    assert!(commit.message().unwrap().starts_with(msg.as_slice()));
+    assert_eq!(::queued_jobs(&mut req).len(), 0);
+}
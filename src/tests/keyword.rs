@@ -15,7 +15,7 @@ struct GoodKeyword { keyword: EncodableKeyword }
 
 #[test]
 fn index() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/keywords");
     let mut response = ok_resp!(middle.call(&mut req));
     let json: KeywordList = ::json(&mut response);
@@ -32,7 +32,7 @@ fn index() {
 
 #[test]
 fn show() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/keywords/foo");
     let response = t_resp!(middle.call(&mut req));
     assert_eq!(response.status.0, 404);
@@ -47,7 +47,7 @@ fn tx(req: &Request) -> &Connection { req.tx().unwrap() }
 
 #[test]
 fn update_crate() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/keywords/foo");
     let cnt = |&: req: &mut MockRequest, kw: &str| {
         req.with_path(format!("/api/v1/keywords/{}", kw).as_slice());
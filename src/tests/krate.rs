@@ -1,21 +1,31 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+use std::collections::btree_map::Entry;
 use std::old_io::fs::PathExtensions;
 use std::old_io::{self, fs, File, MemWriter};
 use std::iter::repeat;
 use std::sync::Arc;
+use std::time::Duration;
 
 use conduit::{Handler, Request, Method};
+use conduit_middleware;
 use conduit_test::MockRequest;
+use flate2::CompressionLevel;
+use flate2::writer::GzEncoder;
 use git2;
+use openssl::crypto::hash;
 use rustc_serialize::{json, Decoder};
+use rustc_serialize::hex::ToHex;
+use s3;
 use semver;
 
 use cargo_registry::App;
-use cargo_registry::dependency::EncodableDependency;
+use cargo_registry::dependency::{EncodableDependency, Kind};
 use cargo_registry::download::EncodableVersionDownload;
-use cargo_registry::krate::{Crate, EncodableCrate};
+use cargo_registry::jobs::MAX_ATTEMPTS;
+use cargo_registry::krate::{Crate, EncodableCrate, EncodableLicense};
 use cargo_registry::upload as u;
-use cargo_registry::user::EncodableUser;
+use cargo_registry::storage::{Backend, Local, Storage};
+use cargo_registry::user::{EncodableUser, Scope};
 use cargo_registry::version::EncodableVersion;
 
 #[derive(RustcDecodable)]
@@ -25,6 +35,8 @@ struct VersionsList { versions: Vec<EncodableVersion> }
 #[derive(RustcDecodable)]
 struct CrateMeta { total: i32 }
 #[derive(RustcDecodable)]
+struct LicenseList { licenses: Vec<EncodableLicense> }
+#[derive(RustcDecodable)]
 struct GitCrate { name: String, vers: String, deps: Vec<String>, cksum: String }
 #[derive(RustcDecodable)]
 struct GoodCrate { krate: EncodableCrate }
@@ -39,7 +51,7 @@ struct Downloads { version_downloads: Vec<EncodableVersionDownload> }
 
 #[test]
 fn index() {
-    let (_b, _app, mut middle) = ::app();
+    let (_app, mut middle) = ::app();
     let mut req = MockRequest::new(Method::Get, "/api/v1/crates");
     let mut response = ok_resp!(middle.call(&mut req));
     let json: CrateList = ::json(&mut response);
@@ -55,11 +67,13 @@ fn index() {
     assert_eq!(json.meta.total, 1);
     assert_eq!(json.crates[0].name, krate.name);
     assert_eq!(json.crates[0].id, krate.name);
+    // Markdown is only rendered when a single crate is shown.
+    assert!(json.crates[0].description_html.is_none());
 }
 
 #[test]
 fn index_queries() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     let mut req = ::req(app, Method::Get, "/api/v1/crates");
     let u = ::mock_user(&mut req, ::user("foo"));
@@ -104,9 +118,74 @@ fn index_queries() {
     assert_eq!(::json::<CrateList>(&mut response).crates.len(), 0);
 }
 
+fn licensed(name: &str, license: &str) -> Crate {
+    let mut krate = ::krate(name);
+    krate.license = Some(license.to_string());
+    krate
+}
+
+#[test]
+fn index_license_filter() {
+    let (app, middle) = ::app();
+
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, licensed("foo", "MIT/Apache-2.0"));
+    ::mock_crate(&mut req, licensed("bar", "MIT AND Zlib"));
+    ::mock_crate(&mut req, licensed("baz", "mit"));
+    ::mock_crate(&mut req, ::krate("qux"));
+
+    let mut response = ok_resp!(middle.call(req.with_query("license=mit")));
+    let json: CrateList = ::json(&mut response);
+    assert_eq!(json.meta.total, 2);
+    assert_eq!(json.crates[0].name.as_slice(), "baz");
+    assert_eq!(json.crates[1].name.as_slice(), "foo");
+    let mut response = ok_resp!(middle.call(req.with_query("license=Apache-2.0")));
+    assert_eq!(::json::<CrateList>(&mut response).meta.total, 1);
+    let mut response = ok_resp!(middle.call(req.with_query("license=Zlib")));
+    assert_eq!(::json::<CrateList>(&mut response).meta.total, 0);
+
+    let json = bad_resp!(middle.call(req.with_query("license=MIT%20OR%20Zlib")));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("must be a single license identifier"),
+            "{:?}", json.errors);
+    let json = bad_resp!(middle.call(req.with_query("license=Bogus")));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("unknown license `Bogus`"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn licenses() {
+    let (app, middle) = ::app();
+
+    let mut req = ::req(app, Method::Get, "/api/v1/licenses");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, licensed("foo", "MIT OR Apache-2.0"));
+    ::mock_crate(&mut req, licensed("bar", "MIT AND Zlib"));
+    ::mock_crate(&mut req, licensed("baz", "MIT"));
+
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: LicenseList = ::json(&mut response);
+    let licenses = json.licenses.iter().map(|l| {
+        (l.id.as_slice(), l.crates_cnt)
+    }).collect::<Vec<_>>();
+    assert_eq!(licenses, vec![("MIT", 2), ("Apache-2.0", 1)]);
+
+    // Relicensing a crate moves it between licenses.
+    ::mock_crate_vers(&mut req, licensed("bar", "Zlib"),
+                      &semver::Version::parse("1.1.0").unwrap());
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: LicenseList = ::json(&mut response);
+    let licenses = json.licenses.iter().map(|l| {
+        (l.id.as_slice(), l.crates_cnt)
+    }).collect::<Vec<_>>();
+    assert_eq!(licenses, vec![("MIT", 2), ("Apache-2.0", 1), ("Zlib", 1)]);
+}
+
 #[test]
 fn show() {
-    let (_b, _app, mut middle) = ::app();
+    let (_app, mut middle) = ::app();
     let mut krate = ::krate("foo");
     krate.description = Some(format!("description"));
     krate.documentation = Some(format!("https://example.com"));
@@ -133,9 +212,29 @@ fn show() {
             "bad suffix {}", json.versions[0].dl_path);
 }
 
+#[test]
+fn show_rendered() {
+    let (_app, mut middle) = ::app();
+    let mut krate = ::krate("foo");
+    krate.description = Some(format!("A *fast* <script>x</script>crate"));
+    krate.readme = Some(format!("# foo\n\n![logo](logo.png)"));
+    krate.repository = Some(format!("https://github.com/foo/foo"));
+    middle.add(::middleware::MockUser(::user("foo")));
+    middle.add(::middleware::MockCrate(krate.clone()));
+    let mut req = MockRequest::new(Method::Get, "/api/v1/crates/foo");
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: CrateResponse = ::json(&mut response);
+    assert_eq!(json.krate.description, krate.description);
+    assert_eq!(json.krate.description_html.unwrap(),
+               "<p>A <em>fast</em> crate</p>\n");
+    assert_eq!(json.krate.readme_html.unwrap(),
+               "<h1>foo</h1>\n<p><img src=\"https://raw.githubusercontent.com/\
+                foo/foo/master/logo.png\" alt=\"logo\"></p>\n");
+}
+
 #[test]
 fn versions() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/versions");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
@@ -144,7 +243,7 @@ fn versions() {
     assert_eq!(json.versions.len(), 1);
 }
 
-fn new_req(app: Arc<App>, krate: &str, version: &str) -> MockRequest {
+pub fn new_req(app: Arc<App>, krate: &str, version: &str) -> MockRequest {
     new_req_full(app, ::krate(krate), version, Vec::new())
 }
 
@@ -155,8 +254,8 @@ fn new_req_full(app: Arc<App>, krate: Crate, version: &str,
     return req;
 }
 
-fn new_req_body(krate: Crate, version: &str, deps: Vec<u::CrateDependency>)
-                -> Vec<u8> {
+pub fn new_req_body(krate: Crate, version: &str,
+                    deps: Vec<u::CrateDependency>) -> Vec<u8> {
     let kws = krate.keywords.into_iter().map(u::Keyword).collect();
     new_crate_to_body(&u::NewCrate {
         name: u::CrateName(krate.name),
@@ -175,18 +274,122 @@ fn new_req_body(krate: Crate, version: &str, deps: Vec<u::CrateDependency>)
     })
 }
 
+fn new_crate(name: &str, version: &str) -> u::NewCrate {
+    u::NewCrate {
+        name: u::CrateName(name.to_string()),
+        vers: u::CrateVersion(semver::Version::parse(version).unwrap()),
+        features: HashMap::new(),
+        deps: Vec::new(),
+        authors: vec!["foo".to_string()],
+        description: Some("description".to_string()),
+        homepage: None,
+        documentation: None,
+        readme: None,
+        keywords: None,
+        license: Some("MIT".to_string()),
+        license_file: None,
+        repository: None,
+    }
+}
+
 fn new_crate_to_body(new_crate: &u::NewCrate) -> Vec<u8> {
+    new_crate_to_body_with_tarball(new_crate,
+                                   crate_tarball(new_crate).as_slice())
+}
+
+// A `Cargo.toml` which agrees with the metadata in `new_crate`.
+fn manifest(new_crate: &u::NewCrate) -> String {
+    let mut sections = BTreeMap::new();
+    for dep in new_crate.deps.iter() {
+        let kind = match dep.kind.unwrap_or(Kind::Normal) {
+            Kind::Normal => "dependencies",
+            Kind::Build => "build-dependencies",
+            Kind::Dev => "dev-dependencies",
+        };
+        let header = match dep.target {
+            Some(ref target) => format!("target.{}.{}", target, kind),
+            None => kind.to_string(),
+        };
+        let lines = match sections.entry(header) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(String::new()),
+        };
+        lines.push_str(format!("{} = \"{}\"\n", &*dep.name,
+                               *dep.version_req).as_slice());
+    }
+
+    let mut manifest = format!("[package]\nname = \"{}\"\nversion = \"{}\"\n",
+                               &*new_crate.name, *new_crate.vers);
+    for (header, lines) in sections.iter() {
+        manifest.push_str(format!("[{}]\n{}", header, lines).as_slice());
+    }
+    manifest
+}
+
+// The `.crate` file that `cargo package` would create for `new_crate`.
+fn crate_tarball(new_crate: &u::NewCrate) -> Vec<u8> {
+    let prefix = format!("{}-{}", &*new_crate.name, *new_crate.vers);
+    let manifest = manifest(new_crate);
+    tarball(&[
+        (format!("{}/Cargo.toml", prefix).as_slice(), manifest.as_bytes()),
+        (format!("{}/src/lib.rs", prefix).as_slice(), b""),
+    ])
+}
+
+fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut tar = Vec::new();
+    for &(path, data) in files.iter() {
+        tar_entry(&mut tar, path, b'0', "", data);
+    }
+    gzip_tar(tar)
+}
+
+fn tar_entry(tar: &mut Vec<u8>, path: &str, kind: u8, link: &str,
+             data: &[u8]) {
+    fn set(header: &mut [u8], at: usize, value: &[u8]) {
+        for (i, b) in value.iter().enumerate() { header[at + i] = *b; }
+    }
+    let mut header = [0u8; 512];
+    set(&mut header, 0, path.as_bytes());
+    set(&mut header, 100, b"0000644\0");
+    set(&mut header, 108, b"0000000\0");
+    set(&mut header, 116, b"0000000\0");
+    set(&mut header, 124, format!("{:011o}\0", data.len()).as_bytes());
+    set(&mut header, 136, b"00000000000\0");
+    set(&mut header, 148, b"        ");
+    header[156] = kind;
+    set(&mut header, 157, link.as_bytes());
+    set(&mut header, 257, b"ustar\000");
+    let cksum = header.iter().fold(0, |sum, b| sum + *b as usize);
+    set(&mut header, 148, format!("{:06o}\0 ", cksum).as_bytes());
+
+    tar.push_all(&header);
+    tar.push_all(data);
+    let padding = (512 - data.len() % 512) % 512;
+    tar.extend(repeat(0).take(padding));
+}
+
+fn gzip_tar(mut tar: Vec<u8>) -> Vec<u8> {
+    tar.extend(repeat(0).take(1024));
+    let mut gz = GzEncoder::new(MemWriter::new(), CompressionLevel::Default);
+    gz.write_all(tar.as_slice()).unwrap();
+    gz.finish().unwrap().into_inner()
+}
+
+fn new_crate_to_body_with_tarball(new_crate: &u::NewCrate, tarball: &[u8])
+                                  -> Vec<u8> {
     let json = json::encode(&new_crate).unwrap();
     let mut body = MemWriter::new();
     body.write_le_u32(json.len() as u32).unwrap();
     body.write_str(json.as_slice()).unwrap();
-    body.write_le_u32(0).unwrap();
+    body.write_le_u32(tarball.len() as u32).unwrap();
+    body.write_all(tarball).unwrap();
     body.into_inner()
 }
 
 #[test]
 fn new_wrong_token() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app.clone(), "foo", "1.0.0");
     bad_resp!(middle.call(&mut req));
     drop(req);
@@ -207,7 +410,7 @@ fn new_wrong_token() {
 fn new_bad_names() {
     fn bad_name(name: &str) {
         println!("testing: `{}`", name);
-        let (_b, app, middle) = ::app();
+        let (app, middle) = ::app();
         let mut req = new_req(app, name, "1.0.0");
         ::mock_user(&mut req, ::user("foo"));
         ::logout(&mut req);
@@ -222,24 +425,61 @@ fn new_bad_names() {
 
 #[test]
 fn new_krate() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app, "foo", "1.0.0");
-    let user = ::mock_user(&mut req, ::user("foo"));
+    ::mock_user(&mut req, ::user("foo"));
+    let token = ::mock_token(&mut req, "ci", Scope::all(), None);
     ::logout(&mut req);
-    req.header("Authorization", user.api_token.as_slice());
+    req.header("Authorization", token.as_slice());
     let mut response = ok_resp!(middle.call(&mut req));
     let json: GoodCrate = ::json(&mut response);
     assert_eq!(json.krate.name.as_slice(), "foo");
     assert_eq!(json.krate.max_version.as_slice(), "1.0.0");
 }
 
+#[test]
+fn new_krate_with_scoped_token() {
+    let (app, middle) = ::app();
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = ::mock_token(&mut req, "ci", &[Scope::PublishNew], Some("fo*"));
+    ::logout(&mut req);
+    req.header("Authorization", token.as_slice());
+    let mut response = ok_resp!(middle.call(&mut req));
+    ::json::<GoodCrate>(&mut response);
+
+    // The token can only publish new crates whose names match its pattern.
+    req.with_body(new_req_body(::krate("foo"), "1.0.1", Vec::new()).as_slice());
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice().contains("`publish-update` scope"),
+            "{:?}", json.errors);
+    req.with_body(new_req_body(::krate("bar"), "1.0.0", Vec::new()).as_slice());
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("`publish-new` scope for the crate `bar`"),
+            "{:?}", json.errors);
+
+    // Nor can it yank, or change the owners.
+    let json = bad_resp!(middle.call(req.with_method(Method::Delete)
+                                        .with_path("/api/v1/crates/foo/1.0.0/yank")));
+    assert!(json.errors[0].detail.as_slice().contains("`yank` scope"),
+            "{:?}", json.errors);
+    let body = r#"{"users":["foobar"]}"#;
+    let json = bad_resp!(middle.call(req.with_method(Method::Put)
+                                        .with_path("/api/v1/crates/foo/owners")
+                                        .with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("`change-owners` scope"),
+            "{:?}", json.errors);
+}
+
 #[test]
 fn new_krate_weird_version() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app, "foo", "0.0.0-pre");
-    let user = ::mock_user(&mut req, ::user("foo"));
+    ::mock_user(&mut req, ::user("foo"));
+    let token = ::mock_token(&mut req, "ci", Scope::all(), None);
     ::logout(&mut req);
-    req.header("Authorization", user.api_token.as_slice());
+    req.header("Authorization", token.as_slice());
     let mut response = ok_resp!(middle.call(&mut req));
     let json: GoodCrate = ::json(&mut response);
     assert_eq!(json.krate.name.as_slice(), "foo");
@@ -248,7 +488,7 @@ fn new_krate_weird_version() {
 
 #[test]
 fn new_krate_with_dependency() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let dep = u::CrateDependency {
         name: u::CrateName("foo".to_string()),
         optional: false,
@@ -267,7 +507,7 @@ fn new_krate_with_dependency() {
 
 #[test]
 fn new_krate_twice() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut krate = ::krate("foo");
     krate.description = Some("description".to_string());
     let mut req = new_req_full(app, krate.clone(), "2.0.0", Vec::new());
@@ -281,7 +521,7 @@ fn new_krate_twice() {
 
 #[test]
 fn new_krate_wrong_user() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     let mut req = new_req(app, "foo", "2.0.0");
 
@@ -299,7 +539,7 @@ fn new_krate_wrong_user() {
 
 #[test]
 fn new_krate_bad_name() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     {
         let mut req = new_req(app.clone(), "snow☃", "2.0.0");
@@ -321,7 +561,7 @@ fn new_krate_bad_name() {
 fn new_crate_owner() {
     #[derive(RustcDecodable)] struct O { ok: bool }
 
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     // Create a crate under one user
     let mut req = new_req(app.clone(), "foo", "1.0.0");
@@ -367,7 +607,7 @@ fn valid_feature_names() {
 
 #[test]
 fn new_krate_too_big() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app, "foo", "1.0.0");
     ::mock_user(&mut req, ::user("foo"));
     req.with_body(repeat("a").take(1000 * 1000).collect::<String>().as_bytes());
@@ -376,7 +616,7 @@ fn new_krate_too_big() {
 
 #[test]
 fn new_krate_duplicate_version() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app, "foo", "1.0.0");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
@@ -387,7 +627,7 @@ fn new_krate_duplicate_version() {
 
 #[test]
 fn new_crate_similar_name() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app, "foo", "1.1.0");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("Foo"));
@@ -398,11 +638,12 @@ fn new_crate_similar_name() {
 
 #[test]
 fn new_krate_git_upload() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = new_req(app, "foo", "1.0.0");
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
 
     let path = ::git::checkout().join("3/f/foo");
     assert!(path.exists());
@@ -411,13 +652,41 @@ fn new_krate_git_upload() {
     assert_eq!(p.name.as_slice(), "foo");
     assert_eq!(p.vers.as_slice(), "1.0.0");
     assert!(p.deps.is_empty());
-    assert_eq!(p.cksum.as_slice(),
-               "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    let tarball = crate_tarball(&new_crate("foo", "1.0.0"));
+    assert_eq!(p.cksum, hash::hash(hash::Type::SHA256, tarball.as_slice()).to_hex());
+}
+
+#[test]
+fn version_cksum() {
+    let (app, middle) = ::app();
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    #[derive(RustcDecodable)]
+    struct Cksum { cksum: Option<String> }
+    let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                           .with_path("/api/v1/crates/foo/1.0.0/cksum")));
+    let json: Cksum = ::json(&mut resp);
+    let tarball = crate_tarball(&new_crate("foo", "1.0.0"));
+    let cksum = hash::hash(hash::Type::SHA256, tarball.as_slice()).to_hex();
+    assert_eq!(json.cksum, Some(cksum.clone()));
+
+    // It's the same as what's in the index.
+    let path = ::git::checkout().join("3/f/foo");
+    let contents = File::open(&path).read_to_string().unwrap();
+    let p: GitCrate = json::decode(contents.as_slice()).unwrap();
+    assert_eq!(p.cksum, cksum);
+
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/crates/foo/2.0.0/cksum")));
+    assert!(json.errors[0].detail.as_slice().contains("does not have a version"),
+            "{:?}", json.errors);
 }
 
 #[test]
 fn new_krate_git_upload_appends() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let path = ::git::checkout().join("3/f/foo");
     fs::mkdir_recursive(&path.dir_path(), old_io::USER_RWX).unwrap();
     File::create(&path).write_str(
@@ -429,6 +698,7 @@ fn new_krate_git_upload_appends() {
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
 
     let contents = File::open(&path).read_to_string().unwrap();
     let mut lines = contents.as_slice().lines();
@@ -445,7 +715,7 @@ fn new_krate_git_upload_appends() {
 
 #[test]
 fn new_krate_git_upload_with_conflicts() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     {
         let repo = git2::Repository::open(&::git::bare()).unwrap();
@@ -461,11 +731,545 @@ fn new_krate_git_upload_with_conflicts() {
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
+    assert!(::git::checkout().join("3/f/foo").exists());
+    assert!(::queued_jobs(&mut req).is_empty());
+}
+
+#[test]
+fn new_krate_stored_locally() {
+    let (app, middle) = ::app();
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    let mut response = ok_resp!(middle.call(&mut req));
+    ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
+    assert!(::git::storage().join("crates/foo/foo-1.0.0.crate").exists());
+    let storage = Local::new(::git::storage());
+    assert_eq!(storage.list("/crates/").unwrap(),
+               vec!["/crates/foo/foo-1.0.0.crate".to_string()]);
+    assert!(storage.list("/crates/bar/").unwrap().is_empty());
+
+    let resp = t_resp!(middle.call(req.with_method(Method::Get)
+                                      .with_path("/api/v1/crates/foo/1.0.0/download")));
+    assert_eq!(resp.status.0, 302);
+    let location = resp.headers.get("Location").unwrap()[0].clone();
+    assert_eq!(location.as_slice(), "/storage/crates/foo/foo-1.0.0.crate");
+
+    let mut resp = ok_resp!(middle.call(req.with_path(location.as_slice())));
+    assert_eq!(resp.body.read_to_end().unwrap(),
+               crate_tarball(&new_crate("foo", "1.0.0")));
+
+    let resp = t_resp!(middle.call(req.with_path("/storage/crates/foo/nope.crate")));
+    assert_eq!(resp.status.0, 404);
+
+    // Nothing outside of the storage directory can be read.
+    t!(File::create(&::git::root().join("secret")).write_str("secret"));
+    for path in ["//etc/passwd", "/../secret", "/crates/../../secret",
+                 "/crates//foo/foo-1.0.0.crate"].iter() {
+        assert!(storage.get(*path).is_err(), "{}", path);
+        let url = format!("/storage{}", path);
+        let resp = t_resp!(middle.call(req.with_path(url.as_slice())));
+        assert_eq!(resp.status.0, 404);
+    }
+}
+
+struct FailingReader;
+
+impl Reader for FailingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> old_io::IoResult<usize> {
+        Err(old_io::standard_error(old_io::OtherIoError))
+    }
+}
+
+#[test]
+fn local_storage_failed_put_cleans_up() {
+    ::app();
+    let storage = Local::new(::git::storage());
+    assert!(storage.put("/crates/foo/foo-1.0.0.crate", &mut FailingReader, 10,
+                        "application/x-tar").is_err());
+    let dir = ::git::storage().join("crates/foo");
+    assert_eq!(t!(fs::readdir(&dir)), Vec::new());
+}
+
+#[test]
+fn new_krate_git_failure_deletes_tarball() {
+    let (app, middle) = ::app();
+    // Nothing can be written where the crate's index file should go.
+    t!(fs::mkdir_recursive(&::git::checkout().join("3/f/foo"),
+                           old_io::USER_RWX));
+    let tarball = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+    assert!(tarball.exists());
+
+    for _ in range(1, MAX_ATTEMPTS) {
+        ::run_jobs_now(&mut req);
+    }
+    let jobs = ::queued_jobs(&mut req);
+    assert_eq!(jobs.len(), 1);
+    assert!(jobs[0].job.as_slice().contains("AddCrate"));
+    assert!(jobs[0].dead);
+    assert!(!tarball.exists());
+}
+
+fn s3_app(server: &::fake_s3::Server)
+          -> (Arc<App>, conduit_middleware::MiddlewareBuilder) {
+    let mut config = ::config();
+    config.storage = Backend::S3;
+    config.s3_bucket = "bucket".to_string();
+    config.s3_endpoint = Some(server.url.clone());
+    config.s3_addressing = s3::Addressing::Path;
+    // Room for a crate big enough to be uploaded in several parts.
+    config.max_upload_size = 4 * s3::MIN_PART_SIZE;
+    config.max_unpack_size = 4 * s3::MIN_PART_SIZE;
+    ::app_with(config)
+}
+
+fn s3_req(app: Arc<App>, tarball: &[u8]) -> MockRequest {
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
+    req.with_body(new_crate_to_body_with_tarball(&new_crate("foo", "1.0.0"),
+                                                 tarball).as_slice());
+    ::mock_user(&mut req, ::user("foo"));
+    return req;
+}
+
+// A tarball for `foo#1.0.0` which takes three parts to upload, as most of it
+// is noise which doesn't compress.
+fn big_tarball() -> Vec<u8> {
+    let mut state = 1u32;
+    let noise = range(0, 2 * s3::MIN_PART_SIZE + 1024).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect::<Vec<u8>>();
+    let manifest = manifest(&new_crate("foo", "1.0.0"));
+    tarball(&[
+        ("foo-1.0.0/Cargo.toml", manifest.as_bytes()),
+        ("foo-1.0.0/noise", noise.as_slice()),
+    ])
+}
+
+#[test]
+fn new_krate_small_s3_upload() {
+    let server = ::fake_s3::server();
+    let (app, middle) = s3_app(&server);
+    let tarball = crate_tarball(&new_crate("foo", "1.0.0"));
+    let mut req = s3_req(app, tarball.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let state = server.state.lock().unwrap();
+    assert_eq!(state.requests,
+               vec!["PUT /bucket/crates/foo/foo-1.0.0.crate".to_string()]);
+    assert_eq!(state.objects["/bucket/crates/foo/foo-1.0.0.crate"],
+               crate_tarball(&new_crate("foo", "1.0.0")));
+}
+
+#[test]
+fn new_krate_small_s3_upload_retried() {
+    let server = ::fake_s3::server();
+    let (app, middle) = s3_app(&server);
+    server.state.lock().unwrap().failures.insert(0, 2);
+    let tarball = crate_tarball(&new_crate("foo", "1.0.0"));
+    let mut req = s3_req(app, tarball.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let path = "/bucket/crates/foo/foo-1.0.0.crate";
+    let state = server.state.lock().unwrap();
+    assert_eq!(state.requests, repeat(format!("PUT {}", path)).take(3)
+                                   .collect::<Vec<_>>());
+    assert_eq!(state.objects[path], crate_tarball(&new_crate("foo", "1.0.0")));
+}
+
+#[test]
+fn new_krate_multipart_s3_upload() {
+    let server = ::fake_s3::server();
+    let (app, middle) = s3_app(&server);
+    // The second part fails once before succeeding.
+    server.state.lock().unwrap().failures.insert(2, 1);
+    let tarball = big_tarball();
+    let mut req = s3_req(app, tarball.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let parts = (tarball.len() + s3::MIN_PART_SIZE - 1) / s3::MIN_PART_SIZE;
+    assert!(parts > 2);
+    let path = "/bucket/crates/foo/foo-1.0.0.crate";
+    let mut expected = vec![format!("POST {}?uploads", path)];
+    for i in range(1, parts + 1) {
+        expected.push(format!("PUT {}?partNumber={}&uploadId=upload-1",
+                              path, i));
+        if i == 2 { expected.push(expected[i].clone()) }
+    }
+    expected.push(format!("POST {}?uploadId=upload-1", path));
+
+    let state = server.state.lock().unwrap();
+    assert_eq!(state.requests, expected);
+    assert_eq!(state.objects[path], tarball);
+    assert!(state.uploads.is_empty());
+}
+
+#[test]
+fn new_krate_multipart_s3_upload_aborted() {
+    let server = ::fake_s3::server();
+    let (app, middle) = s3_app(&server);
+    server.state.lock().unwrap().failures.insert(2, 100);
+    let tarball = big_tarball();
+    let mut req = s3_req(app, tarball.as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    {
+        let state = server.state.lock().unwrap();
+        assert!(state.requests.contains(
+            &"DELETE /bucket/crates/foo/foo-1.0.0.crate?uploadId=upload-1".to_string()));
+        assert!(state.uploads.is_empty());
+        assert!(state.objects.is_empty());
+    }
+
+    // The upload is tried again later, and until it works the crate stays
+    // out of the index.
+    let jobs = ::queued_jobs(&mut req);
+    assert_eq!(jobs.len(), 1);
+    assert!(jobs[0].job.as_slice().contains("UploadTarball"));
+    assert_eq!(jobs[0].attempts, 1);
+    assert!(jobs[0].last_error.is_some());
+    assert!(!::git::checkout().join("3/f/foo").exists());
+}
+
+fn tarball_req(app: Arc<App>, new_crate: &u::NewCrate,
+               files: &[(&str, &[u8])]) -> MockRequest {
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
+    let body = new_crate_to_body_with_tarball(new_crate,
+                                              tarball(files).as_slice());
+    req.with_body(body.as_slice());
+    ::mock_user(&mut req, ::user("foo"));
+    return req;
+}
+
+#[test]
+fn new_krate_tarball_missing_manifest() {
+    let (app, middle) = ::app();
+    let krate = new_crate("foo", "1.0.0");
+    let mut req = tarball_req(app, &krate, &[("foo-1.0.0/src/lib.rs", b"")]);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("missing `foo-1.0.0/Cargo.toml`"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn new_krate_tarball_manifest_mismatch() {
+    let (app, middle) = ::app();
+    let krate = new_crate("foo", "1.0.0");
+
+    let manifest = manifest(&new_crate("bar", "1.0.0"));
+    let mut req = tarball_req(app.clone(), &krate,
+                              &[("foo-1.0.0/Cargo.toml", manifest.as_bytes())]);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice().contains("crate `bar`"),
+            "{:?}", json.errors);
+    drop(req);
+
+    let manifest = manifest(&new_crate("foo", "1.0.1"));
+    let mut req = tarball_req(app.clone(), &krate,
+                              &[("foo-1.0.0/Cargo.toml", manifest.as_bytes())]);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice().contains("version `1.0.1`"),
+            "{:?}", json.errors);
+    drop(req);
+
+    let mut with_dep = new_crate("foo", "1.0.0");
+    with_dep.deps.push(u::CrateDependency {
+        optional: false,
+        default_features: true,
+        name: u::CrateName("bar".to_string()),
+        features: Vec::new(),
+        version_req: u::CrateVersionReq(semver::VersionReq::parse("^1.0").unwrap()),
+        target: None,
+        kind: None,
+    });
+    let manifest = manifest(&with_dep);
+    let mut req = tarball_req(app, &krate,
+                              &[("foo-1.0.0/Cargo.toml", manifest.as_bytes())]);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("dependency `bar` in the `[dependencies]`"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn new_krate_tarball_dependency_mismatch() {
+    let (app, middle) = ::app();
+    let mut krate = new_crate("foo", "1.0.0");
+    krate.deps.push(u::CrateDependency {
+        optional: false,
+        default_features: true,
+        name: u::CrateName("bar".to_string()),
+        features: Vec::new(),
+        version_req: u::CrateVersionReq(semver::VersionReq::parse("^1.0").unwrap()),
+        target: None,
+        kind: Some(Kind::Dev),
+    });
+    let manifest = "[package]\n\
+                    name = \"foo\"\n\
+                    version = \"1.0.0\"\n\
+                    [dev-dependencies]\n\
+                    bar = \"2.0\"\n";
+    let mut req = tarball_req(app.clone(), &krate,
+                              &[("foo-1.0.0/Cargo.toml", manifest.as_bytes())]);
+    ::mock_crate(&mut req, ::krate("bar"));
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("dependency `bar` requires `2.0`"),
+            "{:?}", json.errors);
+    drop(req);
+
+    let manifest = "[package]\n\
+                    name = \"foo\"\n\
+                    version = \"1.0.0\"\n\
+                    [dependencies]\n\
+                    bar = \"1.0\"\n";
+    let mut req = tarball_req(app, &krate,
+                              &[("foo-1.0.0/Cargo.toml", manifest.as_bytes())]);
+    ::mock_crate(&mut req, ::krate("bar"));
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("`bar` is not in the `[dev-dependencies]`"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn new_krate_tarball_escapes_prefix() {
+    let (app, middle) = ::app();
+    let krate = new_crate("foo", "1.0.0");
+    let manifest = manifest(&krate);
+    for path in ["foo-1.0.1/src/lib.rs", "src/lib.rs"].iter() {
+        let mut req = tarball_req(app.clone(), &krate, &[
+            ("foo-1.0.0/Cargo.toml", manifest.as_bytes()),
+            (*path, b""),
+        ]);
+        let json = bad_resp!(middle.call(&mut req));
+        let msg = format!("`{}` is not inside `foo-1.0.0/`", path);
+        assert!(json.errors[0].detail.as_slice().contains(msg.as_slice()),
+                "{:?}", json.errors);
+    }
+}
+
+// Uploads a tarball holding a valid manifest along with `entries`, each of
+// which is a path, a tar typeflag and a link target.
+fn entries_req(app: Arc<App>, entries: &[(&str, u8, &str)]) -> MockRequest {
+    let krate = new_crate("foo", "1.0.0");
+    let mut tar = Vec::new();
+    tar_entry(&mut tar, "foo-1.0.0/Cargo.toml", b'0', "",
+              manifest(&krate).as_bytes());
+    for &(path, kind, link) in entries.iter() {
+        tar_entry(&mut tar, path, kind, link, b"");
+    }
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
+    let body = new_crate_to_body_with_tarball(&krate,
+                                              gzip_tar(tar).as_slice());
+    req.with_body(body.as_slice());
+    ::mock_user(&mut req, ::user("foo"));
+    return req;
+}
+
+#[test]
+fn new_krate_tarball_bad_paths() {
+    let (app, middle) = ::app();
+    let cases = [
+        ("/foo-1.0.0/src/lib.rs", "has an absolute path"),
+        ("foo-1.0.0/../src/lib.rs", "has a `..` component"),
+        ("foo-1.0.0/src/../../lib.rs", "has a `..` component"),
+        ("foo-1.0.0/src/../lib.rs", "has a `..` component"),
+    ];
+    for &(path, msg) in cases.iter() {
+        let mut req = entries_req(app.clone(), &[(path, b'0', "")]);
+        let json = bad_resp!(middle.call(&mut req));
+        let msg = format!("`{}` {}", path, msg);
+        assert!(json.errors[0].detail.as_slice().contains(msg.as_slice()),
+                "{:?}", json.errors);
+    }
+}
+
+#[test]
+fn new_krate_tarball_links() {
+    let (app, middle) = ::app();
+    let cases = [
+        ("foo-1.0.0/link", b'2', "/etc/passwd"),
+        ("foo-1.0.0/link", b'2', "../bar-1.0.0/src/lib.rs"),
+        ("foo-1.0.0/src/link", b'2', "../../.."),
+        ("foo-1.0.0/link", b'1', "/etc/passwd"),
+        ("foo-1.0.0/link", b'1', "src/lib.rs"),
+        ("foo-1.0.0/link", b'1', "foo-1.0.0/src/../src/lib.rs"),
+        ("foo-1.0.0/src/link", b'2', "../src/lib.rs"),
+    ];
+    for &(path, kind, link) in cases.iter() {
+        let mut req = entries_req(app.clone(), &[(path, kind, link)]);
+        let json = bad_resp!(middle.call(&mut req));
+        let msg = format!("`{}` links to `{}`, which is outside of the \
+                           package", path, link);
+        assert!(json.errors[0].detail.as_slice().contains(msg.as_slice()),
+                "{:?}", json.errors);
+    }
+
+    // Each link on its own stays in the package, but `e` goes through `d`
+    // to the directory above it.
+    let mut req = entries_req(app.clone(), &[
+        ("foo-1.0.0/d", b'2', "."),
+        ("foo-1.0.0/e", b'2', "d/.."),
+    ]);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("`foo-1.0.0/e` links to `d/..`, which is outside of \
+                           the package"),
+            "{:?}", json.errors);
+
+    // Links which stay inside of the package are fine.
+    let mut req = entries_req(app.clone(), &[
+        ("foo-1.0.0/src/lib.rs", b'0', ""),
+        ("foo-1.0.0/src/link", b'2', "./lib.rs"),
+        ("foo-1.0.0/d", b'2', "."),
+        ("foo-1.0.0/hard", b'1', "foo-1.0.0/src/lib.rs"),
+    ]);
+    ok_resp!(middle.call(&mut req));
+}
+
+#[test]
+fn new_krate_tarball_special_files() {
+    let (app, middle) = ::app();
+    for kind in [b'3', b'4', b'6'].iter() {
+        let mut req = entries_req(app.clone(), &[("foo-1.0.0/dev", *kind, "")]);
+        let json = bad_resp!(middle.call(&mut req));
+        assert!(json.errors[0].detail.as_slice()
+                    .contains("`foo-1.0.0/dev` is a special file"),
+                "{:?}", json.errors);
+    }
+}
+
+#[test]
+fn new_krate_tarball_too_big_unpacked() {
+    let (app, middle) = ::app();
+    let krate = new_crate("foo", "1.0.0");
+    let manifest = manifest(&krate);
+    // Compresses down to well under `max_upload_size`.
+    let zeros = repeat(0).take(200_000).collect::<Vec<u8>>();
+    let mut req = tarball_req(app, &krate, &[
+        ("foo-1.0.0/Cargo.toml", manifest.as_bytes()),
+        ("foo-1.0.0/zeros", zeros.as_slice()),
+    ]);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("`foo-1.0.0/zeros` takes the uploaded tarball over \
+                           the limit"),
+            "{:?}", json.errors);
+}
+
+#[derive(RustcDecodable)]
+struct Readme { readme: Option<String> }
+
+#[test]
+fn new_krate_readme() {
+    let (app, middle) = ::app();
+    let krate = new_crate("foo", "1.0.0");
+    let manifest = manifest(&krate).replace("[package]\n",
+                                            "[package]\nreadme = \"README.md\"\n");
+    let mut req = tarball_req(app, &krate, &[
+        ("foo-1.0.0/Cargo.toml", manifest.as_bytes()),
+        ("foo-1.0.0/README.md", b"# foo\n\nA <b>crate</b>.<script>x</script>"),
+    ]);
+    ok_resp!(middle.call(&mut req));
+
+    let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                           .with_path("/api/v1/crates/foo/1.0.0/readme")));
+    let json: Readme = ::json(&mut resp);
+    assert_eq!(json.readme.unwrap(),
+               "<h1>foo</h1>\n<p>A <b>crate</b>.</p>\n");
+}
+
+#[test]
+fn new_krate_without_readme() {
+    let (app, middle) = ::app();
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+
+    let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                           .with_path("/api/v1/crates/foo/1.0.0/readme")));
+    let json: Readme = ::json(&mut resp);
+    assert!(json.readme.is_none());
+}
+
+#[derive(RustcDecodable)]
+struct License { license: Option<String>, license_file: Option<LicenseFile> }
+#[derive(RustcDecodable)]
+struct LicenseFile { path: String, content: String }
+
+#[test]
+fn new_krate_license_file() {
+    let (app, middle) = ::app();
+    let mut krate = new_crate("foo", "1.0.0");
+    krate.license = None;
+    krate.license_file = Some("./LICENSE-FOO".to_string());
+    let manifest = manifest(&krate);
+    let mut req = tarball_req(app, &krate, &[
+        ("foo-1.0.0/Cargo.toml", manifest.as_bytes()),
+        ("foo-1.0.0/LICENSE-FOO", b"All rights reserved.\n"),
+    ]);
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: GoodCrate = ::json(&mut response);
+    assert_eq!(json.krate.license.unwrap(), "non-standard");
+
+    // Relicensing a later release leaves the earlier one alone.
+    let mut krate = new_crate("foo", "1.0.1");
+    krate.license = Some("MIT/Apache-2.0".to_string());
+    req.with_body(new_crate_to_body(&krate).as_slice());
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: GoodCrate = ::json(&mut response);
+    assert_eq!(json.krate.license.unwrap(), "MIT OR Apache-2.0");
+
+    let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                           .with_path("/api/v1/crates/foo/1.0.0/license")));
+    let json: License = ::json(&mut resp);
+    assert!(json.license.is_none());
+    let file = json.license_file.unwrap();
+    assert_eq!(file.path.as_slice(), "LICENSE-FOO");
+    assert_eq!(file.content.as_slice(), "All rights reserved.\n");
+
+    let mut resp = ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/1.0.1/license")));
+    let json: License = ::json(&mut resp);
+    assert_eq!(json.license.unwrap(), "MIT OR Apache-2.0");
+    assert!(json.license_file.is_none());
+
+    let mut resp = ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/versions")));
+    let json: VersionsList = ::json(&mut resp);
+    assert_eq!(json.versions[0].license, Some("MIT OR Apache-2.0".to_string()));
+    assert_eq!(json.versions[1].license, None);
+}
+
+#[test]
+fn new_krate_license_file_missing() {
+    let (app, middle) = ::app();
+    let mut krate = new_crate("foo", "1.0.0");
+    krate.license_file = Some("LICENSE".to_string());
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
+    ::mock_user(&mut req, ::user("foo"));
+    req.with_body(new_crate_to_body(&krate).as_slice());
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("license file `LICENSE` is missing"),
+            "{:?}", json.errors);
 }
 
 #[test]
 fn new_krate_dependency_missing() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let dep = u::CrateDependency {
         optional: false,
         default_features: true,
@@ -485,14 +1289,14 @@ fn new_krate_dependency_missing() {
 
 #[test]
 fn summary_doesnt_die() {
-    let (_b, _app, middle) = ::app();
+    let (_app, middle) = ::app();
     let mut req = MockRequest::new(Method::Get, "/summary");
     ok_resp!(middle.call(&mut req));
 }
 
 #[test]
 fn download() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
@@ -514,9 +1318,36 @@ fn download() {
     assert_eq!(downloads.version_downloads.len(), 1);
 }
 
+#[test]
+fn download_presigned() {
+    let mut config = ::config();
+    config.storage = Backend::S3;
+    config.s3_bucket = "bucket".to_string();
+    config.s3_endpoint = Some("http://localhost:9000".to_string());
+    config.s3_url_expiry = Some(Duration::minutes(5));
+    let (app, middle) = ::app_with(config);
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 302);
+    let location = resp.headers.get("Location").unwrap()[0].clone();
+    let prefix = "http://bucket.localhost:9000/crates/foo/foo-1.0.0.crate?\
+                  AWSAccessKeyId=";
+    assert!(location.starts_with(prefix), "bad url {}", location);
+    assert!(location.contains("&Expires="));
+    assert!(location.contains("&Signature="));
+
+    // Downloads are still counted before redirecting.
+    req.with_path("/api/v1/crates/foo/1.0.0/downloads");
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let downloads = ::json::<Downloads>(&mut resp);
+    assert_eq!(downloads.version_downloads.len(), 1);
+}
+
 #[test]
 fn download_bad() {
-    let (_b, _app, mut middle) = ::app();
+    let (_app, mut middle) = ::app();
     let user = ::user("foo");
     let krate = ::krate("foo");
     middle.add(::middleware::MockUser(user.clone()));
@@ -529,7 +1360,7 @@ fn download_bad() {
 
 #[test]
 fn dependencies() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/dependencies");
     ::mock_user(&mut req, ::user("foo"));
@@ -551,7 +1382,7 @@ fn following() {
     #[derive(RustcDecodable)] struct F { following: bool }
     #[derive(RustcDecodable)] struct O { ok: bool }
 
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/following");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
@@ -601,7 +1432,7 @@ fn owners() {
     #[derive(RustcDecodable)] struct R { users: Vec<EncodableUser> }
     #[derive(RustcDecodable)] struct O { ok: bool }
 
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/owners");
     let other = ::user("foobar");
     ::mock_user(&mut req, other);
@@ -644,7 +1475,7 @@ fn owners() {
 fn yank() {
     #[derive(RustcDecodable)] struct O { ok: bool }
     #[derive(RustcDecodable)] struct V { version: EncodableVersion }
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let path = ::git::checkout().join("3/f/foo");
 
     // Upload a new crate, putting it in the git index
@@ -652,6 +1483,7 @@ fn yank() {
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
     assert!(File::open(&path).read_to_string().unwrap().as_slice()
                              .contains("\"yanked\":false"));
 
@@ -664,6 +1496,7 @@ fn yank() {
     let mut r = ok_resp!(middle.call(req.with_method(Method::Delete)
                                         .with_path("/api/v1/crates/foo/1.0.0/yank")));
     assert!(::json::<O>(&mut r).ok);
+    ::run_jobs(&mut req);
     assert!(File::open(&path).read_to_string().unwrap().as_slice()
                              .contains("\"yanked\":true"));
     let mut r = ok_resp!(middle.call(req.with_method(Method::Get)
@@ -674,6 +1507,7 @@ fn yank() {
     let mut r = ok_resp!(middle.call(req.with_method(Method::Put)
                                         .with_path("/api/v1/crates/foo/1.0.0/unyank")));
     assert!(::json::<O>(&mut r).ok);
+    ::run_jobs(&mut req);
     assert!(File::open(&path).read_to_string().unwrap().as_slice()
                              .contains("\"yanked\":false"));
     let mut r = ok_resp!(middle.call(req.with_method(Method::Get)
@@ -681,9 +1515,80 @@ fn yank() {
     assert!(!::json::<V>(&mut r).version.yanked);
 }
 
+#[test]
+fn sparse_index() {
+    let (app, middle) = ::app();
+    let path = ::git::checkout().join("3/f/foo");
+
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    req.with_body(new_req_body(::krate("foo"), "1.0.1", Vec::new()).as_slice());
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    // The file matches the one in the git index byte for byte.
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path("/index/3/f/foo")));
+    let body = response.body.read_to_string().unwrap();
+    assert_eq!(body, File::open(&path).read_to_string().unwrap());
+    let etag = response.headers["ETag"][0].clone();
+    assert!(response.headers.contains_key("Last-Modified"));
+
+    // Clients which have the latest copy get nothing back.
+    req.header("If-None-Match", etag.as_slice());
+    let response = t_resp!(middle.call(&mut req));
+    assert_eq!(response.status.0, 304);
+
+    // Yanking a version changes the file.
+    ok_resp!(middle.call(req.with_method(Method::Delete)
+                            .with_path("/api/v1/crates/foo/1.0.0/yank")));
+    ::run_jobs(&mut req);
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path("/index/3/f/foo")));
+    assert!(response.headers["ETag"][0] != etag);
+    let body = response.body.read_to_string().unwrap();
+    assert_eq!(body, File::open(&path).read_to_string().unwrap());
+
+    // Only the canonical path of a crate is served.
+    let response = t_resp!(middle.call(req.with_path("/index/3/f/FOO")));
+    assert_eq!(response.status.0, 404);
+    let response = t_resp!(middle.call(req.with_path("/index/fo/o/foo")));
+    assert_eq!(response.status.0, 404);
+    let response = t_resp!(middle.call(req.with_path("/index/3/b/bar")));
+    assert_eq!(response.status.0, 404);
+}
+
+#[test]
+fn index_auth_required() {
+    let mut config = ::config();
+    config.index_auth_required = true;
+    let (app, middle) = ::app_with(config);
+
+    let mut req = new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+    let token = ::mock_token(&mut req, "index", &[], None);
+    ::logout(&mut req);
+
+    // Clients are told they need a token, so they can't get anywhere without
+    // one, over either HTTP or git.
+    let response = t_resp!(middle.call(req.with_method(Method::Get)
+                                          .with_path("/index/3/f/foo")));
+    assert_eq!(response.status.0, 403);
+    req.with_query("service=git-upload-pack");
+    let response = t_resp!(middle.call(req.with_path("/git/index/info/refs")));
+    assert_eq!(response.status.0, 403);
+
+    req.header("Authorization", token.as_slice());
+    ok_resp!(middle.call(req.with_path("/index/3/f/foo")));
+    ok_resp!(middle.call(req.with_path("/git/index/info/refs")));
+}
+
 #[test]
 fn yank_not_owner() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Delete, "/api/v1/crates/foo/1.0.0/yank");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
@@ -694,7 +1599,7 @@ fn yank_not_owner() {
 
 #[test]
 fn bad_keywords() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     {
         let mut krate = ::krate("foo");
         krate.keywords.push("super-long-keyword-name-oh-no".to_string());
@@ -731,7 +1636,7 @@ fn bad_keywords() {
 
 #[test]
 fn reverse_dependencies() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
 
     let v100 = semver::Version::parse("1.0.0").unwrap();
     let v110 = semver::Version::parse("1.1.0").unwrap();
@@ -762,7 +1667,7 @@ fn reverse_dependencies() {
 
 #[test]
 fn author_license_and_description_required() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     ::user("foo");
 
     let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
@@ -807,3 +1712,28 @@ fn author_license_and_description_required() {
             !json.errors[0].detail.as_slice().contains("license"),
             "{:?}", json.errors);
 }
+
+#[test]
+fn new_krate_license_expressions() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
+    ::mock_user(&mut req, ::user("foo"));
+
+    let mut krate = new_crate("foo", "1.0.0");
+    krate.license = Some("mit/Apache-2.0".to_string());
+    req.with_body(new_crate_to_body(&krate).as_slice());
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: GoodCrate = ::json(&mut response);
+    assert_eq!(json.krate.license.unwrap(), "MIT OR Apache-2.0");
+
+    let mut krate = new_crate("foo", "1.0.1");
+    krate.license = Some("MIT OR Apache-2.0 OR Bogus-1.0".to_string());
+    req.with_body(new_crate_to_body(&krate).as_slice());
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice()
+                .contains("unknown license `Bogus-1.0`"),
+            "{:?}", json.errors);
+    assert!(json.errors[0].detail.as_slice()
+                .contains("\n                         ^^^^^^^^^"),
+            "{:?}", json.errors);
+}
@@ -0,0 +1,109 @@
+use std::iter::repeat;
+
+use cargo_registry::render::{markdown_to_html, readme_to_html};
+
+fn render(s: &str) -> String {
+    markdown_to_html(s, None)
+}
+
+#[test]
+fn blocks() {
+    assert_eq!(render("# Title #\n\nSome *text*\nand more.\n\n\
+                       Other\n-----\n\n---\n"),
+               "<h1>Title</h1>\n<p>Some <em>text</em>\nand more.</p>\n\
+                <h2>Other</h2>\n<hr>\n");
+    assert_eq!(render("* a\n* b\n  c\n\n1. x\n\n2. y\n"),
+               "<ul>\n<li>a</li>\n<li>b\nc</li>\n</ul>\n\
+                <ol>\n<li><p>x</p>\n</li>\n<li><p>y</p>\n</li>\n</ol>\n");
+    assert_eq!(render("> quoted\n\n```rust\nlet x = \"<\";\n```\n\n    \
+                       indented\n"),
+               "<blockquote>\n<p>quoted</p>\n</blockquote>\n\
+                <pre><code class=\"language-rust\">let x = &quot;&lt;&quot;;\n\
+                </code></pre>\n<pre><code>indented\n</code></pre>\n");
+}
+
+#[test]
+fn deep_nesting() {
+    // Quotes and lists only go so deep, after which it's all text.
+    let quotes = repeat(">").take(100_000).collect::<String>();
+    let html = render(format!("{} x", quotes).as_slice());
+    assert_eq!(html.as_slice().split_str("<blockquote>").count(), 34);
+    assert!(html.as_slice().contains("&gt;&gt; x"));
+
+    let lists = repeat("1. ").take(100_000).collect::<String>();
+    let html = render(format!("{}x", lists).as_slice());
+    assert_eq!(html.as_slice().split_str("<ol>").count(), 34);
+}
+
+#[test]
+fn inlines() {
+    assert_eq!(render("**a** `b*c*` \\*d\\* snake_case_name"),
+               "<p><strong>a</strong> <code>b*c*</code> *d* \
+                snake_case_name</p>\n");
+    assert_eq!(render("[a](http://x.com \"t\") ![b](http://x.com/b.png) \
+                       [c][d]\n\n[d]: https://d.com"),
+               "<p><a href=\"http://x.com\" title=\"t\" rel=\"nofollow\">a</a> \
+                <img src=\"http://x.com/b.png\" alt=\"b\"> \
+                <a href=\"https://d.com\" rel=\"nofollow\">c</a></p>\n");
+    assert_eq!(render("<http://x.com> &copy; a & b"),
+               "<p><a href=\"http://x.com\" rel=\"nofollow\">http://x.com</a> \
+                &copy; a &amp; b</p>\n");
+}
+
+#[test]
+fn raw_html() {
+    assert_eq!(render("<div align=\"center\" class=\"x\">\n\
+                       <img src=\"a.png\" width=100>\n</div>\n\n\
+                       Some <kbd>keys</kbd> and <sup>1</sup>"),
+               "<div align=\"center\">\n<img src=\"a.png\" width=\"100\">\n\
+                </div>\n<p>Some <kbd>keys</kbd> and <sup>1</sup></p>\n");
+    assert_eq!(render("<details><summary>More</summary>\n\nHidden\n"),
+               "<details><summary>More</summary>\n<p>Hidden</p>\n\
+                </details>");
+}
+
+#[test]
+fn sanitized() {
+    assert_eq!(render("<script>alert(1)</script> <style>p {}</style>"),
+               " \n");
+    assert_eq!(render("a <b onclick=\"alert(1)\" style=\"x\">b</b> \
+                       <iframe src=\"http://x.com\"></iframe>c"),
+               "<p>a <b>b</b> c</p>\n");
+    assert_eq!(render("[a](javascript:alert(1)) ![b](JavaScript:alert(1)) \
+                       <javascript:alert(1)>"),
+               "<p><a rel=\"nofollow\">a</a> <img alt=\"b\"> \
+                <a rel=\"nofollow\">javascript:alert(1)</a></p>\n");
+    assert_eq!(render("<a href=\"jav&#x09;ascript:x\">a</a> \
+                       <a href=\"&#106;avascript:x\">b</a> \
+                       <a href=\" javascript:x\">c</a>"),
+               "<p><a rel=\"nofollow\">a</a> <a rel=\"nofollow\">b</a> \
+                <a rel=\"nofollow\">c</a></p>\n");
+    assert_eq!(render("<em>unclosed <b>tags</em> </p></div>"),
+               "<p><em>unclosed <b>tags</b></em> </p>\n");
+}
+
+#[test]
+fn relative_links() {
+    let repo = Some("https://github.com/foo/bar.git");
+    assert_eq!(markdown_to_html("[a](docs/a.md) ![b](./b.png) [c](#c) \
+                                 [d](/d)", repo),
+               "<p><a href=\"https://github.com/foo/bar/blob/master/docs/a.md\" \
+                rel=\"nofollow\">a</a> \
+                <img src=\"https://raw.githubusercontent.com/foo/bar/master/b.png\" \
+                alt=\"b\"> <a href=\"#c\" rel=\"nofollow\">c</a> \
+                <a href=\"https://github.com/foo/bar/blob/master/d\" \
+                rel=\"nofollow\">d</a></p>\n");
+    assert_eq!(markdown_to_html("[a](a.md)", Some("https://example.com/bar/")),
+               "<p><a href=\"https://example.com/bar/a.md\" \
+                rel=\"nofollow\">a</a></p>\n");
+    assert_eq!(markdown_to_html("[a](a.md)", Some("javascript:x")),
+               "<p><a href=\"a.md\" rel=\"nofollow\">a</a></p>\n");
+}
+
+#[test]
+fn plain_text_readmes() {
+    assert_eq!(readme_to_html("README", "a <b>\n", None),
+               "<pre>a &lt;b&gt;\n</pre>\n");
+    assert_eq!(readme_to_html("docs/README.MD", "*a*", None),
+               "<p><em>a</em></p>\n");
+}
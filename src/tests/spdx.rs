@@ -0,0 +1,113 @@
+use std::iter::repeat;
+
+use cargo_registry::spdx;
+
+fn canonical(s: &str) -> String {
+    match spdx::parse(s) {
+        Ok(expr) => expr.to_string(),
+        Err(e) => panic!("failed to parse `{}`: {}", s, e),
+    }
+}
+
+fn error(s: &str) -> String {
+    match spdx::parse(s) {
+        Ok(expr) => panic!("`{}` parsed as `{}`", s, expr),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn canonical_forms() {
+    assert_eq!(canonical("MIT"), "MIT");
+    assert_eq!(canonical("mit"), "MIT");
+    assert_eq!(canonical("MIT/Apache-2.0"), "MIT OR Apache-2.0");
+    assert_eq!(canonical("MIT / Apache-2.0/BSD-3-Clause"),
+               "MIT OR Apache-2.0 OR BSD-3-Clause");
+    assert_eq!(canonical("mit or apache-2.0"), "MIT OR Apache-2.0");
+    assert_eq!(canonical("(MIT AND BSD-3-Clause)"), "MIT AND BSD-3-Clause");
+    assert_eq!(canonical("((MIT OR Zlib)) OR (Apache-2.0)"),
+               "MIT OR Zlib OR Apache-2.0");
+    assert_eq!(canonical("MIT AND Zlib OR Apache-2.0"),
+               "MIT AND Zlib OR Apache-2.0");
+    assert_eq!(canonical("MIT AND (Zlib OR Apache-2.0)"),
+               "MIT AND (Zlib OR Apache-2.0)");
+    assert_eq!(canonical("GPL-2.0+ with classpath-exception-2.0"),
+               "GPL-2.0+ WITH Classpath-exception-2.0");
+}
+
+#[test]
+fn licenses() {
+    let expr = spdx::parse("MIT AND (Zlib OR GPL-2.0+)").unwrap();
+    assert_eq!(expr.licenses(), vec!["MIT", "Zlib", "GPL-2.0"]);
+}
+
+#[test]
+fn allows() {
+    let expr = spdx::parse("MIT OR (Apache-2.0 AND Zlib)").unwrap();
+    assert!(expr.allows("MIT"));
+    assert!(!expr.allows("Apache-2.0"));
+    assert!(!expr.allows("Zlib"));
+    assert!(!expr.allows("BSD-3-Clause"));
+    assert!(spdx::parse("GPL-2.0+ WITH Classpath-exception-2.0").unwrap()
+                .allows("GPL-2.0"));
+}
+
+#[test]
+fn errors() {
+    let e = error("MIT OR Bogus");
+    assert!(e.starts_with("unknown license `Bogus`"), "{}", e);
+    assert!(e.ends_with("\n\n    MIT OR Bogus\n           ^^^^^"), "{}", e);
+
+    let e = error("MIT OR OR Zlib");
+    assert!(e.starts_with("expected a license, found `OR`"), "{}", e);
+    assert!(e.ends_with("\n           ^^"), "{}", e);
+
+    let e = error("MIT Zlib");
+    assert!(e.starts_with("expected `AND`, `OR` or the end of the \
+                           expression, found `Zlib`"), "{}", e);
+
+    let e = error("(MIT OR Zlib");
+    assert!(e.starts_with("unclosed `(`"), "{}", e);
+    assert!(e.ends_with("\n    ^"), "{}", e);
+
+    let e = error("MIT)");
+    assert!(e.starts_with("unmatched `)`"), "{}", e);
+
+    let e = error("MIT AND");
+    assert!(e.starts_with("expected a license, found the end of the \
+                           expression"), "{}", e);
+    assert!(e.ends_with("\n           ^"), "{}", e);
+
+    let e = error("GPL-2.0 WITH Bogus-exception");
+    assert!(e.starts_with("unknown license exception `Bogus-exception`"),
+            "{}", e);
+
+    let e = error("(MIT) WITH Classpath-exception-2.0");
+    assert!(e.starts_with("expected `AND`, `OR` or the end of the \
+                           expression, found `WITH`"), "{}", e);
+
+    let e = error("MIT & Zlib");
+    assert!(e.starts_with("expected `AND`, `OR` or the end of the \
+                           expression, found `&`"), "{}", e);
+
+    let e = error("MIT OR \u{e9}");
+    assert!(e.ends_with("\n           ^"), "{}", e);
+}
+
+#[test]
+fn nesting() {
+    let nested = |depth: usize| {
+        format!("{}MIT{}", repeat('(').take(depth).collect::<String>(),
+                repeat(')').take(depth).collect::<String>())
+    };
+    assert_eq!(canonical(nested(32).as_slice()), "MIT");
+
+    let e = error(nested(33).as_slice());
+    assert!(e.starts_with("parentheses can only be nested 32 deep"), "{}", e);
+    assert!(e.ends_with(format!("\n    {}^", repeat(' ').take(32)
+                                                .collect::<String>())
+                            .as_slice()), "{}", e);
+
+    let e = error(nested(100000).as_slice());
+    assert!(e.starts_with("parentheses can only be nested 32 deep"), "{}", e);
+}
@@ -0,0 +1,183 @@
+use std::old_io::{BufReader, Command, File, MemWriter};
+use std::iter::repeat;
+use std::str;
+
+use civet;
+use conduit::{Handler, Method};
+use flate2::CompressionLevel;
+use flate2::writer::GzEncoder;
+use git2;
+use openssl::crypto::hash::{self, Type};
+
+use cargo_registry::git::replace_files;
+
+fn pkt(line: &str) -> String {
+    format!("{:04x}{}", line.len() + 4, line)
+}
+
+// Checks the header and checksum of `pack`, and returns how many objects it
+// says are in it.
+fn pack_objects(pack: &[u8]) -> u32 {
+    let (data, checksum) = pack.split_at(pack.len() - 20);
+    assert_eq!(hash::hash(Type::SHA1, data).as_slice(), checksum);
+    assert_eq!(str::from_utf8(&data[..4]).unwrap(), "PACK");
+    let mut rdr = BufReader::new(&data[4..]);
+    assert_eq!(rdr.read_be_u32().unwrap(), 2);
+    rdr.read_be_u32().unwrap()
+}
+
+#[test]
+fn advertise_refs() {
+    let (app, middle) = ::app();
+    let head = {
+        let repo = app.git_repo.lock().unwrap();
+        repo.head().unwrap().target().unwrap()
+    };
+
+    let mut req = ::req(app.clone(), Method::Get, "/git/index/info/refs");
+    req.with_query("service=git-upload-pack");
+    let mut response = ok_resp!(middle.call(&mut req));
+    assert_eq!(response.headers["Content-Type"],
+               vec!["application/x-git-upload-pack-advertisement".to_string()]);
+    let body = response.body.read_to_string().unwrap();
+    let refs = format!("{} HEAD\0\n", head);
+    let branches = format!("{} refs/heads/master\n", head);
+    let expected = format!("{}0000{}{}0000", pkt("# service=git-upload-pack\n"),
+                           pkt(refs.as_slice()), pkt(branches.as_slice()));
+    assert_eq!(body, expected);
+
+    // The refs come from the checkout as it is, which is left alone even if
+    // it's behind the index.
+    let second = {
+        let repo = app.git_repo.lock().unwrap();
+        let files = vec![("3/f/foo".to_string(), Some("foo\n".to_string()))];
+        t!(replace_files(&*repo, "add foo", files.as_slice()));
+        let second = repo.head().unwrap().target().unwrap();
+        let obj = t!(repo.find_object(head, None));
+        t!(repo.reset(&obj, git2::ResetType::Hard, None, None, None));
+        second
+    };
+    let mut response = ok_resp!(middle.call(&mut req));
+    let body = response.body.read_to_string().unwrap();
+    assert_eq!(body, expected);
+    {
+        let repo = app.git_repo.lock().unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), head);
+        assert!(repo.find_commit(second).is_ok());
+    }
+
+    // The dumb protocol isn't supported.
+    let mut response = ok_resp!(middle.call(req.with_query("")));
+    ::json::<::Bad>(&mut response);
+}
+
+#[test]
+fn fetch() {
+    let (app, middle) = ::app();
+    let (first, second) = {
+        let repo = app.git_repo.lock().unwrap();
+        let first = repo.head().unwrap().target().unwrap();
+        let files = vec![("3/f/foo".to_string(), Some("foo\n".to_string()))];
+        t!(replace_files(&*repo, "add foo", files.as_slice()));
+        (first, repo.head().unwrap().target().unwrap())
+    };
+    let mut req = ::req(app, Method::Post, "/git/index/git-upload-pack");
+
+    // A fresh clone gets both commits, the empty tree of the first one, and
+    // the three trees and the blob of the second.
+    let body = format!("{}0000{}", pkt(format!("want {}\n", second).as_slice()),
+                       pkt("done\n"));
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert_eq!(response.headers["Content-Type"],
+               vec!["application/x-git-upload-pack-result".to_string()]);
+    let body = response.body.read_to_end().unwrap();
+    assert_eq!(str::from_utf8(&body[..8]).unwrap(), "0008NAK\n");
+    assert_eq!(pack_objects(&body[8..]), 7);
+
+    // While negotiating, the first commit the client has is acknowledged.
+    let want = pkt(format!("want {}\n", second).as_slice());
+    let have = pkt(format!("have {}\n", first).as_slice());
+    let body = format!("{}0000{}0000", want, have);
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert_eq!(response.body.read_to_string().unwrap(),
+               pkt(format!("ACK {}\n", first).as_slice()));
+
+    // Once it's done, it only gets what's new.
+    let body = format!("{}0000{}{}", want, have, pkt("done\n"));
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    let body = response.body.read_to_end().unwrap();
+    let ack = pkt(format!("ACK {}\n", first).as_slice());
+    assert_eq!(str::from_utf8(&body[..ack.len()]).unwrap(), ack);
+    assert_eq!(pack_objects(&body[ack.len()..]), 5);
+
+    // Only the tips of branches can be fetched.
+    let body = format!("{}0000{}", pkt(format!("want {}\n", first).as_slice()),
+                       pkt("done\n"));
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    ::json::<::Bad>(&mut response);
+
+    // Requests bigger than `max_upload_size`, before or after they're
+    // decompressed, are turned away.
+    let haves = repeat(have.clone()).take(100).collect::<Vec<_>>().concat();
+    let body = format!("{}0000{}{}", want, haves, pkt("done\n"));
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    ::json::<::Bad>(&mut response);
+
+    let mut gz = GzEncoder::new(MemWriter::new(), CompressionLevel::Default);
+    t!(gz.write_all(body.as_bytes()));
+    let gz = t!(gz.finish()).into_inner();
+    assert!(gz.len() < 1000);
+    req.header("Content-Encoding", "gzip");
+    let mut response = ok_resp!(middle.call(req.with_body(gz.as_slice())));
+    ::json::<::Bad>(&mut response);
+}
+
+// Runs `git` with `args` in `cwd`, tracing the packets it exchanges, and
+// returns the trace.
+fn git(cwd: &Path, args: &[&str]) -> String {
+    let output = t!(Command::new("git").args(args).cwd(cwd)
+                           .env("GIT_TRACE_PACKET", "1")
+                           .env("GIT_PROTOCOL", "version=0")
+                           .output());
+    let trace = String::from_utf8_lossy(output.error.as_slice()).into_owned();
+    assert!(output.status.success(), "git {:?} failed:\n{}", args, trace);
+    trace
+}
+
+#[test]
+fn git_clone_and_fetch() {
+    let (app, middle) = ::app();
+    let first = {
+        let repo = app.git_repo.lock().unwrap();
+        repo.head().unwrap().target().unwrap()
+    };
+    let port = 18989;
+    let _server = civet::Server::start(civet::Config {
+        port: port,
+        threads: 1,
+    }, middle);
+    let url = format!("http://127.0.0.1:{}/git/index", port);
+    let clone = ::git::clone();
+
+    // The first ref lists no capabilities, and with nothing in common the
+    // client is sent a NAK before the pack.
+    let root = clone.dir_path();
+    let trace = git(&root, &["clone", url.as_slice(),
+                             clone.as_str().unwrap()]);
+    assert!(trace.contains(format!("{} HEAD\0", first).as_slice()),
+            "{}", trace);
+    assert!(trace.contains("< NAK"), "{}", trace);
+
+    // Whatever's been committed since is advertised, and the commit the
+    // client already has is acknowledged.
+    {
+        let repo = app.git_repo.lock().unwrap();
+        let files = vec![("3/f/foo".to_string(), Some("foo\n".to_string()))];
+        t!(replace_files(&*repo, "add foo", files.as_slice()));
+    }
+    let trace = git(&clone, &["pull", "origin", "master"]);
+    assert!(trace.contains(format!("< ACK {}", first).as_slice()),
+            "{}", trace);
+    let contents = t!(File::open(&clone.join("3/f/foo")).read_to_string());
+    assert_eq!(contents.as_slice(), "foo\n");
+}
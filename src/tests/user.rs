@@ -1,22 +1,24 @@
-use std::error::Error;
+use std::iter::repeat;
+use std::time::Duration;
 
-use conduit::{Handler, Request, Response, Method};
-use conduit_middleware::Middleware;
+use conduit::{Handler, Method};
 use conduit_test::MockRequest;
 
 use cargo_registry::krate::EncodableCrate;
-use cargo_registry::user::{User, EncodableUser};
-use cargo_registry::db::RequestTransaction;
+use cargo_registry::user::{token, User, EncodableUser, ApiToken, Scope};
+use cargo_registry::user::{identity, Identity, Profile};
+use cargo_registry::user::identity::EncodableIdentity;
+use cargo_registry::user::token::EncodableApiToken;
 use cargo_registry::version::EncodableVersion;
 
 #[derive(RustcDecodable)]
 struct AuthResponse { url: String, state: String }
 #[derive(RustcDecodable)]
-struct MeResponse { user: EncodableUser, api_token: String }
+struct MeResponse { user: EncodableUser }
 
 #[test]
 fn auth_gives_a_token() {
-    let (_b, _app, middle) = ::app();
+    let (_app, middle) = ::app();
     let mut req = MockRequest::new(Method::Get, "/authorize_url");
     let mut response = ok_resp!(middle.call(&mut req));
     let json: AuthResponse = ::json(&mut response);
@@ -25,34 +27,192 @@ fn auth_gives_a_token() {
 
 #[test]
 fn access_token_needs_data() {
-    let (_b, _app, middle) = ::app();
+    let (_app, middle) = ::app();
     let mut req = MockRequest::new(Method::Get, "/authorize");
     let mut response = ok_resp!(middle.call(&mut req));
     let json: ::Bad = ::json(&mut response);
     assert!(json.errors[0].detail.as_slice().contains("invalid state"));
 }
 
+#[test]
+fn unknown_provider() {
+    let (_app, middle) = ::app();
+    let mut req = MockRequest::new(Method::Get, "/authorize_url/nope");
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice().contains("unknown identity"),
+            "{:?}", json.errors);
+    let json = bad_resp!(middle.call(req.with_path("/authorize_url/local")));
+    assert!(json.errors[0].detail.as_slice().contains("another site"),
+            "{:?}", json.errors);
+}
+
 #[test]
 fn user_insert() {
-    let (_b, app, _middle) = ::app();
+    let (app, _middle) = ::app();
     let conn = t!(app.database.get());
     let tx = t!(conn.transaction());
 
-    let user = t!(User::find_or_insert(&tx, "foo", None, None, None, "bar", "baz"));
-    assert_eq!(t!(User::find_by_api_token(&tx, "baz")), user);
+    let profile = Profile::new("foo");
+    let user = t!(User::find_or_insert(&tx, "github", &profile));
     assert_eq!(t!(User::find(&tx, user.id)), user);
+    assert_eq!(t!(User::find_or_insert(&tx, "github", &profile)), user);
+
+    // Details the provider doesn't know about are left alone.
+    let named = Profile { name: Some("Foo".to_string()), .. profile.clone() };
+    let user2 = t!(User::find_or_insert(&tx, "github", &named));
+    assert_eq!(user2.id, user.id);
+    assert_eq!(user2.name, Some("Foo".to_string()));
+    let user3 = t!(User::find_or_insert(&tx, "github", &profile));
+    assert_eq!(user3, user2);
+
+    // Someone else can't take the same login with another provider.
+    let err = User::find_or_insert(&tx, "oidc", &profile).err().unwrap();
+    assert!(err.to_string().contains("already taken"), "{}", err);
+    assert!(!t!(User::login_available(&tx, "foo")));
+    assert!(t!(User::login_available(&tx, "bar")));
+
+    // Nor can a login be anything a provider likes.
+    let err = User::find_or_insert(&tx, "oidc", &Profile::new("a b/c"))
+                   .err().unwrap();
+    assert!(err.to_string().contains("isn't a valid login"), "{}", err);
+    assert!(!t!(User::login_available(&tx, "a b/c")));
+}
+
+#[test]
+fn legacy_github_identities() {
+    let (app, _middle) = ::app();
+    let conn = t!(app.database.get());
+    let tx = t!(conn.transaction());
+
+    // An identity from before GitHub's ids were used.
+    let legacy = Profile {
+        uid: "login:foo".to_string(),
+        .. Profile::new("foo")
+    };
+    let user = t!(User::find_or_insert(&tx, identity::GITHUB, &legacy));
+
+    t!(identity::claim_legacy_github(&tx, "1234", "foo"));
+    let github = Profile { uid: "1234".to_string(), .. Profile::new("foo") };
+    assert_eq!(t!(User::find_or_insert(&tx, identity::GITHUB, &github)).id,
+               user.id);
+    assert!(t!(Identity::find(&tx, identity::GITHUB, "login:foo")).is_none());
+
+    // Whoever has the login on GitHub later isn't mistaken for them.
+    t!(identity::claim_legacy_github(&tx, "5678", "foo"));
+    let imposter = Profile { uid: "5678".to_string(), .. Profile::new("foo") };
+    assert!(User::find_or_insert(&tx, identity::GITHUB, &imposter).is_err());
+}
+
+#[test]
+fn signup_needs_a_pending_sign_up() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/signup");
+    let body = r#"{"login":"foo"}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("no sign up"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn linked_identities() {
+    let (app, _middle) = ::app();
+    let conn = t!(app.database.get());
+    let tx = t!(conn.transaction());
+
+    let user = t!(User::find_or_insert(&tx, "github", &Profile::new("foo")));
+    let other = t!(User::find_or_insert(&tx, "github", &Profile::new("bar")));
+    let sso = Profile { uid: "1234".to_string(), .. Profile::new("foo-sso") };
+    let identity = t!(Identity::link(&tx, user.id, "oidc", &sso));
+    assert_eq!(t!(Identity::link(&tx, user.id, "oidc", &sso)).id, identity.id);
+    assert!(Identity::link(&tx, other.id, "oidc", &sso).is_err());
 
-    assert_eq!(t!(User::find_or_insert(&tx, "foo", None, None, None,
-                                       "bar", "api")), user);
-    let user2 = t!(User::find_or_insert(&tx, "foo", None, None, None,
-                                        "baz", "api"));
-    assert!(user != user2);
-    assert_eq!(user2.gh_access_token.as_slice(), "baz");
+    assert_eq!(t!(User::find_or_insert(&tx, "oidc", &sso)).id, user.id);
+    let identities = t!(Identity::for_user(&tx, user.id));
+    assert_eq!(identities.iter().map(|i| i.provider.as_slice())
+                         .collect::<Vec<_>>(), vec!["github", "oidc"]);
+}
+
+#[derive(RustcDecodable)]
+struct IdentityList { identities: Vec<EncodableIdentity> }
+
+#[test]
+fn local_accounts() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/register");
+    let body = r#"{"login":"foo","password":"hunter22"}"#;
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    let user = ::json::<MeResponse>(&mut response).user;
+    assert_eq!(user.login.as_slice(), "foo");
+
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("already taken"),
+            "{:?}", json.errors);
+    let body = r#"{"login":"bar","password":"hunter2"}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("at least"),
+            "{:?}", json.errors);
+
+    ::logout(&mut req);
+    req.with_path("/authorize/local");
+    let body = r#"{"login":"foo","password":"hunter23"}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("invalid login"),
+            "{:?}", json.errors);
+    let body = r#"{"login":"foo","password":"hunter22"}"#;
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert_eq!(::json::<MeResponse>(&mut response).user.id, user.id);
+}
+
+#[test]
+fn local_accounts_can_be_disabled() {
+    let mut config = ::config();
+    config.local_accounts = false;
+    let (_app, middle) = ::app_with(config);
+    let mut req = MockRequest::new(Method::Put, "/register");
+    let body = r#"{"login":"foo","password":"hunter22"}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("unknown identity"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn link_and_unlink_identities() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/register");
+    let user = ::mock_user(&mut req, ::user("foo"));
+
+    // Registering while logged in adds a password to the account.
+    let body = r#"{"login":"foo-local","password":"hunter22"}"#;
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert_eq!(::json::<MeResponse>(&mut response).user.id, user.id);
+    ::logout(&mut req);
+    let mut response = ok_resp!(middle.call(req.with_path("/authorize/local")
+                                               .with_body(body.as_bytes())));
+    let me = ::json::<MeResponse>(&mut response).user;
+    assert_eq!(me.id, user.id);
+    assert_eq!(me.login.as_slice(), "foo");
+
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path("/me/identities")));
+    let list: IdentityList = ::json(&mut response);
+    assert_eq!(list.identities.len(), 2);
+    assert_eq!(list.identities[0].provider.as_slice(), identity::GITHUB);
+    assert_eq!(list.identities[1].provider.as_slice(), identity::LOCAL);
+    assert_eq!(list.identities[1].uid.as_slice(), "foo-local");
+
+    // The last identity can't be unlinked.
+    let path = format!("/me/identities/{}", list.identities[0].id);
+    ok_resp!(middle.call(req.with_method(Method::Delete)
+                            .with_path(path.as_slice())));
+    let path = format!("/me/identities/{}", list.identities[1].id);
+    let json = bad_resp!(middle.call(req.with_path(path.as_slice())));
+    assert!(json.errors[0].detail.as_slice().contains("only way"),
+            "{:?}", json.errors);
 }
 
 #[test]
 fn me() {
-    let (_b, _app, mut middle) = ::app();
+    let (_app, mut middle) = ::app();
     let mut req = MockRequest::new(Method::Get, "/me");
     let response = t_resp!(middle.call(&mut req));
     assert_eq!(response.status.0, 403);
@@ -62,40 +222,185 @@ fn me() {
     let mut response = ok_resp!(middle.call(&mut req));
     let json: MeResponse = ::json(&mut response);
     assert_eq!(json.user.email, user.email);
-    assert_eq!(json.api_token, user.api_token);
 }
 
 #[test]
 fn reset_token() {
-    struct ResetTokenTest;
+    #[derive(RustcDecodable)]
+    struct R { api_token: String }
+
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/me/reset_token");
+    ::mock_user(&mut req, ::user("foo"));
+    let mut response = ok_resp!(middle.call(&mut req));
+    let first = ::json::<R>(&mut response).api_token;
+    let mut response = ok_resp!(middle.call(&mut req));
+    let second = ::json::<R>(&mut response).api_token;
+    assert!(first != second);
 
-    let (_b, _app, mut middle) = ::app();
-    middle.add(ResetTokenTest);
-    let mut req = MockRequest::new(Method::Put, "/me/reset_token");
+    // Only the newest token works.
+    ::logout(&mut req);
+    req.with_method(Method::Get).with_path("/me/updates");
+    req.header("Authorization", first.as_slice());
+    let response = t_resp!(middle.call(&mut req));
+    assert_eq!(response.status.0, 403);
+    req.header("Authorization", second.as_slice());
     ok_resp!(middle.call(&mut req));
+}
 
-    impl Middleware for ResetTokenTest {
-        fn before(&self, req: &mut Request) -> Result<(), Box<Error+Send>> {
-            let user = User::find_or_insert(req.tx().unwrap(), "foo", None,
-                                            None, None, "bar", "baz").unwrap();
-            req.mut_extensions().insert(user);
-            Ok(())
-        }
-
-        fn after(&self, req: &mut Request,
-                 response: Result<Response, Box<Error+Send>>)
-                 -> Result<Response, Box<Error+Send>> {
-            let user = req.extensions().find::<User>().unwrap();
-            let u2 = User::find(req.tx().unwrap(), user.id).unwrap();
-            assert!(u2.api_token != user.api_token);
-            response
-        }
-    }
+#[derive(RustcDecodable)]
+struct NewTokenResponse { api_token: EncodableApiToken, token: String }
+#[derive(RustcDecodable)]
+struct TokenList { api_tokens: Vec<EncodableApiToken> }
+
+#[test]
+fn api_tokens() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/me/tokens");
+    ::mock_user(&mut req, ::user("foo"));
+    let body = r#"{"name":"ci","scopes":["publish-update","yank"],
+                   "crate_pattern":"foo-*","expires_in_days":30}"#;
+    let mut response = ok_resp!(middle.call(req.with_body(body.as_bytes())));
+    let new: NewTokenResponse = ::json(&mut response);
+    assert_eq!(new.api_token.name.as_slice(), "ci");
+    assert_eq!(new.api_token.scopes,
+               vec!["publish-update".to_string(), "yank".to_string()]);
+    assert_eq!(new.api_token.crate_pattern, Some("foo-*".to_string()));
+    assert!(new.api_token.expires_at.is_some());
+    assert!(new.token.as_slice().starts_with(new.api_token.prefix.as_slice()));
+
+    // Names are unique for each user.
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("already a token"),
+            "{:?}", json.errors);
+    let body = r#"{"name":"other","scopes":["delete-everything"]}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("unknown scope"),
+            "{:?}", json.errors);
+    let body = r#"{"name":"other","scopes":["yank"],
+                   "expires_in_days":9223372036854775807}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.as_slice().contains("can't expire more"),
+            "{:?}", json.errors);
+
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)));
+    let list: TokenList = ::json(&mut response);
+    assert_eq!(list.api_tokens.len(), 1);
+    assert_eq!(list.api_tokens[0].id, new.api_token.id);
+    assert!(list.api_tokens[0].last_used_at.is_none());
+
+    // Revoking it a second time doesn't work.
+    let path = format!("/me/tokens/{}", new.api_token.id);
+    ok_resp!(middle.call(req.with_method(Method::Delete)
+                            .with_path(path.as_slice())));
+    bad_resp!(middle.call(&mut req));
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path("/me/tokens")));
+    let list: TokenList = ::json(&mut response);
+    assert_eq!(list.api_tokens.len(), 0);
+}
+
+#[test]
+fn api_token_crate_patterns() {
+    let (app, _middle) = ::app();
+    let conn = t!(app.database.get());
+    let tx = t!(conn.transaction());
+    let user = t!(User::find_or_insert(&tx, "github", &Profile::new("foo")));
+    let token = |pattern: &str| {
+        t!(ApiToken::insert(&tx, user.id, pattern, &[Scope::PublishNew],
+                            Some(pattern), None)).0
+    };
+
+    let prefix = token("foo-*");
+    assert!(prefix.allows(Scope::PublishNew, "foo-bar"));
+    assert!(prefix.allows(Scope::PublishNew, "FOO-"));
+    assert!(!prefix.allows(Scope::PublishNew, "foo"));
+    let inner = token("*-*-sys");
+    assert!(inner.allows(Scope::PublishNew, "a-b-c-sys"));
+    assert!(!inner.allows(Scope::PublishNew, "a-sys"));
+
+    // Lots of stars don't take forever to not match.
+    let stars = token(repeat("a*").take(50).collect::<String>().as_slice());
+    let name = repeat("a").take(49).collect::<String>();
+    assert!(!stars.allows(Scope::PublishNew, name.as_slice()));
+}
+
+#[test]
+fn api_tokens_cant_manage_the_account() {
+    let (app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/me");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = ::mock_token(&mut req, "ci", Scope::all(), None);
+    ::logout(&mut req);
+    req.header("Authorization", token.as_slice());
+
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.as_slice().contains("manage the account"),
+            "{:?}", json.errors);
+    bad_resp!(middle.call(req.with_path("/me/tokens")));
+    bad_resp!(middle.call(req.with_method(Method::Put)
+                             .with_path("/me/reset_token")));
+
+    // Once logged in, the token shows up as having been used.
+    ::logout(&mut req);
+    req.header("Authorization", "");
+    ::mock_user(&mut req, ::user("foo"));
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path("/me/tokens")));
+    let list: TokenList = ::json(&mut response);
+    assert!(list.api_tokens[0].last_used_at.is_some());
+}
+
+#[test]
+fn expired_api_tokens() {
+    let (app, _middle) = ::app();
+    let conn = t!(app.database.get());
+    let tx = t!(conn.transaction());
+
+    let user = t!(User::find_or_insert(&tx, "github", &Profile::new("foo")));
+    let past = ::time::now().to_timespec() + Duration::days(-1);
+    let future = ::time::now().to_timespec() + Duration::days(1);
+    let (_, old) = t!(ApiToken::insert(&tx, user.id, "old", Scope::all(),
+                                       None, Some(past)));
+    let (new, token) = t!(ApiToken::insert(&tx, user.id, "new", Scope::all(),
+                                           None, Some(future)));
+    assert!(ApiToken::find_by_token(&tx, old.as_slice()).is_err());
+    assert_eq!(t!(ApiToken::find_by_token(&tx, token.as_slice())).id, new.id);
+}
+
+#[test]
+fn api_tokens_are_hashed() {
+    let (app, _middle) = ::app();
+    let conn = t!(app.database.get());
+    let tx = t!(conn.transaction());
+
+    let user = t!(User::find_or_insert(&tx, "github", &Profile::new("foo")));
+    let (api_token, secret) = t!(ApiToken::insert(&tx, user.id, "ci",
+                                                  Scope::all(), None, None));
+    assert_eq!(api_token.prefix.as_slice(), &secret[..token::PREFIX_LEN]);
+
+    // Only the prefix of the token is in the database, along with a hash.
+    let stmt = t!(tx.prepare("SELECT * FROM api_tokens WHERE id = $1"));
+    let row = t!(stmt.query(&[&api_token.id])).next().unwrap();
+    let hash: String = row.get("token_hash");
+    let salt: String = row.get("salt");
+    assert_eq!(hash.len(), 64);
+    assert!(hash != secret);
+    assert!(salt.len() > 0);
+
+    // A token with the same prefix isn't good enough.
+    assert_eq!(t!(ApiToken::find_by_token(&tx, secret.as_slice())).id,
+               api_token.id);
+    let last = if secret.ends_with("x") {"y"} else {"x"};
+    let wrong = format!("{}{}", &secret[..secret.len() - 1], last);
+    assert!(ApiToken::find_by_token(&tx, wrong.as_slice()).is_err());
+    let prefix = &secret[..token::PREFIX_LEN];
+    assert!(ApiToken::find_by_token(&tx, prefix).is_err());
 }
 
 #[test]
 fn my_packages() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates");
     let u = ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
@@ -117,7 +422,7 @@ fn following() {
     }
     #[derive(RustcDecodable)] struct Meta { more: bool }
 
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
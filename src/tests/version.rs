@@ -18,7 +18,7 @@ fn sv(s: &str) -> semver::Version {
 
 #[test]
 fn index() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/versions");
     let mut response = ok_resp!(middle.call(&mut req));
     let json: VersionList = ::json(&mut response);
@@ -30,8 +30,8 @@ fn index() {
         let req = &mut req as &mut Request;
         let tx = req.tx().unwrap();
         let m = HashMap::new();
-        let v1 = Version::insert(tx, c.id, &sv("2.0.0"), &m, &[]).unwrap();
-        let v2 = Version::insert(tx, c.id, &sv("2.0.1"), &m, &[]).unwrap();
+        let v1 = Version::insert(tx, c.id, &sv("2.0.0"), &m, &[], None).unwrap();
+        let v2 = Version::insert(tx, c.id, &sv("2.0.1"), &m, &[], None).unwrap();
         (v1, v2)
     };
     req.with_query(&format!("ids[]={}&ids[]={}", v1.id, v2.id)[]);
@@ -42,14 +42,15 @@ fn index() {
 
 #[test]
 fn show() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/versions");
     let v = {
         ::mock_user(&mut req, ::user("foo"));
         let (krate, _) = ::mock_crate(&mut req, ::krate("foo"));
         let req = &mut req as &mut Request;
         let tx = req.tx().unwrap();
-        Version::insert(tx, krate.id, &sv("2.0.0"), &HashMap::new(), &[]).unwrap()
+        Version::insert(tx, krate.id, &sv("2.0.0"), &HashMap::new(), &[],
+                        None).unwrap()
     };
     req.with_path(format!("/api/v1/versions/{}", v.id).as_slice());
     let mut response = ok_resp!(middle.call(&mut req));
@@ -59,7 +60,7 @@ fn show() {
 
 #[test]
 fn authors() {
-    let (_b, app, middle) = ::app();
+    let (app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/authors");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
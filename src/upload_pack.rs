@@ -0,0 +1,303 @@
+//! The server side of git's smart HTTP protocol, so the registry can serve
+//! its index to cargo itself.
+//!
+//! Only fetching is supported, which takes two requests. The client first
+//! asks for `info/refs?service=git-upload-pack` to find out which refs there
+//! are, and then `POST`s the commits it wants and those it already has to
+//! `git-upload-pack`. Once it says it's done, it gets back a pack of all the
+//! objects it's missing, which is built by libgit2 and streamed out as it
+//! goes.
+//!
+//! The refs are those already in the index checkout. Serving them never
+//! changes the checkout, which the worker may be in the middle of committing
+//! to, so they're as recent as the last update the worker made.
+
+use std::collections::{HashMap, HashSet};
+use std::old_io::{ChanReader, ChanWriter, MemReader};
+use std::old_io::util::LimitReader;
+use std::str;
+use std::sync::mpsc::channel;
+use std::thread::Thread;
+use flate2::reader::GzDecoder;
+use git2;
+use rustc_serialize::hex::FromHex;
+
+use conduit::{Request, Response, Method};
+use conduit_router::RequestParams;
+
+use app::RequestApp;
+use index;
+use util::{RequestUtils, CargoResult, CargoError, ChainError, human};
+use util::errors::NotFound;
+
+pub fn serve(req: &mut Request) -> CargoResult<Response> {
+    try!(index::authorize(req));
+    let path = req.params()["path"].clone();
+    let app = req.app().clone();
+    match (req.method(), path.as_slice()) {
+        (Method::Get, "info/refs") => {
+            let repo = try!(git2::Repository::open(&app.git_repo_checkout));
+            advertise(req, &repo)
+        }
+        (Method::Post, "git-upload-pack") => {
+            let repo = try!(git2::Repository::open(&app.git_repo_checkout));
+            upload_pack(req, &repo)
+        }
+        _ => Err(Box::new(NotFound) as Box<CargoError>),
+    }
+}
+
+fn advertise(req: &mut Request, repo: &git2::Repository)
+             -> CargoResult<Response> {
+    let query = req.query();
+    if query.get("service").map(|s| s.as_slice()) != Some("git-upload-pack") {
+        return Err(human("only fetching over git's smart HTTP protocol is \
+                          supported"))
+    }
+
+    let mut body = Vec::new();
+    pkt_line(&mut body, "# service=git-upload-pack\n");
+    flush_pkt(&mut body);
+    for (i, &(ref name, id)) in try!(refs(repo)).iter().enumerate() {
+        // The first ref also lists the capabilities of the server, of which
+        // there aren't any.
+        if i == 0 {
+            pkt_line(&mut body, format!("{} {}\0\n", id, name).as_slice());
+        } else {
+            pkt_line(&mut body, format!("{} {}\n", id, name).as_slice());
+        }
+    }
+    flush_pkt(&mut body);
+    let len = body.len();
+    Ok(response("application/x-git-upload-pack-advertisement",
+                Box::new(MemReader::new(body)), Some(len)))
+}
+
+// The refs a client can fetch, `HEAD` first and then every branch.
+fn refs(repo: &git2::Repository) -> CargoResult<Vec<(String, git2::Oid)>> {
+    let head = try!(try!(repo.head()).target().chain_error(|| {
+        human("the index has no commits")
+    }));
+    let mut branches = Vec::new();
+    for reference in try!(repo.references()) {
+        let name = match reference.name() {
+            Some(name) if name.starts_with("refs/heads/") => name.to_string(),
+            _ => continue,
+        };
+        if let Some(id) = reference.target() {
+            branches.push((name, id));
+        }
+    }
+    branches.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut ret = vec![("HEAD".to_string(), head)];
+    ret.extend(branches.into_iter());
+    Ok(ret)
+}
+
+fn upload_pack(req: &mut Request, repo: &git2::Repository)
+               -> CargoResult<Response> {
+    let gzip = req.headers().find("Content-Encoding").map(|h| {
+        h.iter().any(|h| *h == "gzip")
+    }).unwrap_or(false);
+    // Anyone can make this request, so neither what's sent nor what it
+    // inflates to can be allowed to grow without bound.
+    let max = req.app().config.max_upload_size;
+    let data = try!(read_at_most(req.body(), max));
+    let data = if gzip {
+        try!(read_at_most(GzDecoder::new(MemReader::new(data)), max))
+    } else {
+        data
+    };
+
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut done = false;
+    for line in try!(pkt_lines(data.as_slice())).into_iter() {
+        let line = match line {
+            Some(line) => line,
+            None => continue,
+        };
+        let line = try!(str::from_utf8(line).map_err(|_| {
+            human("request to git-upload-pack was not valid utf-8")
+        }));
+        let mut parts = line.trim_right_matches('\n').split(' ');
+        match (parts.next().unwrap(), parts.next()) {
+            ("want", Some(id)) => wants.push(try!(oid(id))),
+            ("have", Some(id)) => haves.push(try!(oid(id))),
+            ("done", None) => done = true,
+            ("shallow", _) | ("deepen", _) => {
+                return Err(human("shallow fetches of the index are not \
+                                  supported"))
+            }
+            _ => return Err(human(format!("unexpected line `{}` in request \
+                                           to git-upload-pack", line))),
+        }
+    }
+
+    // Only what the refs point at can be asked for.
+    let tips = try!(refs(repo)).into_iter().map(|(_, id)| id)
+                               .collect::<HashSet<_>>();
+    if wants.len() == 0 {
+        return Err(human("request to git-upload-pack didn't want anything"))
+    }
+    if let Some(id) = wants.iter().find(|id| !tips.contains(*id)) {
+        return Err(human(format!("`{}` is not the tip of a branch", id)))
+    }
+    let common = haves.into_iter().filter(|id| {
+        repo.find_commit(*id).is_ok()
+    }).collect::<Vec<_>>();
+
+    // Without the multi_ack capability, the first commit in common is
+    // acknowledged, and otherwise there's a NAK. Until the client says it's
+    // done, it's only negotiating and doesn't get a pack yet.
+    let mut body = Vec::new();
+    match common.first() {
+        Some(id) => pkt_line(&mut body, format!("ACK {}\n", id).as_slice()),
+        None => pkt_line(&mut body, "NAK\n"),
+    }
+    if !done {
+        let len = body.len();
+        return Ok(response("application/x-git-upload-pack-result",
+                           Box::new(MemReader::new(body)), Some(len)))
+    }
+
+    // The pack follows straight after, and is written out from a thread of
+    // its own as it's built.
+    let objects = try!(missing_objects(repo, wants.as_slice(),
+                                       common.as_slice()));
+    let path = repo.path().clone();
+    let (tx, rx) = channel();
+    let mut writer = ChanWriter::new(tx);
+    try!(writer.write_all(body.as_slice()));
+    Thread::spawn(move|| {
+        if let Err(e) = pack(&path, objects.as_slice(), &mut writer) {
+            error!("failed to send a pack of the index: {}", e);
+        }
+    });
+    Ok(response("application/x-git-upload-pack-result",
+                Box::new(ChanReader::new(rx)), None))
+}
+
+fn read_at_most<R: Reader>(r: R, max: usize) -> CargoResult<Vec<u8>> {
+    let data = try!(LimitReader::new(r, max as u64 + 1).read_to_end());
+    if data.len() > max {
+        return Err(human(format!("request to git-upload-pack is larger than \
+                                  the maximum of {} bytes", max)))
+    }
+    Ok(data)
+}
+
+fn oid(s: &str) -> CargoResult<git2::Oid> {
+    git2::Oid::from_str(s).map_err(|_| {
+        human(format!("`{}` is not an object id", s))
+    })
+}
+
+// The objects reachable from `wants` which the client doesn't have. Whatever
+// is in the trees of the commits it has is left out, but anything older than
+// those could be sent again, which doesn't do any harm.
+fn missing_objects(repo: &git2::Repository, wants: &[git2::Oid],
+                   common: &[git2::Oid]) -> CargoResult<Vec<git2::Oid>> {
+    let mut seen = HashSet::new();
+    for id in common.iter() {
+        let commit = try!(repo.find_commit(*id));
+        try!(add_tree(repo, commit.tree_id(), &mut seen, &mut Vec::new()));
+    }
+
+    let mut walk = try!(repo.revwalk());
+    for id in wants.iter() { try!(walk.push(*id)); }
+    for id in common.iter() { try!(walk.hide(*id)); }
+    let mut objects = Vec::new();
+    for id in walk {
+        let commit = try!(repo.find_commit(id));
+        objects.push(id);
+        try!(add_tree(repo, commit.tree_id(), &mut seen, &mut objects));
+    }
+    Ok(objects)
+}
+
+// Adds the tree `id` and everything in it to `objects`, apart from what's
+// already been `seen`.
+fn add_tree(repo: &git2::Repository, id: git2::Oid,
+            seen: &mut HashSet<git2::Oid>,
+            objects: &mut Vec<git2::Oid>) -> CargoResult<()> {
+    if !seen.insert(id) { return Ok(()) }
+    objects.push(id);
+    let tree = try!(repo.find_tree(id));
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                try!(add_tree(repo, entry.id(), seen, objects));
+            }
+            Some(git2::ObjectType::Blob) => {
+                if seen.insert(entry.id()) { objects.push(entry.id()) }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Builds a pack file out of `objects` in the repository at `path`, writing it
+// to `out` a chunk at a time. Writing stops early if the client goes away.
+fn pack(path: &Path, objects: &[git2::Oid],
+        out: &mut Writer) -> CargoResult<()> {
+    let repo = try!(git2::Repository::open(path));
+    let mut builder = try!(repo.packbuilder());
+    for id in objects.iter() {
+        try!(builder.insert_object(*id, None));
+    }
+    try!(builder.foreach(|chunk| out.write_all(chunk).is_ok()));
+    Ok(())
+}
+
+// Splits a stream of pkt-lines into their contents, with `None` for each
+// flush-pkt.
+fn pkt_lines(mut data: &[u8]) -> CargoResult<Vec<Option<&[u8]>>> {
+    let mut ret = Vec::new();
+    while data.len() > 0 {
+        let len = if data.len() < 4 {None} else {
+            str::from_utf8(&data[..4]).ok().and_then(|len| {
+                len.from_hex().ok()
+            }).map(|len| ((len[0] as usize) << 8) | len[1] as usize)
+        };
+        match len {
+            Some(0) => {
+                ret.push(None);
+                data = &data[4..];
+            }
+            Some(len) if len >= 4 && len <= data.len() => {
+                ret.push(Some(&data[4..len]));
+                data = &data[len..];
+            }
+            _ => return Err(human("invalid pkt-line in request to \
+                                   git-upload-pack")),
+        }
+    }
+    Ok(ret)
+}
+
+fn pkt_line(out: &mut Vec<u8>, line: &str) {
+    out.push_all(format!("{:04x}", line.len() + 4).as_bytes());
+    out.push_all(line.as_bytes());
+}
+
+fn flush_pkt(out: &mut Vec<u8>) {
+    out.push_all(b"0000");
+}
+
+fn response(content_type: &str, body: Box<Reader + Send>,
+            len: Option<usize>) -> Response {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), vec![content_type.to_string()]);
+    if let Some(len) = len {
+        headers.insert("Content-Length".to_string(), vec![len.to_string()]);
+    }
+    headers.insert("Cache-Control".to_string(), vec!["no-cache".to_string()]);
+    Response {
+        status: (200, "OK"),
+        headers: headers,
+        body: body,
+    }
+}
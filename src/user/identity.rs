@@ -0,0 +1,647 @@
+//! The ways there are of logging in, and the identities users have with each
+//! of them.
+//!
+//! Every user has at least one identity, which is how they first logged in,
+//! and can link any number of others. Logging in with any of them logs in as
+//! the same user. Out of the box there are providers for GitHub, for any
+//! other OAuth2 server which speaks enough OpenID Connect to say who someone
+//! is, and for local accounts with a password, for registries with no other
+//! way of telling who anyone is.
+
+use std::str;
+use openssl::crypto::pkcs5;
+use rand::{thread_rng, Rng};
+use rustc_serialize::Decodable;
+use rustc_serialize::hex::ToHex;
+use time::Timespec;
+
+use conduit::{Request, Response};
+use conduit_cookie::RequestSession;
+use conduit_router::RequestParams;
+use curl::http;
+use oauth2::{self, Authorization};
+use pg;
+use pg::types::ToSql;
+use rustc_serialize::json;
+
+use {Model, Config};
+use app::{App, RequestApp};
+use db::{Connection, RequestTransaction};
+use util::{RequestUtils, CargoResult, ChainError, internal, human};
+use super::{User, account_user};
+use super::token::same_hash;
+
+/// The name of the GitHub provider, which is the one used when none is given.
+pub const GITHUB: &'static str = "github";
+
+/// The name of the provider of local accounts.
+pub const LOCAL: &'static str = "local";
+
+/// How short a local account's password can be.
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// What the uids of GitHub identities start with until they're swapped for
+/// GitHub's numeric ids. Users used to be known by their GitHub logins, which
+/// is all there is to go on for them until they next log in.
+pub const LEGACY_GITHUB_PREFIX: &'static str = "login:";
+
+// Where a sign up which is waiting on a login being picked is kept.
+const SIGNUP_KEY: &'static str = "signup";
+
+const PBKDF2_ROUNDS: usize = 10000;
+
+// What a password is hashed with when there's no login to check it against,
+// so that failing to log in takes just as long either way.
+const DUMMY_SALT: &'static str = "0000000000000000";
+
+/// Somewhere people can log in.
+pub trait Provider {
+    /// What the provider is called in URLs and in the database.
+    fn name(&self) -> &str;
+
+    /// Where to send someone to log in, for providers which send them off
+    /// somewhere else and back again. The `state` has to come back with them.
+    fn authorize_url(&self, state: &str) -> Option<String>;
+
+    /// Works out who's logging in, either from the request they were sent
+    /// back to us with or from the credentials it carries.
+    fn authenticate(&self, req: &mut Request) -> CargoResult<Profile>;
+}
+
+/// What a provider says about someone who has logged in with it.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct Profile {
+    /// What the provider knows them by, which never changes.
+    pub uid: String,
+    /// What they'd like to be known by here, if they're new.
+    pub login: String,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub avatar: Option<String>,
+    pub access_token: Option<String>,
+}
+
+impl Profile {
+    pub fn new(login: &str) -> Profile {
+        Profile {
+            uid: login.to_string(),
+            login: login.to_string(),
+            email: None,
+            name: None,
+            avatar: None,
+            access_token: None,
+        }
+    }
+}
+
+/// The settings for a generic OAuth2/OpenID Connect provider.
+#[derive(Clone)]
+pub struct OAuthConfig {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorize_url: String,
+    pub token_url: String,
+    /// The OpenID Connect userinfo endpoint.
+    pub userinfo_url: String,
+    /// Where the provider sends people back to, which has to be the
+    /// frontend's `/authorize/<name>`.
+    pub redirect_url: String,
+}
+
+/// Sets up all the providers `config` asks for.
+pub fn providers(config: &Config) -> Vec<Box<Provider + Send + Sync>> {
+    let mut providers = Vec::new();
+    providers.push(Box::new(GitHub::new(config.gh_client_id.as_slice(),
+                                        config.gh_client_secret.as_slice()))
+                   as Box<Provider + Send + Sync>);
+    if let Some(ref oauth) = config.oidc {
+        assert!(oauth.name.as_slice() != GITHUB &&
+                oauth.name.as_slice() != LOCAL,
+                "the OAuth provider can't be called `{}`", oauth.name);
+        providers.push(Box::new(OAuth::new(oauth))
+                       as Box<Provider + Send + Sync>);
+    }
+    if config.local_accounts {
+        providers.push(Box::new(Local) as Box<Provider + Send + Sync>);
+    }
+    providers
+}
+
+fn provider<'a>(app: &'a App, name: &str)
+                -> CargoResult<&'a (Provider + Send + Sync)> {
+    app.providers.iter().find(|p| p.name() == name).map(|p| &**p)
+       .chain_error(|| {
+        human(format!("unknown identity provider `{}`", name))
+    })
+}
+
+pub struct GitHub {
+    oauth: oauth2::Config,
+}
+
+impl GitHub {
+    pub fn new(client_id: &str, client_secret: &str) -> GitHub {
+        GitHub {
+            oauth: oauth2::Config::new(
+                client_id,
+                client_secret,
+                "https://github.com/login/oauth/authorize",
+                "https://github.com/login/oauth/access_token",
+            ),
+        }
+    }
+}
+
+impl Provider for GitHub {
+    fn name(&self) -> &str { GITHUB }
+
+    fn authorize_url(&self, state: &str) -> Option<String> {
+        Some(self.oauth.authorize_url(state.to_string()).to_string())
+    }
+
+    fn authenticate(&self, req: &mut Request) -> CargoResult<Profile> {
+        let token = try!(exchange(&self.oauth, GITHUB, req));
+        let resp = try!(http::handle().get("https://api.github.com/user")
+                             .header("Accept", "application/vnd.github.v3+json")
+                             .header("User-Agent", "hello!")
+                             .auth_with(&token)
+                             .exec());
+
+        #[derive(RustcDecodable)]
+        struct GithubUser {
+            id: u64,
+            email: Option<String>,
+            name: Option<String>,
+            login: String,
+            avatar_url: Option<String>,
+        }
+        let ghuser: GithubUser = try!(decode(&resp, "github"));
+        let id = ghuser.id.to_string();
+        try!(claim_legacy_github(try!(req.tx()), id.as_slice(),
+                                 ghuser.login.as_slice()));
+        Ok(Profile {
+            uid: id,
+            login: ghuser.login,
+            email: ghuser.email,
+            name: ghuser.name,
+            avatar: ghuser.avatar_url,
+            access_token: Some(token.access_token),
+        })
+    }
+}
+
+/// Gives the GitHub identity which is still keyed on the login `login` the
+/// numeric id `id` instead, unless there's already one with that id.
+pub fn claim_legacy_github(conn: &Connection, id: &str,
+                           login: &str) -> CargoResult<()> {
+    let provider = GITHUB;
+    let legacy = format!("{}{}", LEGACY_GITHUB_PREFIX, login);
+    try!(conn.execute("UPDATE identities SET uid = $1
+                       WHERE provider = $2 AND uid = $3 AND NOT EXISTS (
+                           SELECT 1 FROM identities
+                           WHERE provider = $2 AND uid = $1)",
+                      &[&id as &ToSql, &provider, &legacy]));
+    Ok(())
+}
+
+/// Any OAuth2 server which can say who someone is through an OpenID Connect
+/// userinfo endpoint.
+pub struct OAuth {
+    name: String,
+    oauth: oauth2::Config,
+    userinfo_url: String,
+}
+
+impl OAuth {
+    pub fn new(config: &OAuthConfig) -> OAuth {
+        let mut oauth = oauth2::Config::new(config.client_id.as_slice(),
+                                            config.client_secret.as_slice(),
+                                            config.authorize_url.as_slice(),
+                                            config.token_url.as_slice());
+        oauth.scopes = vec!["openid".to_string(), "profile".to_string(),
+                            "email".to_string()];
+        oauth.redirect_url = config.redirect_url.clone();
+        OAuth {
+            name: config.name.clone(),
+            oauth: oauth,
+            userinfo_url: config.userinfo_url.clone(),
+        }
+    }
+}
+
+impl Provider for OAuth {
+    fn name(&self) -> &str { self.name.as_slice() }
+
+    fn authorize_url(&self, state: &str) -> Option<String> {
+        Some(self.oauth.authorize_url(state.to_string()).to_string())
+    }
+
+    fn authenticate(&self, req: &mut Request) -> CargoResult<Profile> {
+        let token = try!(exchange(&self.oauth, self.name.as_slice(), req));
+        let auth = format!("Bearer {}", token.access_token);
+        let resp = try!(http::handle().get(self.userinfo_url.as_slice())
+                             .header("Accept", "application/json")
+                             .header("Authorization", auth.as_slice())
+                             .exec());
+
+        #[derive(RustcDecodable)]
+        struct Claims {
+            sub: String,
+            preferred_username: Option<String>,
+            email: Option<String>,
+            name: Option<String>,
+            picture: Option<String>,
+        }
+        let claims: Claims = try!(decode(&resp, self.name.as_slice()));
+        let login = claims.preferred_username.clone().unwrap_or_else(|| {
+            claims.sub.clone()
+        });
+        Ok(Profile {
+            uid: claims.sub,
+            login: login,
+            email: claims.email,
+            name: claims.name,
+            avatar: claims.picture,
+            access_token: Some(token.access_token),
+        })
+    }
+}
+
+/// Accounts with a password, which live entirely in our own database.
+pub struct Local;
+
+impl Provider for Local {
+    fn name(&self) -> &str { LOCAL }
+
+    fn authorize_url(&self, _state: &str) -> Option<String> { None }
+
+    fn authenticate(&self, req: &mut Request) -> CargoResult<Profile> {
+        let body = try!(req.body().read_to_string());
+
+        #[derive(RustcDecodable)]
+        struct Credentials { login: String, password: String }
+        let creds: Credentials = try!(json::decode(body.as_slice())
+                                           .map_err(|_| {
+            human("invalid json request")
+        }));
+
+        let tx = try!(req.tx());
+        let stmt = try!(tx.prepare("SELECT salt, password_hash FROM identities
+                                    WHERE provider = $1 AND uid = $2 AND
+                                          password_hash IS NOT NULL"));
+        let mut rows = try!(stmt.query(&[&LOCAL as &ToSql, &creds.login]));
+        let ok = match rows.next() {
+            Some(row) => {
+                let salt: String = row.get("salt");
+                let expected: String = row.get("password_hash");
+                let actual = hash_password(salt.as_slice(),
+                                           creds.password.as_slice());
+                same_hash(actual.as_slice(), expected.as_slice())
+            }
+            None => {
+                hash_password(DUMMY_SALT, creds.password.as_slice());
+                false
+            }
+        };
+        if !ok {
+            return Err(human("invalid login or password"))
+        }
+        Ok(Profile::new(creds.login.as_slice()))
+    }
+}
+
+fn hash_password(salt: &str, password: &str) -> String {
+    pkcs5::pbkdf2_hmac_sha1(password, salt.as_bytes(), PBKDF2_ROUNDS,
+                            32).to_hex()
+}
+
+// Checks that the request coming back from `provider` is the one we sent
+// off, and trades the code it carries for an access token.
+fn exchange(oauth: &oauth2::Config, provider: &str,
+            req: &mut Request) -> CargoResult<oauth2::Token> {
+    let mut query = req.query();
+    let code = query.remove("code").unwrap_or(String::new());
+    let state = query.remove("state").unwrap_or(String::new());
+
+    {
+        let key = state_key(provider);
+        let session_state = req.session().remove(&key);
+        let session_state = session_state.as_ref().map(|a| a.as_slice());
+        if Some(state.as_slice()) != session_state {
+            return Err(human("invalid state parameter"))
+        }
+    }
+
+    match oauth.exchange(code) {
+        Ok(token) => Ok(token),
+        Err(s) => Err(human(s)),
+    }
+}
+
+fn decode<T: Decodable>(resp: &http::Response,
+                        provider: &str) -> CargoResult<T> {
+    if resp.get_code() != 200 {
+        return Err(internal(format!("didn't get a 200 result from {}: {}",
+                                    provider, resp)))
+    }
+    let json = try!(str::from_utf8(resp.get_body()).ok().chain_error(|| {
+        internal(format!("{} didn't send a utf8-response", provider))
+    }));
+    json::decode(json).chain_error(|| {
+        internal(format!("{} didn't send a valid json response", provider))
+    })
+}
+
+fn state_key(provider: &str) -> String {
+    format!("{}_oauth_state", provider)
+}
+
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub uid: String,
+    pub created_at: Timespec,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct EncodableIdentity {
+    pub id: i32,
+    pub provider: String,
+    pub uid: String,
+    pub created_at: String,
+}
+
+impl Identity {
+    pub fn find(conn: &Connection, provider: &str,
+                uid: &str) -> CargoResult<Option<Identity>> {
+        let stmt = try!(conn.prepare("SELECT * FROM identities
+                                      WHERE provider = $1 AND uid = $2"));
+        let mut rows = try!(stmt.query(&[&provider as &ToSql, &uid]));
+        Ok(rows.next().map(|r| Model::from_row(&r)))
+    }
+
+    pub fn for_user(conn: &Connection,
+                    user_id: i32) -> CargoResult<Vec<Identity>> {
+        let stmt = try!(conn.prepare("SELECT * FROM identities
+                                      WHERE user_id = $1
+                                      ORDER BY created_at ASC, id ASC"));
+        let rows = try!(stmt.query(&[&user_id]));
+        Ok(rows.map(|r| Model::from_row(&r)).collect())
+    }
+
+    pub fn insert(conn: &Connection, user_id: i32, provider: &str,
+                  profile: &Profile) -> CargoResult<Identity> {
+        let stmt = try!(conn.prepare("INSERT INTO identities
+                                      (user_id, provider, uid, access_token,
+                                       created_at)
+                                      VALUES ($1, $2, $3, $4, $5)
+                                      RETURNING *"));
+        let mut rows = try!(stmt.query(&[&user_id as &ToSql, &provider,
+                                         &profile.uid, &profile.access_token,
+                                         &::now()]));
+        let row = try!(rows.next().chain_error(|| {
+            internal("no identity returned")
+        }));
+        Ok(Model::from_row(&row))
+    }
+
+    /// Lets the user `user_id` log in as `profile` with `provider` too, as
+    /// long as nobody else already does.
+    pub fn link(conn: &Connection, user_id: i32, provider: &str,
+                profile: &Profile) -> CargoResult<Identity> {
+        match try!(Identity::find(conn, provider, profile.uid.as_slice())) {
+            Some(ref identity) if identity.user_id != user_id => {
+                Err(human(format!("the {} account `{}` is already linked to \
+                                   another user", provider, profile.uid)))
+            }
+            Some(identity) => Ok(identity),
+            None => Identity::insert(conn, user_id, provider, profile),
+        }
+    }
+
+    /// Sets the password of the local account `login`.
+    pub fn set_password(conn: &Connection, login: &str,
+                        password: &str) -> CargoResult<()> {
+        let salt: String = thread_rng().gen_ascii_chars().take(16).collect();
+        let hash = hash_password(salt.as_slice(), password);
+        try!(conn.execute("UPDATE identities SET salt = $1, password_hash = $2
+                           WHERE provider = $3 AND uid = $4",
+                          &[&salt as &ToSql, &hash, &LOCAL, &login]));
+        Ok(())
+    }
+
+    pub fn encodable(self) -> EncodableIdentity {
+        let Identity { id, provider, uid, created_at, .. } = self;
+        EncodableIdentity {
+            id: id,
+            provider: provider,
+            uid: uid,
+            created_at: ::encode_time(created_at),
+        }
+    }
+}
+
+impl Model for Identity {
+    fn from_row(row: &pg::Row) -> Identity {
+        Identity {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            provider: row.get("provider"),
+            uid: row.get("uid"),
+            created_at: row.get("created_at"),
+        }
+    }
+
+    fn table_name(_: Option<Identity>) -> &'static str { "identities" }
+}
+
+fn provider_name(req: &Request) -> String {
+    req.params().find("provider").unwrap_or(GITHUB).to_string()
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct Signup { provider: String, profile: Profile }
+
+// Logs in as whoever `profile` belongs to, or, if someone is logged in
+// already, links it to them instead.
+//
+// Someone new whose login can't be used has to pick another one. What the
+// provider said about them is kept in the session until they do, and they
+// finish signing up with `signup`.
+fn sign_in(req: &mut Request, provider: &str,
+           profile: &Profile) -> CargoResult<Response> {
+    let current = account_user(req).ok().map(|u| u.id);
+    let needs_login = match current {
+        Some(..) => false,
+        None => {
+            let tx = try!(req.tx());
+            try!(Identity::find(tx, provider,
+                                profile.uid.as_slice())).is_none() &&
+                !try!(User::login_available(tx, profile.login.as_slice()))
+        }
+    };
+    if needs_login {
+        let signup = Signup {
+            provider: provider.to_string(),
+            profile: Profile { access_token: None, .. profile.clone() },
+        };
+        req.session().insert(SIGNUP_KEY.to_string(),
+                             json::encode(&signup).unwrap());
+        return Err(human(format!("the login `{}` can't be used, so pick \
+                                  another one to finish signing up",
+                                 profile.login)))
+    }
+
+    let user = {
+        let tx = try!(req.tx());
+        match current {
+            Some(id) => {
+                try!(Identity::link(tx, id, provider, profile));
+                try!(User::find(tx, id))
+            }
+            None => try!(User::find_or_insert(tx, provider, profile)),
+        }
+    };
+    req.session().insert("user_id".to_string(), user.id.to_string());
+    req.mut_extensions().insert(user);
+    super::me(req)
+}
+
+pub fn authorize_url(req: &mut Request) -> CargoResult<Response> {
+    let name = provider_name(req);
+    let state: String = thread_rng().gen_ascii_chars().take(16).collect();
+    let url = {
+        let p = try!(provider(&**req.app(), name.as_slice()));
+        try!(p.authorize_url(state.as_slice()).chain_error(|| {
+            human(format!("`{}` doesn't log in through another site", name))
+        }))
+    };
+    req.session().insert(state_key(name.as_slice()), state.clone());
+
+    #[derive(RustcEncodable)]
+    struct R { url: String, state: String }
+    Ok(req.json(&R { url: url, state: state }))
+}
+
+pub fn authorize(req: &mut Request) -> CargoResult<Response> {
+    let name = provider_name(req);
+    let app = req.app().clone();
+    let profile = try!(try!(provider(&*app, name.as_slice()))
+                           .authenticate(req));
+    sign_in(req, name.as_slice(), &profile)
+}
+
+/// Finishes signing up someone whose login couldn't be used, with the one
+/// they've picked instead.
+pub fn signup(req: &mut Request) -> CargoResult<Response> {
+    let body = try!(req.body().read_to_string());
+
+    #[derive(RustcDecodable)]
+    struct Login { login: String }
+    let new: Login = try!(json::decode(body.as_slice()).map_err(|_| {
+        human("invalid json request")
+    }));
+    let signup = try!(req.session().get(SIGNUP_KEY).and_then(|s| {
+        json::decode::<Signup>(s.as_slice()).ok()
+    }).chain_error(|| human("there's no sign up waiting to be finished")));
+    if !valid_login(new.login.as_slice()) {
+        return Err(human(format!("`{}` isn't a valid login", new.login)))
+    }
+    if !try!(User::login_available(try!(req.tx()), new.login.as_slice())) {
+        return Err(human(format!("the login `{}` is already taken",
+                                 new.login)))
+    }
+
+    let profile = Profile { login: new.login, .. signup.profile };
+    let resp = try!(sign_in(req, signup.provider.as_slice(), &profile));
+    req.session().remove(&SIGNUP_KEY.to_string());
+    Ok(resp)
+}
+
+/// Creates a local account, or adds one to the user who's logged in.
+pub fn register(req: &mut Request) -> CargoResult<Response> {
+    let body = try!(req.body().read_to_string());
+    try!(provider(&**req.app(), LOCAL));
+
+    #[derive(RustcDecodable)]
+    struct Registration {
+        login: String,
+        password: String,
+        email: Option<String>,
+        name: Option<String>,
+    }
+    let new: Registration = try!(json::decode(body.as_slice()).map_err(|_| {
+        human("invalid json request")
+    }));
+    if !valid_login(new.login.as_slice()) {
+        return Err(human("a login can only contain letters, numbers, `-` \
+                          and `_`"))
+    }
+    if new.password.len() < MIN_PASSWORD_LEN {
+        return Err(human(format!("a password needs at least {} characters",
+                                 MIN_PASSWORD_LEN)))
+    }
+    // A new user gets the login they asked for, so it has to be free as well.
+    let new_user = account_user(req).is_err();
+    let taken = {
+        let tx = try!(req.tx());
+        try!(Identity::find(tx, LOCAL, new.login.as_slice())).is_some() ||
+            (new_user &&
+             !try!(User::login_available(tx, new.login.as_slice())))
+    };
+    if taken {
+        return Err(human(format!("the login `{}` is already taken",
+                                 new.login)))
+    }
+
+    let profile = Profile {
+        email: new.email,
+        name: new.name,
+        .. Profile::new(new.login.as_slice())
+    };
+    let resp = try!(sign_in(req, LOCAL, &profile));
+    try!(Identity::set_password(try!(req.tx()), new.login.as_slice(),
+                                new.password.as_slice()));
+    Ok(resp)
+}
+
+/// Whether `login` can be used as a user's login.
+pub fn valid_login(login: &str) -> bool {
+    login.len() > 0 && login.chars().all(|c| {
+        c.is_alphanumeric() || c == '_' || c == '-'
+    })
+}
+
+pub fn list(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(account_user(req));
+    let identities = try!(Identity::for_user(try!(req.tx()), user.id));
+
+    #[derive(RustcEncodable)]
+    struct R { identities: Vec<EncodableIdentity> }
+    Ok(req.json(&R {
+        identities: identities.into_iter().map(|i| i.encodable()).collect(),
+    }))
+}
+
+pub fn unlink(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(account_user(req));
+    let id = try!(req.params()["identity_id"].parse::<i32>().ok()
+                     .chain_error(|| human("invalid identity id")));
+    let tx = try!(req.tx());
+    let identities = try!(Identity::for_user(tx, user.id));
+    if !identities.iter().any(|i| i.id == id) {
+        return Err(human(format!("you don't have an identity with id {}", id)))
+    }
+    if identities.len() == 1 {
+        return Err(human("you can't unlink the only way you have of \
+                          logging in"))
+    }
+    try!(tx.execute("DELETE FROM identities WHERE id = $1", &[&id]));
+
+    #[derive(RustcEncodable)]
+    struct R { ok: bool }
+    Ok(req.json(&R { ok: true }))
+}
@@ -5,45 +5,79 @@ use conduit::Request;
 use conduit_cookie::RequestSession;
 
 use db::RequestTransaction;
-use super::User;
-use util::errors::{CargoResult, Unauthorized, ChainError, std_error};
+use super::{User, ApiToken, Scope};
+use util::errors::{CargoResult, Unauthorized, ChainError, std_error, human};
 
 pub struct Middleware;
 
 impl conduit_middleware::Middleware for Middleware {
     fn before(&self, req: &mut Request) -> Result<(), Box<Error+Send>> {
-        let user = match req.session().get("user_id").and_then(|s| s.parse().ok()) {
+        let id = req.session().get("user_id").and_then(|s| s.parse().ok());
+        let (user, token) = match id {
             Some(id) => {
                 match User::find(try!(req.tx().map_err(std_error)), id) {
-                    Ok(user) => user,
+                    Ok(user) => (user, None),
                     Err(..) => return Ok(()),
                 }
             }
             None => {
                 let tx = try!(req.tx().map_err(std_error));
-                match req.headers().find("Authorization") {
-                    Some(headers) => {
-                        match User::find_by_api_token(tx, headers[0].as_slice()) {
-                            Ok(user) => user,
-                            Err(..) => return Ok(())
-                        }
-                    }
+                let header = match req.headers().find("Authorization") {
+                    Some(headers) => headers[0].to_string(),
                     None => return Ok(())
-                }
+                };
+                let token = ApiToken::find_by_token(tx, header.as_slice());
+                let token = match token {
+                    Ok(token) => token,
+                    Err(..) => return Ok(())
+                };
+                let user = match User::find(tx, token.user_id) {
+                    Ok(user) => user,
+                    Err(..) => return Ok(())
+                };
+                try!(token.touch(tx).map_err(std_error));
+                (user, Some(token))
             }
         };
 
         req.mut_extensions().insert(user);
+        if let Some(token) = token {
+            req.mut_extensions().insert(token);
+        }
         Ok(())
     }
 }
 
 pub trait RequestUser<'a> {
     fn user(self) -> CargoResult<&'a User>;
+
+    /// The API token the request was made with, if it was made with one.
+    fn api_token(self) -> Option<&'a ApiToken>;
+
+    /// Returns the user making the request, as long as they're allowed to do
+    /// `scope` to the crate `krate`. That's only ever in question for
+    /// requests made with an API token.
+    fn authorize(self, scope: Scope, krate: &str) -> CargoResult<&'a User>;
 }
 
 impl<'a> RequestUser<'a> for &'a (Request + 'a) {
     fn user(self) -> CargoResult<&'a User> {
         self.extensions().find::<User>().chain_error(|| Unauthorized)
     }
+
+    fn api_token(self) -> Option<&'a ApiToken> {
+        self.extensions().find::<ApiToken>()
+    }
+
+    fn authorize(self, scope: Scope, krate: &str) -> CargoResult<&'a User> {
+        let user = try!(self.user());
+        match self.api_token() {
+            Some(token) if !token.allows(scope, krate) => {
+                Err(human(format!("the API token `{}` doesn't have the `{}` \
+                                   scope for the crate `{}`", token.name,
+                                  scope.name(), krate)))
+            }
+            _ => Ok(user),
+        }
+    }
 }
@@ -1,36 +1,35 @@
 use std::collections::HashMap;
-use std::str;
 
 use conduit::{Request, Response};
 use conduit_cookie::{RequestSession};
-use curl::http;
-use oauth2::Authorization;
 use pg::types::ToSql;
 use pg;
-use rand::{thread_rng, Rng};
-use rustc_serialize::json;
 
 use {Model, Version};
-use app::RequestApp;
 use db::{Connection, RequestTransaction};
 use krate::{Crate, EncodableCrate};
 use util::errors::NotFound;
 use util::{RequestUtils, CargoResult, internal, ChainError, human, CommaSep};
 use version::EncodableVersion;
 
+pub use self::identity::{Identity, Profile, Provider};
 pub use self::middleware::{Middleware, RequestUser};
+pub use self::token::{ApiToken, Scope};
 
+pub mod identity;
 pub mod middleware;
+pub mod token;
+
+/// The name of the token which `reset_token` replaces.
+pub const DEFAULT_TOKEN: &'static str = "default";
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct User {
     pub id: i32,
-    pub gh_login: String,
+    pub login: String,
     pub name: Option<String>,
     pub email: Option<String>,
     pub avatar: Option<String>,
-    pub gh_access_token: String,
-    pub api_token: String,
 }
 
 #[derive(RustcDecodable, RustcEncodable)]
@@ -49,7 +48,7 @@ impl User {
 
     pub fn find_by_login(conn: &Connection, login: &str) -> CargoResult<User> {
         let stmt = try!(conn.prepare("SELECT * FROM users
-                                      WHERE gh_login = $1"));
+                                      WHERE login = $1"));
         let mut rows = try!(stmt.query(&[&login as &ToSql]));
         let row = try!(rows.next().chain_error(|| {
             NotFound
@@ -57,67 +56,77 @@ impl User {
         Ok(Model::from_row(&row))
     }
 
-    pub fn find_by_api_token(conn: &Connection, token: &str) -> CargoResult<User> {
-        let stmt = try!(conn.prepare("SELECT * FROM users \
-                                      WHERE api_token = $1 LIMIT 1"));
-        return try!(stmt.query(&[&token as &ToSql])).next()
-                        .map(|r| Model::from_row(&r)).chain_error(|| {
-            NotFound
-        })
-    }
-
+    /// Finds the user who has logged in with `provider` as `profile` before,
+    /// bringing their details up to date, or creates a new user with it as
+    /// their first identity.
     pub fn find_or_insert(conn: &Connection,
-                          login: &str,
-                          email: Option<&str>,
-                          name: Option<&str>,
-                          avatar: Option<&str>,
-                          access_token: &str,
-                          api_token: &str) -> CargoResult<User> {
+                          provider: &str,
+                          profile: &Profile) -> CargoResult<User> {
         // TODO: this is racy, but it looks like any other solution is...
         //       interesting! For now just do the racy thing which will report
         //       more errors than it needs to.
 
-        let stmt = try!(conn.prepare("UPDATE users
-                                      SET gh_access_token = $1,
-                                          email = $2,
-                                          name = $3,
-                                          gh_avatar = $4
-                                      WHERE gh_login = $5
-                                      RETURNING *"));
-        let mut rows = try!(stmt.query(&[&access_token as &ToSql,
-                                         &email, &name, &avatar,
-                                         &login as &ToSql]));
-        match rows.next() {
-            Some(ref row) => return Ok(Model::from_row(row)),
-            None => {}
+        let stmt = try!(conn.prepare("UPDATE identities
+                                      SET access_token = $1
+                                      WHERE provider = $2 AND uid = $3
+                                      RETURNING user_id"));
+        let mut rows = try!(stmt.query(&[&profile.access_token as &ToSql,
+                                         &provider, &profile.uid]));
+        if let Some(row) = rows.next() {
+            let id: i32 = row.get("user_id");
+            let stmt = try!(conn.prepare("UPDATE users
+                                          SET email = COALESCE($1, email),
+                                              name = COALESCE($2, name),
+                                              avatar = COALESCE($3, avatar)
+                                          WHERE id = $4
+                                          RETURNING *"));
+            let mut rows = try!(stmt.query(&[&profile.email as &ToSql,
+                                             &profile.name, &profile.avatar,
+                                             &id]));
+            return Ok(Model::from_row(&try!(rows.next().chain_error(|| {
+                internal("no user for an identity")
+            }))))
+        }
+
+        if !identity::valid_login(profile.login.as_slice()) {
+            return Err(human(format!("`{}` isn't a valid login",
+                                     profile.login)))
+        }
+        if User::find_by_login(conn, profile.login.as_slice()).is_ok() {
+            return Err(human(format!("the login `{}` is already taken; to \
+                                      log in with {} too, log in some other \
+                                      way and link it from there",
+                                     profile.login, provider)))
         }
         let stmt = try!(conn.prepare("INSERT INTO users
-                                      (email, gh_access_token, api_token,
-                                       gh_login, name, gh_avatar)
-                                      VALUES ($1, $2, $3, $4, $5, $6)
+                                      (email, login, name, avatar)
+                                      VALUES ($1, $2, $3, $4)
                                       RETURNING *"));
-        let mut rows = try!(stmt.query(&[&email as &ToSql,
-                                         &access_token as &ToSql,
-                                         &api_token as &ToSql,
-                                         &login as &ToSql,
-                                         &name, &avatar]));
-        Ok(Model::from_row(&try!(rows.next().chain_error(|| {
-            internal("no user with email we just found")
-        }))))
+        let mut rows = try!(stmt.query(&[&profile.email as &ToSql,
+                                         &profile.login, &profile.name,
+                                         &profile.avatar]));
+        let user: User = Model::from_row(&try!(rows.next().chain_error(|| {
+            internal("no user returned")
+        })));
+        try!(Identity::insert(conn, user.id, provider, profile));
+        Ok(user)
     }
 
-    pub fn new_api_token() -> String {
-        thread_rng().gen_ascii_chars().take(32).collect()
+    /// Whether a new user could be given the login `login`.
+    pub fn login_available(conn: &Connection,
+                           login: &str) -> CargoResult<bool> {
+        if !identity::valid_login(login) { return Ok(false) }
+        let stmt = try!(conn.prepare("SELECT 1 FROM users WHERE login = $1"));
+        Ok(try!(stmt.query(&[&login as &ToSql])).next().is_none())
     }
 
     pub fn encodable(self) -> EncodableUser {
-        let User { id, email, api_token: _, gh_access_token: _,
-                   name, gh_login, avatar } = self;
+        let User { id, email, name, login, avatar } = self;
         EncodableUser {
             id: id,
             email: email,
             avatar: avatar,
-            login: gh_login,
+            login: login,
             name: name,
         }
     }
@@ -128,103 +137,40 @@ impl Model for User {
         User {
             id: row.get("id"),
             email: row.get("email"),
-            gh_access_token: row.get("gh_access_token"),
-            api_token: row.get("api_token"),
-            gh_login: row.get("gh_login"),
+            login: row.get("login"),
             name: row.get("name"),
-            avatar: row.get("gh_avatar"),
+            avatar: row.get("avatar"),
         }
     }
 
     fn table_name(_: Option<User>) -> &'static str { "users" }
 }
 
-pub fn github_authorize(req: &mut Request) -> CargoResult<Response> {
-    let state: String = thread_rng().gen_ascii_chars().take(16).collect();
-    req.session().insert("github_oauth_state".to_string(), state.clone());
-
-    let url = req.app().github.authorize_url(state.clone());
-
-    #[derive(RustcEncodable)]
-    struct R { url: String, state: String }
-    Ok(req.json(&R { url: url.to_string(), state: state }))
-}
-
-pub fn github_access_token(req: &mut Request) -> CargoResult<Response> {
-    // Parse the url query
-    let mut query = req.query();
-    let code = query.remove("code").unwrap_or(String::new());
-    let state = query.remove("state").unwrap_or(String::new());
-
-    // Make sure that the state we just got matches the session state that we
-    // should have issued earlier.
-    {
-        let session_state = req.session().remove(&"github_oauth_state".to_string());
-        let session_state = session_state.as_ref().map(|a| a.as_slice());
-        if Some(state.as_slice()) != session_state {
-            return Err(human("invalid state parameter"))
-        }
-    }
-
-    // Fetch the access token from github using the code we just got
-    let token = match req.app().github.exchange(code.clone()) {
-        Ok(token) => token,
-        Err(s) => return Err(human(s)),
-    };
-
-    let resp = try!(http::handle().get("https://api.github.com/user")
-                         .header("Accept", "application/vnd.github.v3+json")
-                         .header("User-Agent", "hello!")
-                         .auth_with(&token)
-                         .exec());
-    if resp.get_code() != 200 {
-        return Err(internal(format!("didn't get a 200 result from github: {}",
-                                    resp)))
-    }
-
-    #[derive(RustcDecodable)]
-    struct GithubUser {
-        email: Option<String>,
-        name: Option<String>,
-        login: String,
-        avatar_url: Option<String>,
-    }
-    let json = try!(str::from_utf8(resp.get_body()).ok().chain_error(||{
-        internal("github didn't send a utf8-response")
-    }));
-    let ghuser: GithubUser = try!(json::decode(json).chain_error(|| {
-        internal("github didn't send a valid json response")
-    }));
-
-    // Into the database!
-    let api_token = User::new_api_token();
-    let user = try!(User::find_or_insert(try!(req.tx()),
-                                         ghuser.login.as_slice(),
-                                         ghuser.email.as_ref()
-                                               .map(|s| s.as_slice()),
-                                         ghuser.name.as_ref()
-                                               .map(|s| s.as_slice()),
-                                         ghuser.avatar_url.as_ref()
-                                               .map(|s| s.as_slice()),
-                                         token.access_token.as_slice(),
-                                         api_token.as_slice()));
-    req.session().insert("user_id".to_string(), user.id.to_string());
-    req.mut_extensions().insert(user);
-    me(req)
-}
-
 pub fn logout(req: &mut Request) -> CargoResult<Response> {
     req.session().remove(&"user_id".to_string());
     Ok(req.json(&true))
 }
 
+// Returns the user making the request, as long as they didn't use an API
+// token, which can't be used to look at or change the account itself.
+fn account_user<'a>(req: &'a (Request + 'a)) -> CargoResult<&'a User> {
+    if let Some(token) = req.api_token() {
+        return Err(human(format!("the API token `{}` can't be used to manage \
+                                  the account", token.name)))
+    }
+    req.user()
+}
+
+/// Replaces the user's `default` API token, which can do anything, with a new
+/// one. This is the only time the new token is shown.
 pub fn reset_token(req: &mut Request) -> CargoResult<Response> {
-    let user = try!(req.user());
+    let user = try!(account_user(req));
 
-    let token = User::new_api_token();
     let conn = try!(req.tx());
-    try!(conn.execute("UPDATE users SET api_token = $1 WHERE id = $2",
-                      &[&token, &user.id]));
+    try!(conn.execute("DELETE FROM api_tokens WHERE user_id = $1 AND name = $2",
+                      &[&user.id as &ToSql, &DEFAULT_TOKEN]));
+    let (_, token) = try!(ApiToken::insert(conn, user.id, DEFAULT_TOKEN,
+                                           Scope::all(), None, None));
 
     #[derive(RustcEncodable)]
     struct R { api_token: String }
@@ -232,12 +178,11 @@ pub fn reset_token(req: &mut Request) -> CargoResult<Response> {
 }
 
 pub fn me(req: &mut Request) -> CargoResult<Response> {
-    let user = try!(req.user());
+    let user = try!(account_user(req));
 
     #[derive(RustcEncodable)]
-    struct R { user: EncodableUser, api_token: String }
-    let token = user.api_token.clone();
-    Ok(req.json(&R{ user: user.clone().encodable(), api_token: token }))
+    struct R { user: EncodableUser }
+    Ok(req.json(&R{ user: user.clone().encodable() }))
 }
 
 pub fn updates(req: &mut Request) -> CargoResult<Response> {
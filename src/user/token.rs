@@ -0,0 +1,372 @@
+//! Named API tokens, of which a user can have any number, e.g. one for each
+//! machine which publishes their crates.
+//!
+//! Each token is limited to the `Scope`s it was created with, and optionally
+//! to crates whose names match a pattern. None of them can be used to manage
+//! the account itself, and they can be revoked one at a time.
+//!
+//! Tokens are never stored as they are. Only a salted hash of each one is
+//! kept, along with its first few characters to find it by, so the token
+//! itself is only ever seen when it's created.
+
+use std::time::Duration;
+use openssl::crypto::hash::{self, Type};
+use rand::{thread_rng, Rng};
+use rustc_serialize::hex::ToHex;
+use time::Timespec;
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
+use pg;
+use pg::types::ToSql;
+use rustc_serialize::json;
+
+use Model;
+use db::{Connection, RequestTransaction};
+use util::{RequestUtils, CargoResult, ChainError, internal, human};
+use util::errors::{NotFound, CargoError};
+use super::account_user;
+
+/// How many characters at the start of a token are stored as they are, so
+/// that it can be found.
+pub const PREFIX_LEN: usize = 8;
+
+/// The furthest off a token's expiry can be, in days.
+pub const MAX_EXPIRY_DAYS: i64 = 3650;
+
+/// Something an API token can be allowed to do.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Scope {
+    /// Publishing the first version of a crate.
+    PublishNew,
+    /// Publishing new versions of an existing crate.
+    PublishUpdate,
+    /// Yanking and unyanking versions.
+    Yank,
+    /// Adding and removing the owners of a crate.
+    ChangeOwners,
+}
+
+impl Scope {
+    pub fn all() -> &'static [Scope] {
+        static ALL: [Scope; 4] = [Scope::PublishNew, Scope::PublishUpdate,
+                                  Scope::Yank, Scope::ChangeOwners];
+        &ALL
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Scope::PublishNew => "publish-new",
+            Scope::PublishUpdate => "publish-update",
+            Scope::Yank => "yank",
+            Scope::ChangeOwners => "change-owners",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scope> {
+        Scope::all().iter().find(|s| s.name() == name).map(|s| *s)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub crate_pattern: Option<String>,
+    pub last_used_at: Option<Timespec>,
+    pub expires_at: Option<Timespec>,
+    pub created_at: Timespec,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct EncodableApiToken {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub crate_pattern: Option<String>,
+    pub last_used_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+/// What's stored of a token: the start of it, and a hash of the whole token
+/// along with a random salt.
+pub struct Digest {
+    pub prefix: String,
+    pub salt: String,
+    pub hash: String,
+}
+
+impl Digest {
+    pub fn new(token: &str) -> Digest {
+        let salt: String = thread_rng().gen_ascii_chars().take(16).collect();
+        Digest {
+            prefix: token.chars().take(PREFIX_LEN).collect(),
+            hash: hash_token(salt.as_slice(), token),
+            salt: salt,
+        }
+    }
+}
+
+/// Generates a new, random, token.
+pub fn new_token() -> String {
+    thread_rng().gen_ascii_chars().take(PREFIX_LEN + 32).collect()
+}
+
+fn hash_token(salt: &str, token: &str) -> String {
+    let data = format!("{}{}", salt, token);
+    hash::hash(Type::SHA256, data.as_bytes()).to_hex()
+}
+
+/// Compares two hashes in an amount of time which doesn't depend on where
+/// they differ, so it can't be used to guess them a character at a time.
+pub fn same_hash(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| {
+        acc | (a ^ b)
+    }) == 0
+}
+
+impl ApiToken {
+    /// Creates a new token, returning it along with the token itself, which
+    /// can't be found out again later.
+    pub fn insert(conn: &Connection, user_id: i32, name: &str,
+                  scopes: &[Scope], crate_pattern: Option<&str>,
+                  expires_at: Option<Timespec>)
+                  -> CargoResult<(ApiToken, String)> {
+        let stmt = try!(conn.prepare("SELECT 1 FROM api_tokens
+                                      WHERE user_id = $1 AND name = $2"));
+        if try!(stmt.query(&[&user_id as &ToSql, &name])).next().is_some() {
+            return Err(human(format!("there's already a token named `{}`",
+                                     name)))
+        }
+
+        let token = new_token();
+        let digest = Digest::new(token.as_slice());
+        let scopes = scopes.iter().map(|s| s.name()).collect::<Vec<_>>();
+        let stmt = try!(conn.prepare("INSERT INTO api_tokens
+                                      (user_id, name, token_prefix, salt,
+                                       token_hash, scopes, crate_pattern,
+                                       expires_at, created_at)
+                                      VALUES ($1, $2, $3, $4, $5, $6, $7, $8,
+                                              $9)
+                                      RETURNING *"));
+        let mut rows = try!(stmt.query(&[&user_id as &ToSql, &name,
+                                         &digest.prefix, &digest.salt,
+                                         &digest.hash, &scopes.connect(","),
+                                         &crate_pattern, &expires_at,
+                                         &::now()]));
+        let row = try!(rows.next().chain_error(|| {
+            internal("no api token returned")
+        }));
+        Ok((Model::from_row(&row), token))
+    }
+
+    /// Finds the token `token`, unless it's expired.
+    pub fn find_by_token(conn: &Connection,
+                         token: &str) -> CargoResult<ApiToken> {
+        let prefix = token.chars().take(PREFIX_LEN).collect::<String>();
+        let stmt = try!(conn.prepare("SELECT * FROM api_tokens
+                                      WHERE token_prefix = $1 AND
+                                            (expires_at IS NULL OR
+                                             expires_at > $2)"));
+        for row in try!(stmt.query(&[&prefix as &ToSql, &::now()])) {
+            let salt: String = row.get("salt");
+            let expected: String = row.get("token_hash");
+            let actual = hash_token(salt.as_slice(), token);
+            if same_hash(actual.as_slice(), expected.as_slice()) {
+                return Ok(Model::from_row(&row))
+            }
+        }
+        Err(Box::new(NotFound) as Box<CargoError>)
+    }
+
+    pub fn for_user(conn: &Connection,
+                    user_id: i32) -> CargoResult<Vec<ApiToken>> {
+        let stmt = try!(conn.prepare("SELECT * FROM api_tokens
+                                      WHERE user_id = $1
+                                      ORDER BY created_at ASC"));
+        let rows = try!(stmt.query(&[&user_id]));
+        Ok(rows.map(|r| Model::from_row(&r)).collect())
+    }
+
+    /// Records that the token has just been used.
+    pub fn touch(&self, conn: &Connection) -> CargoResult<()> {
+        try!(conn.execute("UPDATE api_tokens SET last_used_at = $1
+                           WHERE id = $2",
+                          &[&::now() as &ToSql, &self.id]));
+        Ok(())
+    }
+
+    /// Whether the token can be used to do `scope` to the crate `krate`.
+    pub fn allows(&self, scope: Scope, krate: &str) -> bool {
+        self.scopes.contains(&scope) && match self.crate_pattern {
+            Some(ref pattern) => matches(pattern.as_slice(), krate),
+            None => true,
+        }
+    }
+
+    pub fn encodable(self) -> EncodableApiToken {
+        let ApiToken { id, name, prefix, scopes, crate_pattern, last_used_at,
+                       expires_at, created_at, .. } = self;
+        EncodableApiToken {
+            id: id,
+            name: name,
+            prefix: prefix,
+            scopes: scopes.iter().map(|s| s.name().to_string()).collect(),
+            crate_pattern: crate_pattern,
+            last_used_at: last_used_at.map(::encode_time),
+            expires_at: expires_at.map(::encode_time),
+            created_at: ::encode_time(created_at),
+        }
+    }
+}
+
+impl Model for ApiToken {
+    fn from_row(row: &pg::Row) -> ApiToken {
+        let scopes: String = row.get("scopes");
+        ApiToken {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            name: row.get("name"),
+            prefix: row.get("token_prefix"),
+            scopes: scopes.as_slice().split(',').filter_map(Scope::from_name)
+                          .collect(),
+            crate_pattern: row.get("crate_pattern"),
+            last_used_at: row.get("last_used_at"),
+            expires_at: row.get("expires_at"),
+            created_at: row.get("created_at"),
+        }
+    }
+
+    fn table_name(_: Option<ApiToken>) -> &'static str { "api_tokens" }
+}
+
+// Whether the crate name `name` matches `pattern`, in which a `*` stands for
+// any number of characters. Like crate names everywhere else, the comparison
+// ignores case.
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().map(|c| c.to_lowercase()).collect::<String>();
+    let name = name.chars().map(|c| c.to_lowercase()).collect::<String>();
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+
+    // Only the latest `*` ever needs to be backtracked to: whatever it skips
+    // over, the earlier ones can't do any better. That keeps this to one
+    // pass over `name` for each character of `pattern` at worst.
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((sp, sn)) = star {
+            star = Some((sp, sn + 1));
+            p = sp + 1;
+            n = sn + 1;
+        } else {
+            return false
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn valid_pattern(pattern: &str) -> bool {
+    pattern.len() > 0 && pattern.chars().all(|c| {
+        c.is_alphanumeric() || c == '_' || c == '-' || c == '*'
+    })
+}
+
+pub fn list(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(account_user(req));
+    let tokens = try!(ApiToken::for_user(try!(req.tx()), user.id));
+
+    #[derive(RustcEncodable)]
+    struct R { api_tokens: Vec<EncodableApiToken> }
+    Ok(req.json(&R {
+        api_tokens: tokens.into_iter().map(|t| t.encodable()).collect(),
+    }))
+}
+
+pub fn new(req: &mut Request) -> CargoResult<Response> {
+    let body = try!(req.body().read_to_string());
+    let user = try!(account_user(req));
+
+    #[derive(RustcDecodable)]
+    struct NewToken {
+        name: String,
+        scopes: Vec<String>,
+        crate_pattern: Option<String>,
+        expires_in_days: Option<i64>,
+    }
+    let new: NewToken = try!(json::decode(body.as_slice()).map_err(|_| {
+        human("invalid json request")
+    }));
+
+    if new.name.as_slice().trim().len() == 0 {
+        return Err(human("a token needs a name"))
+    }
+    if new.scopes.len() == 0 {
+        return Err(human(format!("a token needs at least one of the scopes: \
+                                  {}", Scope::all().iter().map(|s| s.name())
+                                                   .collect::<Vec<_>>()
+                                                   .connect(", "))))
+    }
+    let mut scopes = Vec::new();
+    for scope in new.scopes.iter() {
+        match Scope::from_name(scope.as_slice()) {
+            Some(scope) => scopes.push(scope),
+            None => return Err(human(format!("unknown scope `{}`", scope))),
+        }
+    }
+    if let Some(ref pattern) = new.crate_pattern {
+        if !valid_pattern(pattern.as_slice()) {
+            return Err(human(format!("invalid crate pattern `{}`", pattern)))
+        }
+    }
+    let expires_at = match new.expires_in_days {
+        Some(days) if days <= 0 => {
+            return Err(human("a token has to expire in at least one day"))
+        }
+        Some(days) if days > MAX_EXPIRY_DAYS => {
+            return Err(human(format!("a token can't expire more than {} days \
+                                      from now", MAX_EXPIRY_DAYS)))
+        }
+        Some(days) => Some(::now() + Duration::days(days)),
+        None => None,
+    };
+
+    let (api_token, token) = try!(ApiToken::insert(try!(req.tx()), user.id,
+                                                   new.name.as_slice(),
+                                                   scopes.as_slice(),
+                                                   new.crate_pattern.as_ref()
+                                                      .map(|s| s.as_slice()),
+                                                   expires_at));
+
+    #[derive(RustcEncodable)]
+    struct R { api_token: EncodableApiToken, token: String }
+    Ok(req.json(&R { api_token: api_token.encodable(), token: token }))
+}
+
+pub fn revoke(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(account_user(req));
+    let id = try!(req.params()["token_id"].parse::<i32>().ok().chain_error(|| {
+        human("invalid token id")
+    }));
+    let tx = try!(req.tx());
+    let n = try!(tx.execute("DELETE FROM api_tokens
+                             WHERE id = $1 AND user_id = $2",
+                            &[&id, &user.id]));
+    if n == 0 {
+        return Err(human(format!("you don't have a token with id {}", id)))
+    }
+
+    #[derive(RustcEncodable)]
+    struct R { ok: bool }
+    Ok(req.json(&R { ok: true }))
+}
@@ -11,13 +11,13 @@ use semver;
 use url;
 
 use {Model, Crate, User};
-use app::RequestApp;
 use db::{Connection, RequestTransaction};
 use dependency::{Dependency, EncodableDependency, Kind};
 use download::{VersionDownload, EncodableVersionDownload};
-use git;
+use jobs::{self, Job};
+use render;
 use upload;
-use user::RequestUser;
+use user::{RequestUser, Scope};
 use util::{RequestUtils, CargoResult, ChainError, internal, human, CommaSep};
 
 #[derive(Clone)]
@@ -30,6 +30,11 @@ pub struct Version {
     pub downloads: i32,
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
+    pub license: Option<String>,
+    /// Whether the version is still waiting to be added to the index, until
+    /// when it's left out of the index served over HTTP and can't be
+    /// downloaded.
+    pub pending: bool,
 }
 
 pub enum Author {
@@ -48,6 +53,7 @@ pub struct EncodableVersion {
     pub downloads: i32,
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
+    pub license: Option<String>,
     pub links: VersionLinks,
 }
 
@@ -56,6 +62,13 @@ pub struct VersionLinks {
     pub dependencies: String,
     pub version_downloads: String,
     pub authors: String,
+    pub license: String,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct EncodableLicenseFile {
+    pub path: String,
+    pub content: String,
 }
 
 impl Version {
@@ -75,17 +88,20 @@ impl Version {
     pub fn insert(conn: &Connection, crate_id: i32,
                   num: &semver::Version,
                   features: &HashMap<String, Vec<String>>,
-                  authors: &[String])
+                  authors: &[String],
+                  license: Option<&str>)
                   -> CargoResult<Version> {
         let num = num.to_string();
         let features = json::encode(features).unwrap();
         let stmt = try!(conn.prepare("INSERT INTO versions \
                                       (crate_id, num, updated_at, \
-                                       created_at, downloads, features) \
-                                      VALUES ($1, $2, $3, $3, 0, $4) \
+                                       created_at, downloads, features, \
+                                       license) \
+                                      VALUES ($1, $2, $3, $3, 0, $4, $5) \
                                       RETURNING *"));
         let now = ::now();
-        let mut rows = try!(stmt.query(&[&crate_id, &num, &now, &features]));
+        let mut rows = try!(stmt.query(&[&crate_id, &num, &now, &features,
+                                         &license]));
         let ret: Version = Model::from_row(&try!(rows.next().chain_error(|| {
             internal("no version returned")
         })));
@@ -101,7 +117,7 @@ impl Version {
 
     pub fn encodable(self, crate_name: &str) -> EncodableVersion {
         let Version { id, crate_id: _, num, updated_at, created_at,
-                      downloads, features, yanked } = self;
+                      downloads, features, yanked, license, pending: _ } = self;
         let num = num.to_string();
         EncodableVersion {
             dl_path: format!("/api/v1/crates/{}/{}/download", crate_name, num),
@@ -113,12 +129,14 @@ impl Version {
             downloads: downloads,
             features: features,
             yanked: yanked,
+            license: license,
             links: VersionLinks {
                 dependencies: format!("/api/v1/crates/{}/{}/dependencies",
                                       crate_name, num),
                 version_downloads: format!("/api/v1/crates/{}/{}/downloads",
                                            crate_name, num),
                 authors: format!("/api/v1/crates/{}/{}/authors", crate_name, num),
+                license: format!("/api/v1/crates/{}/{}/license", crate_name, num),
             },
         }
     }
@@ -153,7 +171,8 @@ impl Version {
                                       FROM dependencies
                                       LEFT JOIN crates
                                         ON crates.id = dependencies.crate_id
-                                      WHERE dependencies.version_id = $1"));
+                                      WHERE dependencies.version_id = $1
+                                      ORDER BY dependencies.id ASC"));
         Ok(try!(stmt.query(&[&self.id])).map(|r| {
             (Model::from_row(&r), r.get("crate_name"))
         }).collect())
@@ -181,9 +200,88 @@ impl Version {
         Ok(())
     }
 
+    /// Stores the readme at `path` in this version's tarball, along with its
+    /// rendering as HTML. Relative links in it point into `repository`.
+    pub fn add_readme(&self, conn: &Connection, path: &str, content: &str,
+                      repository: Option<&str>) -> CargoResult<()> {
+        let rendered = render::readme_to_html(path, content, repository);
+        try!(conn.execute("INSERT INTO readmes \
+                           (version_id, path, content, rendered) \
+                           VALUES ($1, $2, $3, $4)",
+                          &[&self.id, &path as &ToSql, &content as &ToSql,
+                            &rendered]));
+        Ok(())
+    }
+
+    /// Returns the rendered readme of this version, if it has one.
+    pub fn readme(&self, conn: &Connection) -> CargoResult<Option<String>> {
+        let stmt = try!(conn.prepare("SELECT rendered FROM readmes \
+                                      WHERE version_id = $1"));
+        let mut rows = try!(stmt.query(&[&self.id]));
+        Ok(rows.next().map(|r| r.get("rendered")))
+    }
+
+    /// Stores the license file at `path` in this version's tarball.
+    pub fn add_license_file(&self, conn: &Connection, path: &str,
+                            content: &str) -> CargoResult<()> {
+        try!(conn.execute("INSERT INTO license_files \
+                           (version_id, path, content) \
+                           VALUES ($1, $2, $3)",
+                          &[&self.id, &path as &ToSql, &content as &ToSql]));
+        Ok(())
+    }
+
+    /// Returns the path and contents of the license file this version was
+    /// published with, if it had one.
+    pub fn license_file(&self, conn: &Connection)
+                        -> CargoResult<Option<EncodableLicenseFile>> {
+        let stmt = try!(conn.prepare("SELECT path, content FROM license_files \
+                                      WHERE version_id = $1"));
+        let mut rows = try!(stmt.query(&[&self.id]));
+        Ok(rows.next().map(|r| {
+            EncodableLicenseFile { path: r.get("path"), content: r.get("content") }
+        }))
+    }
+
+    /// Records the SHA256 checksum of this version's tarball, as hex.
+    pub fn set_checksum(&self, conn: &Connection,
+                        cksum: &str) -> CargoResult<()> {
+        try!(conn.execute("UPDATE versions SET checksum = $1 WHERE id = $2",
+                          &[&cksum as &ToSql, &self.id]));
+        Ok(())
+    }
+
+    /// Returns the SHA256 checksum recorded for this version's tarball, if
+    /// there is one.
+    pub fn checksum(&self, conn: &Connection) -> CargoResult<Option<String>> {
+        let stmt = try!(conn.prepare("SELECT checksum FROM versions \
+                                      WHERE id = $1"));
+        let mut rows = try!(stmt.query(&[&self.id]));
+        Ok(rows.next().and_then(|r| r.get("checksum")))
+    }
+
     pub fn yank(&self, conn: &Connection, yanked: bool) -> CargoResult<()> {
-        try!(conn.execute("UPDATE versions SET yanked = $1 WHERE id = $2",
-                          &[&yanked, &self.id]));
+        try!(conn.execute("UPDATE versions SET yanked = $1, updated_at = $2 \
+                           WHERE id = $3",
+                          &[&yanked, &::now(), &self.id]));
+        Ok(())
+    }
+
+    pub fn set_pending(&self, conn: &Connection,
+                       pending: bool) -> CargoResult<()> {
+        try!(conn.execute("UPDATE versions SET pending = $1 WHERE id = $2",
+                          &[&pending, &self.id]));
+        Ok(())
+    }
+
+    /// Deletes the version along with everything which refers to it.
+    pub fn delete(&self, conn: &Connection) -> CargoResult<()> {
+        for table in ["version_downloads", "version_authors", "dependencies",
+                      "readmes", "license_files"].iter() {
+            let sql = format!("DELETE FROM {} WHERE version_id = $1", table);
+            try!(conn.execute(sql.as_slice(), &[&self.id]));
+        }
+        try!(conn.execute("DELETE FROM versions WHERE id = $1", &[&self.id]));
         Ok(())
     }
 }
@@ -204,6 +302,8 @@ impl Model for Version {
             downloads: row.get("downloads"),
             features: features,
             yanked: row.get("yanked"),
+            license: row.get("license"),
+            pending: row.get("pending"),
         }
     }
     fn table_name(_: Option<Version>) -> &'static str { "versions" }
@@ -329,6 +429,36 @@ pub fn authors(req: &mut Request) -> CargoResult<Response> {
     Ok(req.json(&R{ users: users, meta: Meta { names: names } }))
 }
 
+pub fn readme(req: &mut Request) -> CargoResult<Response> {
+    let (version, _) = try!(version_and_crate(req));
+    let tx = try!(req.tx());
+    let readme = try!(version.readme(tx));
+
+    #[derive(RustcEncodable)]
+    struct R { readme: Option<String> }
+    Ok(req.json(&R{ readme: readme }))
+}
+
+pub fn license(req: &mut Request) -> CargoResult<Response> {
+    let (version, _) = try!(version_and_crate(req));
+    let tx = try!(req.tx());
+    let license_file = try!(version.license_file(tx));
+
+    #[derive(RustcEncodable)]
+    struct R { license: Option<String>, license_file: Option<EncodableLicenseFile> }
+    Ok(req.json(&R{ license: version.license, license_file: license_file }))
+}
+
+pub fn cksum(req: &mut Request) -> CargoResult<Response> {
+    let (version, _) = try!(version_and_crate(req));
+    let tx = try!(req.tx());
+    let cksum = try!(version.checksum(tx));
+
+    #[derive(RustcEncodable)]
+    struct R { cksum: Option<String> }
+    Ok(req.json(&R{ cksum: cksum }))
+}
+
 pub fn yank(req: &mut Request) -> CargoResult<Response> {
     modify_yank(req, true)
 }
@@ -339,7 +469,7 @@ pub fn unyank(req: &mut Request) -> CargoResult<Response> {
 
 fn modify_yank(req: &mut Request, yanked: bool) -> CargoResult<Response> {
     let (version, krate) = try!(version_and_crate(req));
-    let user = try!(req.user());
+    let user = try!(req.authorize(Scope::Yank, krate.name.as_slice()));
     let tx = try!(req.tx());
     let owners = try!(krate.owners(tx));
     if !owners.iter().any(|u| u.id == user.id) {
@@ -348,7 +478,8 @@ fn modify_yank(req: &mut Request, yanked: bool) -> CargoResult<Response> {
 
     if version.yanked != yanked {
         try!(version.yank(tx, yanked));
-        try!(git::yank(&**req.app(), krate.name.as_slice(), &version.num, yanked));
+        let job = Job::Yank(krate.name.clone(), version.num.to_string());
+        try!(jobs::enqueue(tx, &job, None));
     }
 
     #[derive(RustcEncodable)]